@@ -0,0 +1,126 @@
+// Throughput of get_env with many concurrent clients, straight through the
+// shared command path. Run with `cargo bench --bench get_env`.
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use shinobi_secrets_server::server::policy::Principal;
+use shinobi_secrets_server::server::server::SecretsServer;
+use shinobi_secrets_server::types::metadata::SecretOrigin;
+use shinobi_secrets_server::types::namespace::Namespace;
+use shinobi_secrets_server::types::request::Command;
+
+const KEYS: usize = 50;
+// Split evenly between the clients, so every level does the same work
+const REQUESTS: usize = 20_000;
+const CLIENTS: [usize; 5] = [1, 4, 16, 64, 256];
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("runtime");
+
+    let namespace = Namespace::new("bench", "dev");
+    let server = Arc::new(SecretsServer::new(String::new(), String::new()));
+    {
+        let mut store = server.store.write().expect("store");
+        for i in 0..KEYS {
+            store
+                .store_secret(
+                    &namespace,
+                    format!("KEY_{}", i),
+                    format!("value-{}-{}", i, "x".repeat(64)),
+                    SecretOrigin::StoreEnv,
+                    None,
+                )
+                .expect("store_secret");
+        }
+    }
+
+    for writer in [false, true] {
+        println!(
+            "get_env of {} keys, {} requests{}",
+            KEYS,
+            REQUESTS,
+            if writer {
+                ", with a concurrent writer"
+            } else {
+                ""
+            }
+        );
+        for clients in CLIENTS {
+            let elapsed = runtime.block_on(run(&server, &namespace, clients, writer));
+            let requests = REQUESTS / clients * clients;
+            println!(
+                "  {:>4} clients: {:>9.0} requests/s ({:.2?} per request)",
+                clients,
+                requests as f64 / elapsed.as_secs_f64(),
+                elapsed / requests as u32,
+            );
+        }
+    }
+}
+
+async fn run(
+    server: &Arc<SecretsServer>,
+    namespace: &Namespace,
+    clients: usize,
+    writer: bool,
+) -> Duration {
+    let done = Arc::new(AtomicBool::new(false));
+    let writer = writer.then(|| {
+        let server = Arc::clone(server);
+        let namespace = namespace.clone();
+        let done = Arc::clone(&done);
+        tokio::spawn(async move {
+            let mut version = 0u64;
+            while !done.load(Ordering::Relaxed) {
+                version += 1;
+                let command = Command::StoreEnv {
+                    secrets: HashMap::from([("KEY_0".to_string(), version.to_string())]),
+                    ttl: None,
+                    types: HashMap::new(),
+                };
+                server
+                    .execute(&namespace, command, &Principal::Uid(0))
+                    .await
+                    .expect("store_env");
+                tokio::task::yield_now().await;
+            }
+        })
+    });
+
+    let keys: Vec<String> = (0..KEYS).map(|i| format!("KEY_{}", i)).collect();
+    let started = Instant::now();
+    let handles: Vec<_> = (0..clients)
+        .map(|_| {
+            let server = Arc::clone(server);
+            let namespace = namespace.clone();
+            let keys = keys.clone();
+            tokio::spawn(async move {
+                for _ in 0..REQUESTS / clients {
+                    let command = Command::GetEnv {
+                        keys: keys.clone(),
+                        versions: HashMap::new(),
+                    };
+                    server
+                        .execute(&namespace, command, &Principal::Uid(0))
+                        .await
+                        .expect("get_env");
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.await.expect("client");
+    }
+    let elapsed = started.elapsed();
+
+    done.store(true, Ordering::Relaxed);
+    if let Some(writer) = writer {
+        writer.await.expect("writer");
+    }
+    elapsed
+}
@@ -0,0 +1,226 @@
+use ed25519_dalek::SigningKey;
+use num_bigint::BigUint;
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, ClientConnection, StreamOwned};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+
+use crate::error::Error;
+use crate::server::identity;
+use crate::server::key_exchange::{DHKeyExchange, SessionCipher};
+use crate::types::change::ChangeEvent;
+
+pub const DEFAULT_ADDR: &str = "127.0.0.1:6000";
+
+// Speaks the daemon's handshake-then-request protocol. Every request runs
+// over a fresh connection, mirroring how the server handles them. With a TLS
+// config it talks to the TLS listener instead, skipping the key exchange.
+pub struct SecretsClient {
+    addr: String,
+    namespace: Option<String>,
+    environment: Option<String>,
+    token: Option<String>,
+    tls: Option<Arc<ClientConfig>>,
+    identity: Option<(String, SigningKey)>,
+}
+
+trait Stream: Read + Write {}
+
+impl<T: Read + Write> Stream for T {}
+
+// A connection with its request sent, and the shared secret replies are
+// sealed under, which TLS connections do without
+type Opened = (Box<dyn Stream>, Option<Vec<u8>>);
+
+impl SecretsClient {
+    pub fn new(addr: impl Into<String>) -> Self {
+        SecretsClient {
+            addr: addr.into(),
+            namespace: None,
+            environment: None,
+            token: None,
+            tls: None,
+            identity: None,
+        }
+    }
+
+    pub fn with_namespace(mut self, namespace: Option<String>) -> Self {
+        self.namespace = namespace;
+        self
+    }
+
+    pub fn with_environment(mut self, environment: Option<String>) -> Self {
+        self.environment = environment;
+        self
+    }
+
+    pub fn with_token(mut self, token: Option<String>) -> Self {
+        self.token = token;
+        self
+    }
+
+    // Signs every key exchange with a key registered on the server under
+    // `name`, so policies can refer to this client as `client:NAME`
+    pub fn with_identity(mut self, name: impl Into<String>, key: SigningKey) -> Self {
+        self.identity = Some((name.into(), key));
+        self
+    }
+
+    // See `server::tls::client_config`
+    pub fn with_tls(mut self, config: ClientConfig) -> Self {
+        self.tls = Some(Arc::new(config));
+        self
+    }
+
+    // Sends `command` (an object with a "command" field) and decodes the
+    // reply. `None` means the server acknowledged the request with nothing to
+    // return; a refused or failed request comes back as the server's error.
+    pub fn send<T: DeserializeOwned>(&self, command: Value) -> Result<Option<T>, Error> {
+        let (mut stream, shared_secret) = self.open(command)?;
+
+        let Some(encrypted) = read_frame(&mut stream)? else {
+            // An error follows the empty frame; servers that predate typed
+            // errors just close the connection
+            return match read_frame(&mut stream) {
+                Ok(Some(encrypted)) => {
+                    let decrypted = open_reply(shared_secret.as_deref(), encrypted)?;
+                    let response = serde_json::from_slice(&decrypted)
+                        .map_err(|e| Error::Protocol(e.to_string()))?;
+                    Err(Error::from_response(response))
+                }
+                Ok(None) => Ok(None),
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(None),
+                Err(e) => Err(e.into()),
+            };
+        };
+        let decrypted = open_reply(shared_secret.as_deref(), encrypted)?;
+
+        serde_json::from_slice(&decrypted)
+            .map(Some)
+            .map_err(|e| Error::Protocol(e.to_string()))
+    }
+
+    // Subscribes to changes and calls `on_event` for each one, starting with
+    // the current version of every subscribed key. Returns once the server
+    // closes the stream or `on_event` returns false.
+    pub fn watch(
+        &self,
+        keys: Vec<String>,
+        prefixes: Vec<String>,
+        mut on_event: impl FnMut(ChangeEvent) -> bool,
+    ) -> std::io::Result<()> {
+        let command = serde_json::json!({
+            "command": "watch",
+            "keys": keys,
+            "prefixes": prefixes,
+        });
+        let (mut stream, shared_secret) = self.open(command)?;
+        let mut cipher = shared_secret
+            .as_deref()
+            .map(SessionCipher::new)
+            .transpose()
+            .map_err(std::io::Error::other)?;
+
+        loop {
+            let sealed = match read_frame(&mut stream) {
+                Ok(Some(sealed)) => sealed,
+                // Keep-alive
+                Ok(None) => continue,
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            let event = match &mut cipher {
+                Some(cipher) => cipher.open(&sealed).map_err(std::io::Error::other)?,
+                None => sealed,
+            };
+            if !on_event(serde_json::from_slice(&event)?) {
+                return Ok(());
+            }
+        }
+    }
+
+    // Connects, runs the key exchange or TLS handshake and sends `command`,
+    // filling in the client's namespace and token
+    fn open(&self, mut command: Value) -> std::io::Result<Opened> {
+        if let Value::Object(fields) = &mut command {
+            for (name, value) in [
+                ("namespace", &self.namespace),
+                ("environment", &self.environment),
+                ("token", &self.token),
+            ] {
+                if let Some(value) = value {
+                    fields.insert(name.to_string(), Value::String(value.clone()));
+                }
+            }
+        }
+
+        let mut stream = TcpStream::connect(&self.addr)?;
+
+        if let Some(tls) = &self.tls {
+            let host = self
+                .addr
+                .rsplit_once(':')
+                .map_or(self.addr.as_str(), |(host, _)| host)
+                .trim_start_matches('[')
+                .trim_end_matches(']');
+            let name = ServerName::try_from(host.to_string())
+                .map_err(|e| std::io::Error::other(format!("{}: {}", host, e)))?;
+            let connection =
+                ClientConnection::new(Arc::clone(tls), name).map_err(std::io::Error::other)?;
+            let mut stream = StreamOwned::new(connection, stream);
+
+            stream.write_all(&serde_json::to_vec(&command)?)?;
+            stream.flush()?;
+            return Ok((Box::new(stream), None));
+        }
+
+        let mut server_key_length = [0u8; 4];
+        stream.read_exact(&mut server_key_length)?;
+        let mut server_public_key = vec![0u8; u32::from_be_bytes(server_key_length) as usize];
+        stream.read_exact(&mut server_public_key)?;
+
+        let dh_exchange = DHKeyExchange::new();
+        let client_public_key = dh_exchange.get_public_key().to_bytes_be();
+        stream.write_all(&(client_public_key.len() as u32).to_be_bytes())?;
+        stream.write_all(&client_public_key)?;
+
+        let shared_secret =
+            dh_exchange.compute_shared_secret(&BigUint::from_bytes_be(&server_public_key));
+
+        if let (Some((name, key)), Value::Object(fields)) = (&self.identity, &mut command) {
+            let transcript = identity::transcript(&server_public_key, &client_public_key);
+            let proof = identity::prove(name, key, &transcript, &Value::Object(fields.clone()));
+            fields.insert("identity".to_string(), serde_json::to_value(proof)?);
+        }
+
+        stream.write_all(&serde_json::to_vec(&command)?)?;
+        stream.flush()?;
+
+        Ok((Box::new(stream), Some(shared_secret)))
+    }
+}
+
+// Replies are sealed under the shared secret, except over TLS
+fn open_reply(shared_secret: Option<&[u8]>, reply: Vec<u8>) -> Result<Vec<u8>, Error> {
+    match shared_secret {
+        Some(shared_secret) => DHKeyExchange::decrypt(shared_secret, &reply),
+        None => Ok(reply),
+    }
+}
+
+// Reads one length-prefixed frame; `None` for an empty one
+fn read_frame(stream: &mut impl Read) -> std::io::Result<Option<Vec<u8>>> {
+    let mut length = [0u8; 4];
+    stream.read_exact(&mut length)?;
+    let length = u32::from_be_bytes(length) as usize;
+    if length == 0 {
+        return Ok(None);
+    }
+
+    let mut frame = vec![0u8; length];
+    stream.read_exact(&mut frame)?;
+    Ok(Some(frame))
+}
@@ -0,0 +1,92 @@
+use serde::{Deserialize, Serialize};
+
+// Every failure a request can run into. Module internals mostly report plain
+// strings; they are classified into one of these at the request boundary,
+// which decides what the client is told.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    // The client sent something the daemon cannot understand
+    #[error("protocol error: {0}")]
+    Protocol(String),
+    #[error("crypto error: {0}")]
+    Crypto(String),
+    // The store could not complete the operation, or its lock was poisoned
+    #[error("store error: {0}")]
+    Store(String),
+    // The secret source or write-back target failed
+    #[error("backend error: {0}")]
+    Backend(String),
+    // A value does not match the type declared for its key
+    #[error("invalid value: {0}")]
+    Validation(String),
+    // The caller is not allowed to do this
+    #[error("not authorized: {0}")]
+    Policy(String),
+    // The caller is over a rate limit or banned for repeated failures
+    #[error("rate limited: {0}")]
+    RateLimited(String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// What a client receives in place of a response when its request failed
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ErrorResponse {
+    pub kind: String,
+    pub message: String,
+}
+
+impl Error {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::Protocol(_) => "protocol",
+            Error::Crypto(_) => "crypto",
+            Error::Store(_) => "store",
+            Error::Backend(_) => "backend",
+            Error::Validation(_) => "validation",
+            Error::Policy(_) => "policy",
+            Error::RateLimited(_) => "rate_limited",
+            Error::Io(_) => "io",
+        }
+    }
+
+    // Whether the caller was turned away, rather than the daemon failing to
+    // do what was asked
+    pub fn is_refusal(&self) -> bool {
+        matches!(self, Error::Policy(_) | Error::RateLimited(_))
+    }
+
+    pub fn to_response(&self) -> ErrorResponse {
+        let message = match self {
+            Error::Protocol(message)
+            | Error::Crypto(message)
+            | Error::Store(message)
+            | Error::Backend(message)
+            | Error::Validation(message)
+            | Error::Policy(message)
+            | Error::RateLimited(message) => message.clone(),
+            Error::Io(e) => e.to_string(),
+        };
+
+        ErrorResponse {
+            kind: self.kind().to_string(),
+            message,
+        }
+    }
+
+    pub fn from_response(response: ErrorResponse) -> Self {
+        let ErrorResponse { kind, message } = response;
+        match kind.as_str() {
+            "protocol" => Error::Protocol(message),
+            "crypto" => Error::Crypto(message),
+            "store" => Error::Store(message),
+            "backend" => Error::Backend(message),
+            "validation" => Error::Validation(message),
+            "policy" => Error::Policy(message),
+            "rate_limited" => Error::RateLimited(message),
+            _ => Error::Io(std::io::Error::other(message)),
+        }
+    }
+}
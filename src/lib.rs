@@ -1,2 +1,4 @@
+pub mod client;
+pub mod error;
 pub mod server;
-mod types;
+pub mod types;
@@ -0,0 +1,747 @@
+use serde_json::json;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::process::ExitCode;
+
+use shinobi_secrets_server::client::{SecretsClient, DEFAULT_ADDR};
+use shinobi_secrets_server::server::identity;
+use shinobi_secrets_server::server::policy::Principal;
+use shinobi_secrets_server::server::rate_limit::RateLimitConfig;
+use shinobi_secrets_server::server::server::{GetKeysInput, SecretsServer};
+use shinobi_secrets_server::server::snapshot::{SnapshotConfig, SnapshotKey};
+use shinobi_secrets_server::server::source::{
+    DotEnvSource, EncryptedFileSource, EnvSource, LayeredSource, SecretSource, ShinobiApiSource,
+};
+use shinobi_secrets_server::server::template::{self, SignalTarget, TemplateConfig};
+use shinobi_secrets_server::server::tls::{self, TlsConfig};
+use shinobi_secrets_server::server::write_back::{WriteBackConfig, WriteMode};
+use shinobi_secrets_server::types::format::Format;
+use shinobi_secrets_server::types::namespace::Namespace;
+use shinobi_secrets_server::types::protected_secret::ProtectedSecret;
+use shinobi_secrets_server::types::secret_type::SecretType;
+use std::collections::HashMap;
+use std::path::Path;
+
+const USAGE: &str = "usage:
+  shinobi_secrets_server serve [--base-url URL --token TOKEN] --project NAME[/ENV]=TOKEN...
+        [--source SOURCE]... [--optional-source SOURCE]... [--state-key KEY]
+        [--snapshot PATH] [--snapshot-max-age SECONDS]
+        [--write-back through|back --write-queue PATH] [--write-back-interval SECONDS]
+        [--protect ENV]... [--allow-protected PRINCIPAL]... [--allow-export PRINCIPAL]...
+        [--template [PROJECT[/ENV]:]SOURCE=DEST]... [--template-signal PID|PIDFILE:SIGNAL]
+        [--http-socket PATH] [--trust uid:UID]... [--metrics-addr 127.0.0.1:PORT]
+        [--rate-limit [peer-]connections|[peer-]commands=RATE[/BURST]|off]...
+        [--ban-after FAILURES] [--ban-secs SECONDS] [--exempt-loopback yes]
+        [--timeout SECONDS] [--max-connections N]  (per TCP and TLS listener)
+        [--tls-addr HOST:PORT --tls-cert PEM --tls-key PEM --tls-client-ca PEM]
+        [--client NAME=PUBLIC_KEY]... [--admin-token TOKEN] [--admin PRINCIPAL]...
+        [--rotate-keys-secs SECONDS] [--secret-type [PROJECT[/ENV]:]KEY=TYPE]...
+        [--namespace-ttl PROJECT[/ENV]=SECONDS]...
+  shinobi_secrets_server import FILE [--format dotenv|json|yaml] [--ttl SECONDS] [CLIENT OPTIONS]
+  shinobi_secrets_server export [--format dotenv|json|shell] [--prefix P] [--pattern GLOB] [CLIENT OPTIONS]
+  shinobi_secrets_server status [CLIENT OPTIONS]  (the admin token as --token shows every namespace)
+  shinobi_secrets_server watch [--key KEY]... [--prefix P]... [CLIENT OPTIONS]
+  shinobi_secrets_server render TEMPLATE --out DEST [--signal PID|PIDFILE:SIGNAL] [CLIENT OPTIONS]
+  shinobi_secrets_server generate KEY [--format alphanumeric|charset|hex|base64|uuid|ed25519|rsa]
+        [--length N] [--charset CHARS] [--bits 2048|3072|4096] [--ttl SECONDS]
+        [--replace yes] [--write-back yes] [CLIENT OPTIONS]
+  shinobi_secrets_server keygen KEYFILE
+  shinobi_secrets_server admin ACTION [--admin-token TOKEN] [CLIENT OPTIONS]
+
+admin actions:
+  reload | purge | rotate-keys | metadata | policy | clients
+  grant|revoke trust|export|protected PRINCIPAL  protect|unprotect ENV
+  register-client NAME PUBLIC_KEY  revoke-client NAME
+
+sources, layered in order, later ones overriding earlier ones:
+  shinobi  dotenv:PATH  env:PREFIX  snapshot:PATH  (the default is shinobi alone,
+  which needs --base-url and --token, as does --write-back)
+
+state keys, sealing snapshots and the write-back queue:
+  passphrase-env:VARIABLE  keyring:PATH  key-file:PATH
+
+tokens, for --token, --project and --admin-token; never given inline, since
+any local user can read a command line:
+  env:VARIABLE  file:PATH  (a file only its owner can read)
+
+secret types:
+  string int url json pem-cert pem-private-key base64 ssh-key
+  template  (any string; ${KEY} and ${PROJECT[/ENV]:KEY} expand when read)
+
+client options:
+  --addr HOST:PORT  --namespace PROJECT[/ENV]  --environment ENV  --token TOKEN
+  --tls-ca PEM --tls-cert PEM --tls-key PEM  (to reach the TLS listener)
+  --identity NAME=KEYFILE  (sign in as a client registered with --client)";
+
+// Flags given as `--name value`, in order, plus bare positional arguments
+struct Args {
+    flags: Vec<(String, String)>,
+    positional: Vec<String>,
+}
+
+impl Args {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut flags = Vec::new();
+        let mut positional = Vec::new();
+
+        while let Some(arg) = args.next() {
+            match arg.strip_prefix("--") {
+                Some(name) => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| format!("--{} needs a value", name))?;
+                    flags.push((name.to_string(), value));
+                }
+                None => positional.push(arg),
+            }
+        }
+
+        Ok(Args { flags, positional })
+    }
+
+    fn get(&self, name: &str) -> Option<String> {
+        self.flags
+            .iter()
+            .rev()
+            .find(|(flag, _)| flag == name)
+            .map(|(_, value)| value.clone())
+    }
+
+    fn all(&self, name: &str) -> Vec<String> {
+        self.flags
+            .iter()
+            .filter(|(flag, _)| flag == name)
+            .map(|(_, value)| value.clone())
+            .collect()
+    }
+
+    fn require(&self, name: &str) -> Result<String, String> {
+        self.get(name)
+            .ok_or_else(|| format!("--{} is required", name))
+    }
+
+    fn client(&self) -> Result<SecretsClient, String> {
+        let client =
+            SecretsClient::new(self.get("addr").unwrap_or_else(|| DEFAULT_ADDR.to_string()))
+                .with_namespace(self.get("namespace"))
+                .with_environment(self.get("environment"))
+                .with_token(
+                    self.get("token")
+                        .map(|spec| read_token("--token", &spec))
+                        .transpose()?,
+                );
+
+        let client = match self.get("identity") {
+            Some(identity) => {
+                let (name, path) = identity.split_once('=').ok_or_else(|| {
+                    format!("--identity {} should look like NAME=KEYFILE", identity)
+                })?;
+                client.with_identity(name, identity::load_key_file(Path::new(path))?)
+            }
+            None => client,
+        };
+
+        match self.get("tls-ca") {
+            Some(ca) => {
+                let config = tls::client_config(
+                    Path::new(&ca),
+                    Path::new(&self.require("tls-cert")?),
+                    Path::new(&self.require("tls-key")?),
+                )?;
+                Ok(client.with_tls(config))
+            }
+            None => Ok(client),
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let mut args = std::env::args().skip(1);
+    let command = args.next();
+
+    let result = Args::parse(args).and_then(|args| match command.as_deref() {
+        Some("serve") => serve(&args),
+        Some("import") => import(&args),
+        Some("export") => export(&args),
+        Some("status") => status(&args),
+        Some("watch") => watch(&args),
+        Some("render") => render(&args),
+        Some("generate") => generate(&args),
+        Some("keygen") => keygen(&args),
+        Some("admin") => admin(&args),
+        _ => Err(USAGE.to_string()),
+    });
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn serve(args: &Args) -> Result<(), String> {
+    let sources: Vec<(String, bool)> = args
+        .flags
+        .iter()
+        .filter_map(|(flag, spec)| match flag.as_str() {
+            "source" => Some((spec.clone(), true)),
+            "optional-source" => Some((spec.clone(), false)),
+            _ => None,
+        })
+        .collect();
+    let uses_backend = sources.is_empty()
+        || sources.iter().any(|(spec, _)| spec == "shinobi")
+        || args.get("write-back").is_some();
+    let (base_url, token) = if uses_backend {
+        (
+            args.require("base-url")?,
+            read_token("--token", &args.require("token")?)?,
+        )
+    } else {
+        (String::new(), String::new())
+    };
+
+    let state_key = args
+        .get("state-key")
+        .map(|spec| parse_state_key(&spec))
+        .transpose()?;
+    let require_state_key = |flag: &str| {
+        state_key
+            .clone()
+            .ok_or_else(|| format!("{} needs --state-key to seal its file", flag))
+    };
+    let snapshot_max_age = args
+        .get("snapshot-max-age")
+        .map(|secs| {
+            secs.parse::<u64>()
+                .map_err(|_| "--snapshot-max-age must be a number of seconds".to_string())
+        })
+        .transpose()?;
+    let snapshot_config = |path: String, flag: &str| -> Result<SnapshotConfig, String> {
+        let config = SnapshotConfig::new(path, require_state_key(flag)?);
+        Ok(match snapshot_max_age {
+            Some(max_age) => config.with_max_age(max_age),
+            None => config,
+        })
+    };
+
+    let mut server = SecretsServer::new(base_url.clone(), token.clone());
+
+    if !sources.is_empty() {
+        let mut layered = LayeredSource::new();
+        for (spec, required) in sources {
+            let source: Box<dyn SecretSource> = match spec.split_once(':') {
+                None if spec == "shinobi" => Box::new(ShinobiApiSource::new(
+                    reqwest::Client::new(),
+                    base_url.clone(),
+                    token.clone(),
+                )),
+                Some(("dotenv", path)) => Box::new(DotEnvSource::new(path)),
+                Some(("env", prefix)) if !prefix.is_empty() => Box::new(EnvSource::new(prefix)),
+                Some(("snapshot", path)) => Box::new(EncryptedFileSource::new(snapshot_config(
+                    path.to_string(),
+                    "A snapshot source",
+                )?)),
+                _ => return Err(format!("Unknown source '{}'", spec)),
+            };
+            layered = if required {
+                layered.with_layer(source)
+            } else {
+                layered.with_optional_layer(source)
+            };
+        }
+        server = server.with_source(layered);
+    }
+
+    if let Some(path) = args.get("snapshot") {
+        server = server.with_snapshot(snapshot_config(path, "--snapshot")?);
+    }
+
+    if let Some(mode) = args.get("write-back") {
+        let mode = match mode.as_str() {
+            "through" => WriteMode::WriteThrough,
+            "back" => WriteMode::WriteBack,
+            _ => return Err("--write-back must be through or back".to_string()),
+        };
+        let mut config = WriteBackConfig::new(
+            mode,
+            args.require("write-queue")?,
+            require_state_key("--write-back")?,
+        );
+        if let Some(secs) = args.get("write-back-interval") {
+            config.flush_interval = secs
+                .parse()
+                .ok()
+                .filter(|secs| *secs > 0)
+                .ok_or("--write-back-interval must be a positive number of seconds")?;
+        }
+        server = server.with_write_back(config);
+    }
+
+    {
+        let mut policy = server.policy.lock().map_err(|e| e.to_string())?;
+        for environment in args.all("protect") {
+            policy.protect_environment(environment);
+        }
+        for caller in args.all("allow-protected") {
+            policy.allow_protected(caller.parse::<Principal>()?);
+        }
+        for caller in args.all("allow-export") {
+            policy.allow_export(caller.parse::<Principal>()?);
+        }
+        for caller in args.all("trust") {
+            policy.trust(caller.parse::<Principal>()?);
+        }
+        if let Some(spec) = args.get("admin-token") {
+            policy.set_admin_token(read_token("--admin-token", &spec)?);
+        }
+        for caller in args.all("admin") {
+            policy.allow_admin(caller.parse::<Principal>()?);
+        }
+    }
+
+    let mut projects = Vec::new();
+    for project in args.all("project") {
+        let (name, token) = project
+            .split_once('=')
+            .ok_or_else(|| format!("--project {} is missing a token", project))?;
+        let token = read_token(&format!("--project {}", name), token)?;
+        let namespace = Namespace::parse(name);
+        projects.push(GetKeysInput {
+            project_name: namespace.project,
+            token,
+            environment: name.contains('/').then_some(namespace.environment),
+        });
+    }
+    if projects.is_empty() {
+        return Err("At least one --project is required".to_string());
+    }
+
+    if let Some(path) = args.get("http-socket") {
+        server = server.with_http_socket(path);
+    }
+    if let Some(addr) = args.get("metrics-addr") {
+        let addr = addr
+            .parse()
+            .map_err(|_| format!("--metrics-addr {} is not an address", addr))?;
+        server = server.with_metrics_addr(addr);
+    }
+
+    // Both listeners share these. The `tls-` spellings are older names.
+    let timeout_secs = match args.get("timeout").or_else(|| args.get("tls-timeout")) {
+        Some(secs) => secs
+            .parse()
+            .ok()
+            .filter(|secs| *secs > 0)
+            .ok_or("--timeout must be a positive number of seconds")?,
+        None => server.connection_timeout_secs,
+    };
+    let max_connections = match args
+        .get("max-connections")
+        .or_else(|| args.get("tls-max-connections"))
+    {
+        Some(max) => max
+            .parse()
+            .ok()
+            .filter(|max| *max > 0)
+            .ok_or("--max-connections must be a positive number")?,
+        None => server.max_connections,
+    };
+    server = server.with_connection_limits(timeout_secs, max_connections);
+
+    if let Some(addr) = args.get("tls-addr") {
+        let addr = addr
+            .parse()
+            .map_err(|_| format!("--tls-addr {} is not an address", addr))?;
+        let tls = TlsConfig::new(
+            addr,
+            args.require("tls-cert")?,
+            args.require("tls-key")?,
+            args.require("tls-client-ca")?,
+        )
+        .with_timeout_secs(timeout_secs)
+        .with_max_connections(max_connections);
+        server = server.with_tls(tls);
+    }
+
+    for client in args.all("client") {
+        let (name, key) = client
+            .split_once('=')
+            .ok_or_else(|| format!("--client {} should look like NAME=PUBLIC_KEY", client))?;
+        server = server.with_client_identity(name, identity::parse_public_key(key)?);
+    }
+
+    let mut limits = RateLimitConfig::default();
+    for spec in args.all("rate-limit") {
+        limits = limits.with_limit(&spec)?;
+    }
+    let ban_after = match args.get("ban-after") {
+        Some(after) => after
+            .parse()
+            .map_err(|_| "--ban-after must be a number of failures")?,
+        None => limits.ban_after,
+    };
+    let ban_secs = match args.get("ban-secs") {
+        Some(secs) => secs
+            .parse()
+            .map_err(|_| "--ban-secs must be a number of seconds")?,
+        None => limits.ban_secs,
+    };
+    let exempt_loopback = args
+        .get("exempt-loopback")
+        .is_some_and(|value| matches!(value.as_str(), "yes" | "true"));
+    server = server.with_rate_limits(
+        limits
+            .with_ban(ban_after, ban_secs)
+            .with_loopback_exemption(exempt_loopback),
+    );
+
+    if let Some(secs) = args.get("rotate-keys-secs") {
+        let secs = secs
+            .parse()
+            .ok()
+            .filter(|secs| *secs > 0)
+            .ok_or("--rotate-keys-secs must be a positive number of seconds")?;
+        server = server.with_key_rotation(secs);
+    }
+
+    for spec in args.all("namespace-ttl") {
+        let (namespace, secs) = spec
+            .split_once('=')
+            .ok_or_else(|| format!("--namespace-ttl {} should look like NS=SECONDS", spec))?;
+        let secs = secs
+            .parse()
+            .ok()
+            .filter(|secs| *secs > 0)
+            .ok_or("--namespace-ttl must be a positive number of seconds")?;
+        server = server.with_namespace_ttl(&Namespace::parse(namespace), secs);
+    }
+
+    // Like templates, types without a namespace apply to the first project
+    for spec in args.all("secret-type") {
+        let (namespace, declaration) = match spec.split_once(':') {
+            Some((namespace, declaration)) => (Namespace::parse(namespace), declaration),
+            None => (projects[0].namespace(), spec.as_str()),
+        };
+        let (key, kind) = declaration
+            .split_once('=')
+            .ok_or_else(|| format!("--secret-type {} should look like KEY=TYPE", spec))?;
+        server = server.with_secret_type(&namespace, key, SecretType::parse_name(kind)?);
+    }
+
+    // Templates without a namespace render from the first project
+    let signal = args
+        .get("template-signal")
+        .map(|target| SignalTarget::parse(&target))
+        .transpose()?;
+    for spec in args.all("template") {
+        let (namespace, paths) = match spec.split_once(':') {
+            Some((namespace, paths)) => (Namespace::parse(namespace), paths),
+            None => (projects[0].namespace(), spec.as_str()),
+        };
+        let (source, destination) = paths
+            .split_once('=')
+            .ok_or_else(|| format!("--template {} should look like SOURCE=DEST", spec))?;
+
+        let mut config = TemplateConfig::new(source, destination, namespace);
+        if let Some(signal) = &signal {
+            config = config.with_signal(signal.clone());
+        }
+        server = server.with_template(config);
+    }
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .map_err(|e| e.to_string())?;
+
+    let result = runtime
+        .block_on(server.run(projects))
+        .map_err(|e| e.to_string());
+
+    // The store is already wiped; a handler stuck on a slow client must not
+    // hold up the exit
+    runtime.shutdown_background();
+    result
+}
+
+fn parse_state_key(spec: &str) -> Result<SnapshotKey, String> {
+    match spec.split_once(':') {
+        Some(("passphrase-env", variable)) => std::env::var(variable)
+            .map(SnapshotKey::Passphrase)
+            .map_err(|_| format!("${} is not set", variable)),
+        Some(("keyring", path)) => Ok(SnapshotKey::Keyring(path.into())),
+        Some(("key-file", path)) => Ok(SnapshotKey::KeyFile(path.into())),
+        _ => Err(format!(
+            "--state-key {} should be passphrase-env:VARIABLE, keyring:PATH or key-file:PATH",
+            spec
+        )),
+    }
+}
+
+// Tokens come from the environment or a file only the owner can read. Any
+// local user can read a command line, from `ps` or /proc.
+fn read_token(flag: &str, spec: &str) -> Result<String, String> {
+    let token = match spec.split_once(':') {
+        Some(("env", variable)) => {
+            std::env::var(variable).map_err(|_| format!("${} is not set", variable))?
+        }
+        Some(("file", path)) => {
+            let mut file = File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+            let mode = file
+                .metadata()
+                .map_err(|e| format!("{}: {}", path, e))?
+                .permissions()
+                .mode();
+            if mode & 0o077 != 0 {
+                return Err(format!("{} can be read by other users; make it 0600", path));
+            }
+            let mut token = String::new();
+            file.read_to_string(&mut token)
+                .map_err(|e| format!("{}: {}", path, e))?;
+            token
+        }
+        _ => {
+            return Err(format!(
+                "{} should be env:VARIABLE or file:PATH, not the token itself",
+                flag
+            ))
+        }
+    };
+
+    let token = token.trim();
+    if token.is_empty() {
+        return Err(format!("{} {} is empty", flag, spec));
+    }
+    Ok(token.to_string())
+}
+
+fn import(args: &Args) -> Result<(), String> {
+    let path = args
+        .positional
+        .first()
+        .ok_or("import needs a file to read")?;
+    let format = match args.get("format") {
+        Some(format) => Format::parse_name(&format)?,
+        None => Format::from_path(path),
+    };
+    let content = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+
+    let mut command = json!({
+        "command": "import",
+        "format": format.name(),
+        "content": content,
+    });
+    if let Some(ttl) = args.get("ttl") {
+        let ttl: u64 = ttl
+            .parse()
+            .map_err(|_| "--ttl must be a number of seconds")?;
+        command["ttl"] = json!(ttl);
+    }
+
+    let imported: Vec<String> = args
+        .client()?
+        .send(command)
+        .map_err(|e| e.to_string())?
+        .ok_or("The server refused the import")?;
+
+    println!("Imported {} keys", imported.len());
+    Ok(())
+}
+
+fn export(args: &Args) -> Result<(), String> {
+    let format = Format::parse_name(&args.get("format").unwrap_or_else(|| "dotenv".into()))?;
+
+    let mut command = json!({
+        "command": "export",
+        "format": format.name(),
+    });
+    for name in ["prefix", "pattern"] {
+        if let Some(value) = args.get(name) {
+            command[name] = json!(value);
+        }
+    }
+
+    let rendered: String = args
+        .client()?
+        .send(command)
+        .map_err(|e| e.to_string())?
+        .ok_or("The server refused the export")?;
+
+    std::io::stdout()
+        .write_all(rendered.as_bytes())
+        .map_err(|e| e.to_string())
+}
+
+// Prints the daemon's status. Fails while the daemon is unreachable or not
+// yet ready, so it doubles as a liveness and readiness probe.
+fn status(args: &Args) -> Result<(), String> {
+    let status: serde_json::Value = args
+        .client()?
+        .send(json!({ "command": "status" }))
+        .map_err(|e| e.to_string())?
+        .ok_or("The server did not report its status")?;
+
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&status).map_err(|e| e.to_string())?
+    );
+
+    if status["ready"] == json!(true) {
+        Ok(())
+    } else {
+        Err("The server is not ready".to_string())
+    }
+}
+
+// Prints one JSON line per change until the daemon goes away or stdout closes
+fn watch(args: &Args) -> Result<(), String> {
+    let mut stdout = std::io::stdout();
+
+    args.client()?
+        .watch(
+            args.all("key"),
+            args.all("prefix"),
+            |event| match serde_json::to_string(&event) {
+                Ok(line) => writeln!(stdout, "{}", line)
+                    .and_then(|_| stdout.flush())
+                    .is_ok(),
+                Err(_) => false,
+            },
+        )
+        .map_err(|e| e.to_string())
+}
+
+fn render(args: &Args) -> Result<(), String> {
+    let path = args
+        .positional
+        .first()
+        .ok_or("render needs a template to read")?;
+    let destination = args.require("out")?;
+    let source = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+
+    let keys = template::placeholders(&source)?;
+    let secrets: HashMap<String, ProtectedSecret> = args
+        .client()?
+        .send(json!({ "command": "get_env", "keys": keys }))
+        .map_err(|e| e.to_string())?
+        .ok_or("The server refused the request")?;
+
+    let mut rendered = template::render(&source, |key| {
+        secrets
+            .get(key)
+            .and_then(ProtectedSecret::get_value)
+            .map(|value| (**value).to_string())
+    })?
+    .into_bytes();
+    let result = template::write_output(destination.as_ref(), &rendered);
+    rendered.fill(0);
+    result?;
+
+    if let Some(target) = args.get("signal") {
+        SignalTarget::parse(&target)?.send()?;
+    }
+
+    Ok(())
+}
+
+// Has the daemon create a secret in place. Prints its version, and the
+// public key for key pairs; the value itself is never sent back.
+fn generate(args: &Args) -> Result<(), String> {
+    let key = args
+        .positional
+        .first()
+        .ok_or("generate needs the key to create")?;
+
+    let mut command = json!({
+        "command": "generate",
+        "key": key,
+        "format": args.get("format").unwrap_or_else(|| "alphanumeric".into()),
+    });
+    for name in ["length", "bits", "ttl"] {
+        if let Some(value) = args.get(name) {
+            let number: u64 = value
+                .parse()
+                .map_err(|_| format!("--{} must be a number", name))?;
+            command[name] = json!(number);
+        }
+    }
+    if let Some(charset) = args.get("charset") {
+        command["charset"] = json!(charset);
+    }
+    for name in ["replace", "write-back"] {
+        if let Some(value) = args.get(name) {
+            command[name.replace('-', "_")] = json!(matches!(value.as_str(), "yes" | "true"));
+        }
+    }
+
+    let generated: serde_json::Value = args
+        .client()?
+        .send(command)
+        .map_err(|e| e.to_string())?
+        .ok_or("The server did not confirm the new secret")?;
+
+    println!("Generated {} version {}", key, generated["version"]);
+    if let Some(public_key) = generated["public_key"].as_str() {
+        print!("{}", public_key);
+    }
+    Ok(())
+}
+
+// Creates an identity key for `--identity` and prints the public key to
+// register with the daemon's `--client`
+fn keygen(args: &Args) -> Result<(), String> {
+    let path = args
+        .positional
+        .first()
+        .ok_or("keygen needs a file to write the key to")?;
+    let public_key = identity::generate_key_file(Path::new(path))?;
+    println!("{}", identity::encode_public_key(&public_key));
+    Ok(())
+}
+
+// Sends one admin command and prints what it returns
+fn admin(args: &Args) -> Result<(), String> {
+    let words: Vec<&str> = args.positional.iter().map(String::as_str).collect();
+    let mut command = match words.as_slice() {
+        ["reload"] => json!({ "action": "reload" }),
+        ["purge"] => json!({ "action": "purge" }),
+        ["rotate-keys"] => json!({ "action": "rotate_keys" }),
+        ["metadata"] => json!({ "action": "dump_metadata" }),
+        ["policy"] => json!({ "action": "show_policy" }),
+        ["clients"] => json!({ "action": "list_clients" }),
+        [action @ ("grant" | "revoke"), permission, principal] => json!({
+            "action": action,
+            "permission": permission,
+            "principal": principal,
+        }),
+        [action @ ("protect" | "unprotect"), environment] => json!({
+            "action": action,
+            "environment": environment,
+        }),
+        ["register-client", name, public_key] => json!({
+            "action": "register_client",
+            "name": name,
+            "public_key": public_key,
+        }),
+        ["revoke-client", name] => json!({ "action": "revoke_client", "name": name }),
+        _ => return Err(USAGE.to_string()),
+    };
+    command["command"] = json!("admin");
+    if let Some(spec) = args.get("admin-token") {
+        command["admin_token"] = json!(read_token("--admin-token", &spec)?);
+    }
+
+    let response: Option<serde_json::Value> =
+        args.client()?.send(command).map_err(|e| e.to_string())?;
+    if let Some(response) = response {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&response).map_err(|e| e.to_string())?
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,209 @@
+use log::info;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::sync::MutexGuard;
+use std::time::Instant;
+
+use crate::error::Error;
+use crate::server::audit;
+use crate::server::identity;
+use crate::server::policy::{AccessPolicy, Principal};
+use crate::server::rate_limit::Limit;
+use crate::server::server::{to_response, SecretsServer};
+use crate::types::admin::AdminAction;
+use crate::types::key_filter::KeyFilter;
+use crate::types::namespace::Namespace;
+
+// Admin commands act on the daemon as a whole, so no project token unlocks
+// them. A caller needs the admin token set with `--admin-token`, or to be
+// one of the principals given with `--admin`, such as `uid:0` on the Unix
+// socket or a registered `client:NAME`. Changes made here last until the
+// daemon restarts; the command line is still what a fresh start uses.
+impl SecretsServer {
+    pub fn with_admin_token(self, token: impl Into<String>) -> Self {
+        if let Ok(mut policy) = self.policy.lock() {
+            policy.set_admin_token(token.into());
+        }
+        self
+    }
+
+    pub fn with_admin(self, caller: Principal) -> Self {
+        if let Ok(mut policy) = self.policy.lock() {
+            policy.allow_admin(caller);
+        }
+        self
+    }
+
+    // Checks the admin credential and runs `action`. Every admin command is
+    // audited under its own target, refused or not, and refusals count
+    // towards a ban like any other.
+    pub async fn administer(
+        &self,
+        action: AdminAction,
+        token: Option<&str>,
+        caller: &Principal,
+    ) -> Result<Option<Value>, Error> {
+        let name = action.name();
+
+        let authorized = self.admit(caller, Limit::Command).and_then(|()| {
+            self.policy
+                .lock()
+                .map_err(|e| Error::Store(format!("Error locking policy: {}", e)))?
+                .authorize_admin(token, caller)
+                .map_err(Error::Policy)
+        });
+        if let Err(e) = authorized {
+            if let Error::Policy(_) = e {
+                self.record_failure(caller);
+            }
+            audit::admin(caller, name, Err(&e));
+            self.metrics
+                .record_command(name, if e.is_refusal() { "refused" } else { "error" });
+            return Err(e);
+        }
+
+        info!("{}", name.to_uppercase());
+        let started = Instant::now();
+        let result = self.run_admin(action, caller).await;
+        self.metrics.observe_command(name, started.elapsed());
+        self.metrics
+            .record_command(name, if result.is_ok() { "ok" } else { "error" });
+        audit::admin(caller, name, result.as_ref().map(|_| ()));
+        result
+    }
+
+    async fn run_admin(
+        &self,
+        action: AdminAction,
+        caller: &Principal,
+    ) -> Result<Option<Value>, Error> {
+        match action {
+            AdminAction::Reload => {
+                let failed = self.reload().await;
+                Ok(Some(json!({
+                    "reloaded": self.projects.len() - failed,
+                    "failed": failed,
+                })))
+            }
+
+            AdminAction::Purge => {
+                let purged = self
+                    .store
+                    .write()
+                    .map_err(|e| Error::Store(format!("Error locking store: {}", e)))?
+                    .purge_expired();
+                Ok(Some(json!({ "purged": purged })))
+            }
+
+            AdminAction::RotateKeys => {
+                let rotated = self.rotate_keys(&caller.to_string())?;
+                Ok(Some(json!({ "rotated": rotated })))
+            }
+
+            AdminAction::DumpMetadata => {
+                let store = self
+                    .store
+                    .read()
+                    .map_err(|e| Error::Store(format!("Error locking store: {}", e)))?;
+                let metadata: BTreeMap<String, _> = store
+                    .namespaces()
+                    .map(|namespace| {
+                        let keys = store.list_keys(namespace, &KeyFilter::default());
+                        (namespace.to_string(), keys)
+                    })
+                    .collect();
+                to_response(&metadata)
+            }
+
+            AdminAction::ShowPolicy => to_response(&self.lock_policy()?.describe()),
+
+            AdminAction::Grant {
+                permission,
+                principal,
+            } => {
+                let caller = principal.parse::<Principal>().map_err(Error::Protocol)?;
+                self.lock_policy()?.grant(permission, caller);
+                Ok(None)
+            }
+
+            AdminAction::Revoke {
+                permission,
+                principal,
+            } => {
+                let caller = principal.parse::<Principal>().map_err(Error::Protocol)?;
+                if !self.lock_policy()?.revoke(permission, &caller) {
+                    return Err(Error::Protocol(format!(
+                        "{} has no {} grant",
+                        caller,
+                        permission.name()
+                    )));
+                }
+                Ok(None)
+            }
+
+            AdminAction::Protect { environment } => {
+                self.lock_policy()?.protect_environment(environment);
+                Ok(None)
+            }
+
+            AdminAction::Unprotect { environment } => {
+                if !self.lock_policy()?.unprotect_environment(&environment) {
+                    return Err(Error::Protocol(format!(
+                        "Environment '{}' is not protected",
+                        environment
+                    )));
+                }
+                Ok(None)
+            }
+
+            AdminAction::ListClients => {
+                let names = self
+                    .identities
+                    .read()
+                    .map_err(|e| Error::Policy(format!("Error locking client identities: {}", e)))?
+                    .names();
+                Ok(Some(json!({ "clients": names })))
+            }
+
+            AdminAction::RegisterClient { name, public_key } => {
+                let key = identity::parse_public_key(&public_key).map_err(Error::Protocol)?;
+                self.identities
+                    .write()
+                    .map_err(|e| Error::Policy(format!("Error locking client identities: {}", e)))?
+                    .register(name, key);
+                Ok(None)
+            }
+
+            AdminAction::RevokeClient { name } => {
+                let revoked = self
+                    .identities
+                    .write()
+                    .map_err(|e| Error::Policy(format!("Error locking client identities: {}", e)))?
+                    .revoke(&name);
+                if !revoked {
+                    return Err(Error::Protocol(format!("Unknown client '{}'", name)));
+                }
+                Ok(None)
+            }
+        }
+    }
+
+    pub(crate) fn lock_policy(&self) -> Result<MutexGuard<'_, AccessPolicy>, Error> {
+        self.policy
+            .lock()
+            .map_err(|e| Error::Policy(format!("Error locking policy: {}", e)))
+    }
+
+    // Whether `caller`, reading `from`, may follow a reference into `to`.
+    // The policy is only held for the check, never while values resolve.
+    pub(crate) fn authorize_reference(
+        &self,
+        from: &Namespace,
+        to: &Namespace,
+        caller: &Principal,
+    ) -> Result<(), Error> {
+        self.lock_policy()?
+            .authorize_reference(from, to, caller)
+            .map_err(Error::Policy)
+    }
+}
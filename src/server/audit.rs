@@ -0,0 +1,126 @@
+use log::{info, warn};
+
+use crate::error::Error;
+use crate::server::policy::Principal;
+use crate::types::namespace::Namespace;
+
+// Audit records go to their own log target so they can be routed or kept
+// separately, e.g. with `RUST_LOG=audit=info`. They name who did what to
+// which namespace, never any values.
+pub const AUDIT_TARGET: &str = "audit";
+// Admin commands get a target of their own under `audit`, so
+// `RUST_LOG=audit::admin=info` keeps just the privileged operations
+pub const ADMIN_AUDIT_TARGET: &str = "audit::admin";
+
+// A failure is `refused` when the caller was turned away and `error` when
+// the daemon failed to do what it was asked
+fn failure(e: &Error) -> &'static str {
+    if e.is_refusal() {
+        "refused"
+    } else {
+        "error"
+    }
+}
+
+pub fn record(
+    caller: &Principal,
+    namespace: Option<&Namespace>,
+    action: &str,
+    outcome: Result<(), &Error>,
+) {
+    let namespace = namespace.map_or_else(|| "-".to_string(), Namespace::to_string);
+
+    match outcome {
+        Ok(()) => info!(
+            target: AUDIT_TARGET,
+            "caller={} namespace={} action={} outcome=ok", caller, namespace, action
+        ),
+        Err(e) => warn!(
+            target: AUDIT_TARGET,
+            "caller={} namespace={} action={} outcome={} reason={:?}",
+            caller,
+            namespace,
+            action,
+            failure(e),
+            e.to_string()
+        ),
+    }
+}
+
+// A caller refused for a while after too many failed handshakes or refused
+// requests
+pub fn ban(caller: &Principal, failures: usize, ban_secs: u64) {
+    warn!(
+        target: AUDIT_TARGET,
+        "caller={} action=ban failures={} duration={}s", caller, failures, ban_secs
+    );
+}
+
+// A connection or command turned away by a rate limit or a ban
+pub fn rate_limited(caller: &Principal, limit: &str, reason: &str) {
+    warn!(
+        target: AUDIT_TARGET,
+        "caller={} action=rate_limit limit={} outcome=refused reason={:?}",
+        caller,
+        limit,
+        reason
+    );
+}
+
+pub fn admin(caller: &Principal, action: &str, outcome: Result<(), &Error>) {
+    match outcome {
+        Ok(()) => info!(
+            target: ADMIN_AUDIT_TARGET,
+            "caller={} action={} outcome=ok", caller, action
+        ),
+        Err(e) => warn!(
+            target: ADMIN_AUDIT_TARGET,
+            "caller={} action={} outcome={} reason={:?}",
+            caller,
+            action,
+            failure(e),
+            e.to_string()
+        ),
+    }
+}
+
+// Every secret was resealed under fresh keys. `trigger` is "schedule" or the
+// admin command's caller.
+pub fn key_rotation(trigger: &str, outcome: Result<usize, &str>) {
+    match outcome {
+        Ok(blocks) => info!(
+            target: AUDIT_TARGET,
+            "trigger={} action=rotate_keys blocks={} outcome=ok", trigger, blocks
+        ),
+        Err(reason) => warn!(
+            target: AUDIT_TARGET,
+            "trigger={} action=rotate_keys outcome=error reason={:?}", trigger, reason
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_refusals_are_audited_as_refused() {
+        let refused = [
+            Error::Policy("not an admin".to_string()),
+            Error::RateLimited("banned".to_string()),
+        ];
+        for e in &refused {
+            assert_eq!(failure(e), "refused", "{}", e);
+        }
+
+        let failed = [
+            Error::Store("locked".to_string()),
+            Error::Backend("unreachable".to_string()),
+            Error::Protocol("malformed".to_string()),
+            Error::Io(std::io::Error::other("reset")),
+        ];
+        for e in &failed {
+            assert_eq!(failure(e), "error", "{}", e);
+        }
+    }
+}
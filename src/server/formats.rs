@@ -0,0 +1,392 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+
+use crate::types::format::Format;
+
+// Reads a flat key/value map in any importable format. Shell input is read
+// as `.env`, which already accepts `export KEY=value` lines.
+pub fn import(format: Format, content: &str) -> Result<HashMap<String, String>, String> {
+    match format {
+        Format::Dotenv | Format::Shell => parse_dotenv(content),
+        Format::Json => {
+            let map: BTreeMap<String, serde_json::Value> =
+                serde_json::from_str(content).map_err(|e| e.to_string())?;
+            map.into_iter()
+                .map(|(key, value)| match value {
+                    serde_json::Value::String(value) => Ok((key, value)),
+                    serde_json::Value::Number(n) => Ok((key, n.to_string())),
+                    serde_json::Value::Bool(b) => Ok((key, b.to_string())),
+                    _ => Err(format!("'{}' is not a scalar value", key)),
+                })
+                .collect()
+        }
+        Format::Yaml => {
+            let map: BTreeMap<String, serde_yaml::Value> =
+                serde_yaml::from_str(content).map_err(|e| e.to_string())?;
+            map.into_iter()
+                .map(|(key, value)| match value {
+                    serde_yaml::Value::String(value) => Ok((key, value)),
+                    serde_yaml::Value::Number(n) => Ok((key, n.to_string())),
+                    serde_yaml::Value::Bool(b) => Ok((key, b.to_string())),
+                    _ => Err(format!("'{}' is not a scalar value", key)),
+                })
+                .collect()
+        }
+    }
+}
+
+// Renders secrets sorted by key. YAML is accepted on import only.
+pub fn export(format: Format, secrets: &BTreeMap<String, String>) -> Result<String, String> {
+    let mut out = String::new();
+
+    match format {
+        Format::Json => {
+            out = serde_json::to_string_pretty(secrets).map_err(|e| e.to_string())?;
+            out.push('\n');
+        }
+        Format::Dotenv => {
+            for (key, value) in secrets {
+                check_key(key)?;
+                let _ = writeln!(out, "{}=\"{}\"", key, escape_double_quoted(value));
+            }
+        }
+        Format::Shell => {
+            for (key, value) in secrets {
+                check_key(key)?;
+                let _ = writeln!(out, "export {}='{}'", key, value.replace('\'', "'\\''"));
+            }
+        }
+        Format::Yaml => return Err("YAML export is not supported".to_string()),
+    }
+
+    Ok(out)
+}
+
+// Keys end up as shell variable names, so keep them to identifiers
+fn check_key(key: &str) -> Result<(), String> {
+    let mut chars = key.chars();
+    let valid = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+
+    if valid {
+        Ok(())
+    } else {
+        Err(format!("'{}' is not a valid variable name", key))
+    }
+}
+
+fn escape_double_quoted(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '$' => escaped.push_str("\\$"),
+            '`' => escaped.push_str("\\`"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Parses `.env` content. Supports `#` comments, an optional `export` prefix,
+// unquoted values (trimmed, with a `#` after whitespace starting a trailing
+// comment), single quoted values taken literally and double quoted values
+// with backslash escapes. Both quoted forms may span several lines, and
+// adjacent quoted parts join up as in the shell, so the `'it'\''s'` that
+// shell export writes reads back as `it's`.
+pub fn parse_dotenv(content: &str) -> Result<HashMap<String, String>, String> {
+    let mut secrets = HashMap::new();
+    let mut chars = content.chars().peekable();
+    let mut line = 1;
+
+    loop {
+        // Skip blank lines, indentation and comment lines
+        while let Some(&c) = chars.peek() {
+            match c {
+                '\n' => {
+                    line += 1;
+                    chars.next();
+                }
+                c if c.is_whitespace() => {
+                    chars.next();
+                }
+                '#' => while chars.next_if(|&c| c != '\n').is_some() {},
+                _ => break,
+            }
+        }
+
+        if chars.peek().is_none() {
+            break;
+        }
+
+        let mut key = String::new();
+        while let Some(c) = chars.next_if(|&c| c != '=' && c != '\n') {
+            key.push(c);
+        }
+        let mut key = key.trim();
+        if let Some(rest) = key
+            .strip_prefix("export")
+            .filter(|rest| rest.starts_with([' ', '\t']))
+        {
+            key = rest.trim_start();
+        }
+
+        if chars.next() != Some('=') {
+            return Err(format!("line {}: expected KEY=VALUE", line));
+        }
+        if key.is_empty()
+            || !key
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+        {
+            return Err(format!("line {}: invalid key '{}'", line, key));
+        }
+
+        let mut spaced = false;
+        while chars.next_if(|&c| c == ' ' || c == '\t').is_some() {
+            spaced = true;
+        }
+
+        let value = match chars.peek() {
+            Some('\'' | '"') => {
+                let mut value = String::new();
+                loop {
+                    match chars.peek() {
+                        Some('\'') => {
+                            chars.next();
+                            read_single_quoted(&mut chars, &mut line, &mut value)?;
+                        }
+                        Some('"') => {
+                            chars.next();
+                            read_double_quoted(&mut chars, &mut line, &mut value)?;
+                        }
+                        // An escaped character between quoted parts
+                        Some('\\') => {
+                            chars.next();
+                            match chars.next_if(|&c| c != '\n') {
+                                Some(c) => value.push(c),
+                                None => {
+                                    return Err(format!(
+                                        "line {}: unexpected text after quoted value",
+                                        line
+                                    ))
+                                }
+                            }
+                        }
+                        _ => break,
+                    }
+                }
+                skip_trailing(&mut chars, line)?;
+                value
+            }
+            _ => {
+                let mut value = String::new();
+                while let Some(c) = chars.next_if(|&c| c != '\n') {
+                    let after_space = value.ends_with([' ', '\t']) || (value.is_empty() && spaced);
+                    if c == '#' && after_space {
+                        while chars.next_if(|&c| c != '\n').is_some() {}
+                        break;
+                    }
+                    value.push(c);
+                }
+                value.trim_end().to_string()
+            }
+        };
+
+        secrets.insert(key.to_string(), value);
+    }
+
+    Ok(secrets)
+}
+
+// Reads up to and including the closing `'`, taking everything literally
+fn read_single_quoted(
+    chars: &mut std::iter::Peekable<std::str::Chars<'_>>,
+    line: &mut usize,
+    value: &mut String,
+) -> Result<(), String> {
+    loop {
+        match chars.next() {
+            Some('\'') => return Ok(()),
+            Some(c) => {
+                if c == '\n' {
+                    *line += 1;
+                }
+                value.push(c);
+            }
+            None => return Err(format!("line {}: unterminated quote", line)),
+        }
+    }
+}
+
+// Reads up to and including the closing `"`, resolving backslash escapes
+fn read_double_quoted(
+    chars: &mut std::iter::Peekable<std::str::Chars<'_>>,
+    line: &mut usize,
+    value: &mut String,
+) -> Result<(), String> {
+    loop {
+        match chars.next() {
+            Some('"') => return Ok(()),
+            Some('\\') => match chars.next() {
+                Some('n') => value.push('\n'),
+                Some('r') => value.push('\r'),
+                Some('t') => value.push('\t'),
+                Some(c @ ('"' | '\\' | '$' | '\'' | '`')) => value.push(c),
+                Some('\n') => *line += 1,
+                Some(c) => {
+                    value.push('\\');
+                    value.push(c);
+                }
+                None => return Err(format!("line {}: unterminated quote", line)),
+            },
+            Some(c) => {
+                if c == '\n' {
+                    *line += 1;
+                }
+                value.push(c);
+            }
+            None => return Err(format!("line {}: unterminated quote", line)),
+        }
+    }
+}
+
+// Only whitespace or a comment may follow a closing quote
+fn skip_trailing(
+    chars: &mut std::iter::Peekable<std::str::Chars<'_>>,
+    line: usize,
+) -> Result<(), String> {
+    while chars.next_if(|&c| c == ' ' || c == '\t').is_some() {}
+
+    match chars.peek() {
+        None | Some('\n') => Ok(()),
+        Some('#') => {
+            while chars.next_if(|&c| c != '\n').is_some() {}
+            Ok(())
+        }
+        Some(_) => Err(format!("line {}: unexpected text after quoted value", line)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Values that need every kind of quoting and escaping
+    const AWKWARD: &[(&str, &str)] = &[
+        ("PLAIN", "value"),
+        ("EMPTY", ""),
+        ("SPACES", "  padded value  "),
+        ("SINGLE", "it's"),
+        ("DOUBLE", "say \"hi\""),
+        ("SHELLY", "$HOME `id` $(id) \\ !"),
+        ("MULTILINE", "line one\nline two\r\n\tindented"),
+        ("HASH", "# not a comment"),
+        ("ADJACENT", "'\"'\"'"),
+        ("UNICODE", "pässwörd ✓"),
+    ];
+
+    fn secrets(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn every_export_format_reads_back() {
+        let secrets = secrets(AWKWARD);
+        for format in [Format::Dotenv, Format::Json, Format::Shell] {
+            let exported = export(format, &secrets).unwrap();
+            let imported: BTreeMap<String, String> =
+                import(format, &exported).unwrap().into_iter().collect();
+            assert_eq!(imported, secrets, "{:?}:\n{}", format, exported);
+        }
+    }
+
+    #[test]
+    fn yaml_is_imported_but_not_exported() {
+        let imported = import(Format::Yaml, "A: one\nB: 2\nC: true\n").unwrap();
+        assert_eq!(imported["A"], "one");
+        assert_eq!(imported["B"], "2");
+        assert_eq!(imported["C"], "true");
+        assert!(export(Format::Yaml, &secrets(&[("A", "1")])).is_err());
+    }
+
+    #[test]
+    fn export_refuses_keys_that_are_not_variable_names() {
+        for key in ["1ABC", "A-B", "A B", "", "A.B"] {
+            let secrets = secrets(&[(key, "x")]);
+            assert!(export(Format::Dotenv, &secrets).is_err(), "{}", key);
+            assert!(export(Format::Shell, &secrets).is_err(), "{}", key);
+        }
+    }
+
+    #[test]
+    fn dotenv_lines_parse() {
+        let cases: &[(&str, &str)] = &[
+            ("KEY=value", "value"),
+            ("KEY = value  ", "value"),
+            ("KEY=", ""),
+            ("KEY= #comment", ""),
+            ("KEY=\t# comment", ""),
+            ("KEY=#not-a-comment", "#not-a-comment"),
+            ("KEY=a#b", "a#b"),
+            ("KEY=value # comment", "value"),
+            ("KEY=value\t# comment", "value"),
+            ("export KEY=value", "value"),
+            ("export\tKEY=value", "value"),
+            ("  export   KEY=value", "value"),
+            ("KEY='single $HOME \\n'", "single $HOME \\n"),
+            ("KEY='a # b' # comment", "a # b"),
+            ("KEY=\"a\\nb\\t\\\"c\\\" \\$d \\\\\"", "a\nb\t\"c\" $d \\"),
+            ("KEY=\"unknown \\q\"", "unknown \\q"),
+            ("KEY='multi\nline'", "multi\nline"),
+            ("KEY=\"multi\nline\"", "multi\nline"),
+            ("KEY=\"joined \\\nline\"", "joined line"),
+            ("KEY='it'\\''s'", "it's"),
+            ("KEY='a'\"b\"'c'", "abc"),
+        ];
+        for (line, expected) in cases {
+            let parsed = parse_dotenv(line).unwrap_or_else(|e| panic!("{}: {}", line, e));
+            assert_eq!(parsed.len(), 1, "{}", line);
+            assert_eq!(parsed["KEY"], *expected, "{}", line);
+        }
+
+        let parsed = parse_dotenv("export=1\nexported=2").unwrap();
+        assert_eq!(parsed["export"], "1");
+        assert_eq!(parsed["exported"], "2");
+    }
+
+    #[test]
+    fn comments_and_blank_lines_are_skipped() {
+        let parsed = parse_dotenv("# header\n\n  \nA=1\n  # indented\nB=2\n").unwrap();
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed["A"], "1");
+        assert_eq!(parsed["B"], "2");
+    }
+
+    #[test]
+    fn malformed_dotenv_is_refused_with_its_line() {
+        let cases: &[(&str, &str)] = &[
+            ("A=1\nNOEQUALS", "line 2"),
+            ("A=1\n=value", "line 2"),
+            ("A B=1", "line 1"),
+            ("A='open", "line 1"),
+            ("A=\"open\\", "line 1"),
+            ("A='x'y", "line 1"),
+            ("A='x'\\\n", "line 1"),
+            ("A='one\ntwo'\nB=\"x\" y", "line 3"),
+        ];
+        for (content, line) in cases {
+            let error = parse_dotenv(content).unwrap_err();
+            assert!(error.starts_with(line), "{:?}: {}", content, error);
+        }
+    }
+}
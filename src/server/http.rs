@@ -0,0 +1,367 @@
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request as HttpRequest, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use log::{error, info};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::net::UnixListener;
+
+use crate::error::Error;
+use crate::server::lifecycle::{stopped, Inflight, StopSignal};
+use crate::server::policy::Principal;
+use crate::server::rate_limit::Limit;
+use crate::server::server::{SecretsServer, MAX_REQUEST_SIZE};
+use crate::types::key_filter::KeyFilter;
+use crate::types::request::{Command, Request};
+
+type HttpResponse = Response<Full<Bytes>>;
+
+// The HTTP/JSON API mirrors the TCP protocol for clients that would rather
+// not implement the key exchange. It only listens on a Unix socket, where the
+// kernel tells us the caller's uid.
+//
+//   GET    /v1/health               liveness: 200 while the daemon answers
+//   GET    /v1/ready                readiness: 503 until every project is loaded
+//   GET    /v1/status               uptime and readiness; with a token, also
+//                                   sync state and counters for its namespace,
+//                                   or for all of them with the admin token
+//   GET    /v1/secrets?keys=A,B     get_env
+//   PUT    /v1/secrets              store_env, body {"secrets": {...}, "ttl": N,
+//                                   "types": {"KEY": "pem-cert", ...}}
+//   DELETE /v1/secrets?keys=A,B     delete_env
+//   GET    /v1/keys?prefix=P        list_keys
+//   POST   /v1/command              any request in the TCP protocol's JSON form
+//
+// `namespace` and `environment` query parameters pick the namespace. The
+// project token goes in `Authorization: Bearer`, unless the caller's uid is
+// trusted.
+//
+// Admin commands go through `/v1/command` too. They take the admin token as
+// `admin_token` or in `Authorization: Bearer`, unless the caller's uid is
+// an admin.
+//
+// Failures from the command path answer `{"error": message, "kind": kind}`,
+// with the status code following the kind.
+impl SecretsServer {
+    pub fn with_http_socket(mut self, path: impl Into<PathBuf>) -> Self {
+        self.http_socket = Some(path.into());
+        self
+    }
+
+    // Serves until shutdown starts, then drains the open connections
+    pub async fn serve_http(
+        self: Arc<Self>,
+        path: &Path,
+        mut stop: StopSignal,
+    ) -> std::io::Result<()> {
+        // A socket left behind by a previous run would make bind fail
+        if std::fs::symlink_metadata(path).is_ok() {
+            std::fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        // Owner only; widen with group ownership if other users should connect
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+        info!("HTTP API listening on {}", path.display());
+        self.add_listener(format!("unix://{}", path.display()));
+
+        let mut connections = Inflight::default();
+        let deadline = loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+                deadline = stopped(&mut stop) => break deadline,
+            };
+            let (stream, _) = match accepted {
+                Ok(connection) => connection,
+                Err(e) => {
+                    error!("Connection failed: {}", e);
+                    continue;
+                }
+            };
+
+            let caller = match stream.peer_cred() {
+                Ok(credentials) => Principal::Uid(credentials.uid()),
+                Err(e) => {
+                    error!("Failed to read peer credentials: {}", e);
+                    continue;
+                }
+            };
+            if let Err(e) = self.admit(&caller, Limit::Connection) {
+                info!("Dropped connection: {}", e);
+                continue;
+            }
+
+            let server = Arc::clone(&self);
+            connections.spawn(async move {
+                let service = service_fn(|request| {
+                    let server = Arc::clone(&server);
+                    let caller = caller.clone();
+                    async move { Ok::<_, Infallible>(server.handle_http(request, &caller).await) }
+                });
+
+                if let Err(e) = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await
+                {
+                    error!("Error handling HTTP client: {}", e);
+                }
+            });
+        };
+
+        drop(listener);
+        connections.drain(deadline).await;
+        Ok(())
+    }
+
+    async fn handle_http(
+        &self,
+        request: HttpRequest<Incoming>,
+        caller: &Principal,
+    ) -> HttpResponse {
+        let method = request.method().clone();
+        let path = request.uri().path().to_string();
+        let query = parse_query(request.uri().query().unwrap_or(""));
+        let token = request
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(str::to_string);
+
+        let body = match Limited::new(request.into_body(), MAX_REQUEST_SIZE)
+            .collect()
+            .await
+        {
+            Ok(body) => body.to_bytes(),
+            Err(_) => return error_response(StatusCode::PAYLOAD_TOO_LARGE, "Request too large"),
+        };
+
+        // Probes need no token; status only shows more with one
+        if method == Method::GET {
+            match path.as_str() {
+                "/v1/health" => return json_response(StatusCode::OK, &json!({ "status": "ok" })),
+                "/v1/ready" => {
+                    let ready = self.ready.load(Ordering::SeqCst);
+                    let status = if ready {
+                        StatusCode::OK
+                    } else {
+                        StatusCode::SERVICE_UNAVAILABLE
+                    };
+                    return json_response(status, &json!({ "ready": ready }));
+                }
+                "/v1/status" => {
+                    let scope = self.status_scope(
+                        query.get("namespace").map(String::as_str),
+                        query.get("environment").map(String::as_str),
+                        token.as_deref(),
+                        caller,
+                    );
+                    let status = self
+                        .status(&scope)
+                        .and_then(|status| serde_json::to_value(status).map_err(|e| e.to_string()));
+                    return match status {
+                        Ok(status) => json_response(StatusCode::OK, &status),
+                        Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, &e),
+                    };
+                }
+                _ => {}
+            }
+        }
+
+        let request = match route(&method, &path, &query, &body) {
+            Ok(Some(command)) => Request {
+                legacy: false,
+                namespace: query.get("namespace").cloned(),
+                environment: query.get("environment").cloned(),
+                token,
+                identity: None,
+                command,
+            },
+            Ok(None) => match Request::parse(&body) {
+                Ok(mut request) => {
+                    request.token = request.token.or(token);
+                    request
+                }
+                Err(e) => return error_response(StatusCode::BAD_REQUEST, &e),
+            },
+            Err((status, e)) => return error_response(status, &e),
+        };
+
+        if matches!(request.command, Command::Watch { .. }) {
+            return error_response(
+                StatusCode::BAD_REQUEST,
+                "watch is only available over the TCP protocol",
+            );
+        }
+
+        // The socket's uid already says who the caller is
+        if let Err(e) = self.identify(&request, &body, caller.clone(), None) {
+            return failure_response(&e);
+        }
+
+        let result = if let Command::Admin {
+            admin_token,
+            action,
+        } = request.command
+        {
+            let token = admin_token.or(request.token);
+            self.administer(action, token.as_deref(), caller).await
+        } else {
+            match self.authorize(&request, caller) {
+                Ok(namespace) => self.execute(&namespace, request.command, caller).await,
+                Err(e) => Err(e),
+            }
+        };
+
+        match result {
+            Ok(Some(response)) => json_response(StatusCode::OK, &response),
+            Ok(None) => empty_response(StatusCode::NO_CONTENT),
+            Err(e) => failure_response(&e),
+        }
+    }
+}
+
+// Maps a REST route to a command. `Ok(None)` is the generic command route,
+// whose body is parsed as a full request instead.
+fn route(
+    method: &Method,
+    path: &str,
+    query: &HashMap<String, String>,
+    body: &[u8],
+) -> Result<Option<Command>, (StatusCode, String)> {
+    let keys = || {
+        query
+            .get("keys")
+            .map(|keys| {
+                keys.split(',')
+                    .filter(|key| !key.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+
+    match (method, path) {
+        (&Method::GET, "/v1/secrets") => Ok(Some(Command::GetEnv {
+            keys: keys(),
+            versions: HashMap::new(),
+        })),
+        (&Method::PUT, "/v1/secrets") => {
+            let body: Value = serde_json::from_slice(body)
+                .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+            let secrets = serde_json::from_value(body.get("secrets").cloned().unwrap_or_default())
+                .map_err(|e| (StatusCode::BAD_REQUEST, format!("secrets: {}", e)))?;
+            let ttl = body.get("ttl").and_then(Value::as_u64);
+            let types = match body.get("types") {
+                Some(types) => serde_json::from_value(types.clone())
+                    .map_err(|e| (StatusCode::BAD_REQUEST, format!("types: {}", e)))?,
+                None => HashMap::new(),
+            };
+            Ok(Some(Command::StoreEnv {
+                secrets,
+                ttl,
+                types,
+            }))
+        }
+        (&Method::DELETE, "/v1/secrets") => Ok(Some(Command::DeleteEnv { keys: keys() })),
+        (&Method::GET, "/v1/keys") => Ok(Some(Command::ListKeys {
+            filter: KeyFilter {
+                prefix: query.get("prefix").cloned(),
+                pattern: query.get("pattern").cloned(),
+            },
+        })),
+        (&Method::POST, "/v1/command") => Ok(None),
+        (
+            _,
+            "/v1/secrets" | "/v1/keys" | "/v1/command" | "/v1/health" | "/v1/ready" | "/v1/status",
+        ) => Err((
+            StatusCode::METHOD_NOT_ALLOWED,
+            format!("{} is not supported on {}", method, path),
+        )),
+        _ => Err((StatusCode::NOT_FOUND, format!("No route for {}", path))),
+    }
+}
+
+fn parse_query(query: &str) -> HashMap<String, String> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (percent_decode(name), percent_decode(value))
+        })
+        .collect()
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = |byte: u8| (byte as char).to_digit(16);
+                match (hex(bytes[i + 1]), hex(bytes[i + 2])) {
+                    (Some(high), Some(low)) => {
+                        decoded.push((high * 16 + low) as u8);
+                        i += 2;
+                    }
+                    _ => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn json_response(status: StatusCode, body: &Value) -> HttpResponse {
+    let mut response = Response::new(Full::new(Bytes::from(body.to_string())));
+    *response.status_mut() = status;
+    response.headers_mut().insert(
+        CONTENT_TYPE,
+        hyper::header::HeaderValue::from_static("application/json"),
+    );
+    response
+}
+
+fn error_response(status: StatusCode, message: &str) -> HttpResponse {
+    json_response(status, &json!({ "error": message }))
+}
+
+// Errors from the shared command path carry their kind, which also picks the
+// status code
+fn failure_response(e: &Error) -> HttpResponse {
+    let status = match e {
+        Error::Policy(_) => StatusCode::FORBIDDEN,
+        Error::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+        Error::Protocol(_) | Error::Crypto(_) => StatusCode::BAD_REQUEST,
+        Error::Validation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        Error::Backend(_) => StatusCode::BAD_GATEWAY,
+        Error::Store(_) | Error::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    let response = e.to_response();
+    json_response(
+        status,
+        &json!({ "error": response.message, "kind": response.kind }),
+    )
+}
+
+fn empty_response(status: StatusCode) -> HttpResponse {
+    let mut response = Response::new(Full::new(Bytes::new()));
+    *response.status_mut() = status;
+    response
+}
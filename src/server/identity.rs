@@ -0,0 +1,254 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand::rngs::OsRng;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+use crate::error::Error;
+use crate::server::audit;
+use crate::server::policy::Principal;
+use crate::server::server::SecretsServer;
+use crate::types::identity::IdentityProof;
+use crate::types::request::Request;
+
+// Keeps signatures made for this protocol from being valid anywhere else
+const TRANSCRIPT_CONTEXT: &[u8] = b"shinobi-client-identity-v2";
+
+// What a client signs to prove its identity on one connection. Both public
+// keys are fresh per connection, so a signature cannot be replayed, and a
+// relay that runs its own key exchange with the server would need the
+// client's key to sign the different transcript.
+pub fn transcript(server_public_key: &[u8], client_public_key: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(TRANSCRIPT_CONTEXT);
+    for key in [server_public_key, client_public_key] {
+        hasher.update((key.len() as u32).to_be_bytes());
+        hasher.update(key);
+    }
+    hasher.finalize().to_vec()
+}
+
+// What a client actually signs: the connection's transcript bound to the
+// request it makes on it. The request travels in the clear, so without it
+// a relay passing the key exchange through could swap the command under a
+// valid signature. The `identity` field itself is left out, and the rest
+// is hashed as re-serialized JSON, whose object keys come out sorted, so
+// both ends hash the same bytes whatever order the fields were sent in.
+fn signed_message(transcript: &[u8], request: &Value) -> Vec<u8> {
+    let mut request = request.clone();
+    if let Value::Object(fields) = &mut request {
+        fields.remove("identity");
+    }
+
+    let mut hasher = Sha256::new();
+    hasher.update(transcript);
+    hasher.update(Sha256::digest(request.to_string()));
+    hasher.finalize().to_vec()
+}
+
+pub fn prove(name: &str, key: &SigningKey, transcript: &[u8], request: &Value) -> IdentityProof {
+    IdentityProof {
+        name: name.to_string(),
+        signature: STANDARD.encode(key.sign(&signed_message(transcript, request)).to_bytes()),
+    }
+}
+
+// Public keys travel as base64 of the 32 raw bytes
+pub fn encode_public_key(key: &VerifyingKey) -> String {
+    STANDARD.encode(key.as_bytes())
+}
+
+pub fn parse_public_key(value: &str) -> Result<VerifyingKey, String> {
+    let bytes: [u8; 32] = STANDARD
+        .decode(value.trim())
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| format!("'{}' is not a base64 Ed25519 public key", value))?;
+    VerifyingKey::from_bytes(&bytes).map_err(|e| e.to_string())
+}
+
+// Creates a signing key in a new 0600 file, holding the base64 seed, and
+// returns its public half
+pub fn generate_key_file(path: &Path) -> Result<VerifyingKey, String> {
+    let key = SigningKey::generate(&mut OsRng);
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    writeln!(file, "{}", STANDARD.encode(key.to_bytes()))
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    Ok(key.verifying_key())
+}
+
+pub fn load_key_file(path: &Path) -> Result<SigningKey, String> {
+    let seed: [u8; 32] = std::fs::read_to_string(path)
+        .map_err(|e| format!("{}: {}", path.display(), e))
+        .and_then(|content| {
+            STANDARD
+                .decode(content.trim())
+                .ok()
+                .and_then(|bytes| bytes.try_into().ok())
+                .ok_or_else(|| format!("{} does not hold an Ed25519 key", path.display()))
+        })?;
+    Ok(SigningKey::from_bytes(&seed))
+}
+
+// Long-term keys of the clients allowed to identify themselves by name
+#[derive(Default)]
+pub struct ClientRegistry {
+    keys: BTreeMap<String, VerifyingKey>,
+}
+
+impl ClientRegistry {
+    pub fn new() -> Self {
+        ClientRegistry::default()
+    }
+
+    pub fn register(&mut self, name: String, key: VerifyingKey) {
+        self.keys.insert(name, key);
+    }
+
+    pub fn revoke(&mut self, name: &str) -> bool {
+        self.keys.remove(name).is_some()
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.keys.keys().cloned().collect()
+    }
+
+    pub fn verify(
+        &self,
+        proof: &IdentityProof,
+        transcript: &[u8],
+        request: &Value,
+    ) -> Result<Principal, String> {
+        let key = self
+            .keys
+            .get(&proof.name)
+            .ok_or_else(|| format!("Unknown client '{}'", proof.name))?;
+        let signature = STANDARD
+            .decode(&proof.signature)
+            .ok()
+            .and_then(|bytes| Signature::from_slice(&bytes).ok())
+            .ok_or("Malformed identity signature")?;
+
+        key.verify(&signed_message(transcript, request), &signature)
+            .map(|()| Principal::Client(proof.name.clone()))
+            .map_err(|_| format!("Bad identity signature for client '{}'", proof.name))
+    }
+}
+
+impl SecretsServer {
+    pub fn with_client_identity(self, name: impl Into<String>, key: VerifyingKey) -> Self {
+        if let Ok(mut identities) = self.identities.write() {
+            identities.register(name.into(), key);
+        }
+        self
+    }
+
+    // Swaps the connection's caller for the registered client the request
+    // proves to be. Proofs are only checked against a key exchange
+    // transcript and the request's raw `body`; TLS clients are identified by
+    // their certificate instead. Failed proofs are audited and count towards
+    // a ban.
+    pub(crate) fn identify(
+        &self,
+        request: &Request,
+        body: &[u8],
+        caller: Principal,
+        transcript: Option<&[u8]>,
+    ) -> Result<Principal, Error> {
+        let Some(proof) = &request.identity else {
+            return Ok(caller);
+        };
+
+        let result = match transcript {
+            Some(transcript) => serde_json::from_slice::<Value>(body)
+                .map_err(|e| Error::Protocol(e.to_string()))
+                .and_then(|body| {
+                    self.identities
+                        .read()
+                        .map_err(|e| {
+                            Error::Store(format!("Error locking client identities: {}", e))
+                        })?
+                        .verify(proof, transcript, &body)
+                        .map_err(Error::Policy)
+                }),
+            None => Err(Error::Protocol(
+                "Client identities are only accepted over the key exchange".to_string(),
+            )),
+        };
+
+        if let Err(e) = &result {
+            audit::record(&caller, None, request.command.name(), Err(e));
+            self.metrics.record_command(
+                request.command.name(),
+                if e.is_refusal() { "refused" } else { "error" },
+            );
+            self.record_failure(&caller);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn registry(key: &SigningKey) -> ClientRegistry {
+        let mut registry = ClientRegistry::new();
+        registry.register("deploy".to_string(), key.verifying_key());
+        registry
+    }
+
+    #[test]
+    fn proof_covers_the_request() {
+        let key = SigningKey::generate(&mut OsRng);
+        let registry = registry(&key);
+        let transcript = transcript(b"server", b"client");
+        let request = json!({"command": "GetEnv", "namespace": "app/dev", "token": "t"});
+        let proof = prove("deploy", &key, &transcript, &request);
+
+        // The identity field travels inside the request it signs
+        let mut sent = request.clone();
+        sent["identity"] = serde_json::to_value(&proof).unwrap();
+        assert_eq!(
+            registry.verify(&proof, &transcript, &sent),
+            Ok(Principal::Client("deploy".to_string()))
+        );
+
+        let mut swapped = sent.clone();
+        swapped["command"] = json!("DeleteEnv");
+        assert!(registry.verify(&proof, &transcript, &swapped).is_err());
+
+        let other = super::transcript(b"server", b"relay");
+        assert!(registry.verify(&proof, &other, &sent).is_err());
+    }
+
+    #[test]
+    fn field_order_does_not_matter() {
+        let key = SigningKey::generate(&mut OsRng);
+        let transcript = transcript(b"server", b"client");
+        let proof = prove(
+            "deploy",
+            &key,
+            &transcript,
+            &json!({"command": "GetEnv", "namespace": "app/dev"}),
+        );
+
+        let received: Value =
+            serde_json::from_str(r#"{"namespace":"app/dev","command":"GetEnv"}"#).unwrap();
+        assert!(registry(&key)
+            .verify(&proof, &transcript, &received)
+            .is_ok());
+    }
+}
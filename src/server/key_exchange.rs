@@ -1,9 +1,10 @@
 use aes_gcm::aead::{Aead, KeyInit};
 use aes_gcm::{Aes256Gcm, Key, Nonce};
 use num_bigint::{BigUint, RandBigInt};
-use rand::Rng;
 use sha2::{Digest, Sha256};
 
+use crate::error::Error;
+
 // Diffie-Hellman Key Exchange Struct
 pub struct DHKeyExchange {
     private_key: BigUint,
@@ -11,6 +12,12 @@ pub struct DHKeyExchange {
     generator: BigUint,
 }
 
+impl Default for DHKeyExchange {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl DHKeyExchange {
     pub fn new() -> Self {
         let prime = BigUint::parse_bytes(
@@ -34,6 +41,19 @@ impl DHKeyExchange {
         self.generator.modpow(&self.private_key, &self.prime)
     }
 
+    // Public keys of 0, 1 or p - 1 (and anything outside the group) force a
+    // shared secret anyone can compute, so they are refused
+    pub fn check_public_key(&self, public_key: &BigUint) -> Result<(), Error> {
+        let one = BigUint::from(1u32);
+        if *public_key > one && *public_key < &self.prime - &one {
+            Ok(())
+        } else {
+            Err(Error::Crypto(
+                "Invalid Diffie-Hellman public key".to_string(),
+            ))
+        }
+    }
+
     pub fn compute_shared_secret(&self, other_public_key: &BigUint) -> Vec<u8> {
         let shared_secret = other_public_key.modpow(&self.private_key, &self.prime);
 
@@ -42,23 +62,134 @@ impl DHKeyExchange {
         hasher.finalize().to_vec()
     }
 
-    pub fn encrypt(key: &[u8], data: &[u8]) -> Vec<u8> {
-        let key = Key::<Aes256Gcm>::from_slice(key);
-        let cipher = Aes256Gcm::new(key);
-
+    pub fn encrypt(key: &[u8], data: &[u8]) -> Result<Vec<u8>, Error> {
+        let cipher = cipher(key)?;
         let nonce = Nonce::from_slice(b"unique nonce");
 
-        cipher.encrypt(nonce, data).expect("encryption failure!")
+        cipher
+            .encrypt(nonce, data)
+            .map_err(|_| Error::Crypto("Failed to encrypt response".to_string()))
     }
 
-    pub fn decrypt(key: &[u8], encrypted_data: &[u8]) -> Vec<u8> {
-        let key = Key::<Aes256Gcm>::from_slice(key);
-        let cipher = Aes256Gcm::new(key);
-
+    pub fn decrypt(key: &[u8], encrypted_data: &[u8]) -> Result<Vec<u8>, Error> {
+        let cipher = cipher(key)?;
         let nonce = Nonce::from_slice(b"unique nonce");
 
         cipher
             .decrypt(nonce, encrypted_data)
-            .expect("decryption failure!")
+            .map_err(|_| Error::Crypto("Failed to decrypt response".to_string()))
+    }
+}
+
+// `from_slice` panics on a key of the wrong length
+fn cipher(key: &[u8]) -> Result<Aes256Gcm, Error> {
+    if key.len() != 32 {
+        return Err(Error::Crypto(format!(
+            "Expected a 32-byte key, got {} bytes",
+            key.len()
+        )));
+    }
+    Ok(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key)))
+}
+
+// Seals a stream of messages under one shared secret. Each message gets its
+// own nonce made of a fixed prefix and a counter, so the key can be used for
+// as many frames as a session needs. Both ends count the frames they have
+// seen, which also rejects replayed or reordered frames.
+pub struct SessionCipher {
+    cipher: Aes256Gcm,
+    counter: u64,
+}
+
+impl SessionCipher {
+    // Prefix for streamed frames; it cannot collide with the single-reply nonce
+    const NONCE_PREFIX: [u8; 4] = *b"strm";
+
+    pub fn new(key: &[u8]) -> Result<Self, Error> {
+        Ok(SessionCipher {
+            cipher: cipher(key)?,
+            counter: 0,
+        })
+    }
+
+    pub fn seal(&mut self, data: &[u8]) -> Result<Vec<u8>, String> {
+        let nonce = self.next_nonce()?;
+        self.cipher
+            .encrypt(Nonce::from_slice(&nonce), data)
+            .map_err(|_| "Failed to seal frame".to_string())
+    }
+
+    pub fn open(&mut self, data: &[u8]) -> Result<Vec<u8>, String> {
+        let nonce = self.next_nonce()?;
+        self.cipher
+            .decrypt(Nonce::from_slice(&nonce), data)
+            .map_err(|_| "Failed to open frame".to_string())
+    }
+
+    fn next_nonce(&mut self) -> Result<[u8; 12], String> {
+        let mut nonce = [0u8; 12];
+        nonce[..4].copy_from_slice(&Self::NONCE_PREFIX);
+        nonce[4..].copy_from_slice(&self.counter.to_be_bytes());
+        self.counter = self
+            .counter
+            .checked_add(1)
+            .ok_or("Session nonce space exhausted")?;
+        Ok(nonce)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn decrypt_rejects_garbage_without_panicking() {
+        let mut rng = StdRng::seed_from_u64(41);
+        for _ in 0..2000 {
+            let key: Vec<u8> = (0..rng.gen_range(0..64)).map(|_| rng.gen()).collect();
+            let data: Vec<u8> = (0..rng.gen_range(0..256)).map(|_| rng.gen()).collect();
+            assert!(DHKeyExchange::decrypt(&key, &data).is_err());
+        }
+    }
+
+    #[test]
+    fn decrypt_rejects_truncated_ciphertext() {
+        let key = [7u8; 32];
+        let sealed = DHKeyExchange::encrypt(&key, b"{\"command\":\"status\"}").unwrap();
+        for len in 0..sealed.len() {
+            assert!(DHKeyExchange::decrypt(&key, &sealed[..len]).is_err());
+        }
+        assert_eq!(
+            DHKeyExchange::decrypt(&key, &sealed).unwrap(),
+            b"{\"command\":\"status\"}"
+        );
+    }
+
+    #[test]
+    fn session_cipher_refuses_keys_of_the_wrong_length() {
+        for len in [0, 1, 16, 31, 33, 64] {
+            assert!(SessionCipher::new(&vec![0u8; len]).is_err());
+        }
+        assert!(SessionCipher::new(&[0u8; 32]).is_ok());
+    }
+
+    #[test]
+    fn degenerate_public_keys_are_refused() {
+        let exchange = DHKeyExchange::new();
+        let prime = exchange.prime.clone();
+        for key in [
+            BigUint::from(0u32),
+            BigUint::from(1u32),
+            &prime - 1u32,
+            prime.clone(),
+            &prime + 5u32,
+        ] {
+            assert!(exchange.check_public_key(&key).is_err());
+        }
+        assert!(exchange
+            .check_public_key(&DHKeyExchange::new().get_public_key())
+            .is_ok());
     }
 }
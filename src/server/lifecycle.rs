@@ -0,0 +1,270 @@
+use log::{error, info, warn};
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+
+use crate::server::server::SecretsServer;
+
+// How long in-flight requests get to finish once shutdown starts
+const SHUTDOWN_GRACE_SECS: u64 = 10;
+// How long handlers that were cut off at the deadline get to notice
+const CUTOFF_SECS: u64 = 2;
+
+// Carries the deadline for in-flight requests once shutdown has started
+pub type StopSignal = watch::Receiver<Option<Instant>>;
+
+// Resolves once shutdown has started, with the deadline listeners drain
+// their connections by
+pub async fn stopped(stop: &mut StopSignal) -> Instant {
+    loop {
+        if let Some(deadline) = *stop.borrow_and_update() {
+            return deadline;
+        }
+        if stop.changed().await.is_err() {
+            return Instant::now();
+        }
+    }
+}
+
+// Bounds every read and write on `stream`, so a client that stalls, during
+// the handshake or after it, cannot hold its connection open for good
+pub(crate) fn set_timeouts(stream: &TcpStream, timeout_secs: u64) -> std::io::Result<()> {
+    let timeout = Some(Duration::from_secs(timeout_secs.max(1)));
+    stream.set_read_timeout(timeout)?;
+    stream.set_write_timeout(timeout)
+}
+
+// Connections a listener is still serving. Async handlers can be aborted,
+// but handlers on the blocking pool cannot, so their sockets are kept and
+// shut down once the grace period is over; the blocked read or write then
+// fails and the handler returns.
+#[derive(Default)]
+pub struct Inflight {
+    tasks: JoinSet<()>,
+    sockets: Arc<Mutex<HashMap<u64, TcpStream>>>,
+    next_id: u64,
+}
+
+impl Inflight {
+    pub fn spawn(&mut self, handler: impl Future<Output = ()> + Send + 'static) {
+        self.tasks.spawn(handler);
+    }
+
+    // Runs a handler that blocks on `stream`
+    pub fn spawn_blocking(
+        &mut self,
+        stream: TcpStream,
+        handler: impl FnOnce(TcpStream) + Send + 'static,
+    ) {
+        let id = self.next_id;
+        self.next_id += 1;
+        if let Ok(socket) = stream.try_clone() {
+            lock(&self.sockets).insert(id, socket);
+        }
+
+        let sockets = Arc::clone(&self.sockets);
+        self.tasks.spawn_blocking(move || {
+            handler(stream);
+            lock(&sockets).remove(&id);
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.tasks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+
+    // Collects a finished handler, so the set does not grow with every
+    // connection ever served
+    pub async fn join_next(&mut self) -> Option<()> {
+        self.tasks.join_next().await.map(|_| ())
+    }
+
+    // Waits for every handler until `deadline`, then cuts off the rest
+    pub async fn drain(mut self, deadline: Instant) {
+        let finished = tokio::time::timeout_at(deadline, async {
+            while self.tasks.join_next().await.is_some() {}
+        })
+        .await;
+        if finished.is_ok() {
+            return;
+        }
+
+        warn!(
+            "Cutting off {} connections still open after {}s",
+            self.tasks.len(),
+            SHUTDOWN_GRACE_SECS
+        );
+        for socket in lock(&self.sockets).values() {
+            let _ = socket.shutdown(Shutdown::Both);
+        }
+        self.tasks.abort_all();
+
+        let cutoff = Duration::from_secs(CUTOFF_SECS);
+        let stopped = tokio::time::timeout(cutoff, async {
+            while self.tasks.join_next().await.is_some() {}
+        })
+        .await;
+        if stopped.is_err() {
+            warn!("Abandoning {} connections", self.tasks.len());
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl SecretsServer {
+    // SIGHUP or the admin reload command: fetch every project again and
+    // re-render templates, picking up edited template files. A project that
+    // fails to sync keeps serving the secrets it already has. Returns the
+    // number of projects that failed.
+    pub async fn reload(&self) -> usize {
+        info!("Reloading {} projects", self.projects.len());
+
+        let mut failed = 0;
+        for input in self.projects.iter().cloned() {
+            let namespace = input.namespace();
+            match self.sync_project(input).await {
+                Ok(_) => info!("Reloaded {}", namespace),
+                Err(e) => {
+                    failed += 1;
+                    error!(
+                        "Failed to reload {}, keeping current secrets: {}",
+                        namespace, e
+                    );
+                }
+            }
+        }
+
+        self.templates_stale.store(true, Ordering::SeqCst);
+        failed
+    }
+
+    // SIGTERM/SIGINT, once the TCP listener is closed: tells every other
+    // listener and background task to stop, waits for in-flight requests on
+    // all of them up to a deadline, gives queued writes a last chance to
+    // reach the backend, then wipes every secure block. Nothing that could
+    // still touch the store is left running by then, and the wipe happens
+    // even if a handler panicked while holding the store.
+    pub async fn shutdown(
+        &self,
+        stop: watch::Sender<Option<Instant>>,
+        connections: Inflight,
+        mut tasks: JoinSet<()>,
+    ) {
+        self.ready.store(false, Ordering::SeqCst);
+        info!("Shutting down, draining {} connections", connections.len());
+
+        let grace = Duration::from_secs(SHUTDOWN_GRACE_SECS);
+        let deadline = Instant::now() + grace;
+        let _ = stop.send(Some(deadline));
+
+        // Watch streams never finish on their own; ending their
+        // subscriptions lets them return instead of holding up the drain
+        self.store
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .end_subscriptions();
+
+        connections.drain(deadline).await;
+
+        // Listeners drain their own connections by the same deadline
+        let cutoff = deadline + Duration::from_secs(CUTOFF_SECS);
+        let stopped =
+            tokio::time::timeout_at(cutoff, async { while tasks.join_next().await.is_some() {} })
+                .await;
+        if stopped.is_err() {
+            warn!("Abandoning {} background tasks", tasks.len());
+            tasks.abort_all();
+            while tasks.join_next().await.is_some() {}
+        }
+
+        if self.write_back.is_some() {
+            match tokio::time::timeout(grace, self.flush_writes()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => error!("Failed to write back before exit: {}", e),
+                Err(_) => error!("Timed out writing back before exit"),
+            }
+        }
+
+        if let Some(path) = &self.http_socket {
+            let _ = std::fs::remove_file(path);
+        }
+
+        let wiped = self
+            .store
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .wipe();
+        info!("Wiped {} secure memory blocks", wiped);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::server::GetKeysInput;
+    use crate::server::source::DotEnvSource;
+    use crate::types::metadata::SecretOrigin;
+    use crate::types::namespace::Namespace;
+
+    #[tokio::test]
+    async fn reload_keeps_locally_written_keys() {
+        let path = std::env::temp_dir().join(format!("reload-{}.env", std::process::id()));
+        std::fs::write(&path, "FROM_BACKEND=1\nDROPPED=2\n").unwrap();
+
+        let mut server =
+            SecretsServer::new(String::new(), String::new()).with_source(DotEnvSource::new(&path));
+        server.projects = vec![GetKeysInput {
+            project_name: "app".to_string(),
+            token: "token".to_string(),
+            environment: Some("dev".to_string()),
+        }];
+        let namespace = Namespace::parse("app/dev");
+        assert_eq!(server.reload().await, 0);
+
+        for (key, origin) in [
+            ("STORED", SecretOrigin::StoreEnv),
+            ("IMPORTED", SecretOrigin::Import),
+            ("GENERATED", SecretOrigin::Generated),
+        ] {
+            server
+                .store
+                .write()
+                .unwrap()
+                .store_secret(
+                    &namespace,
+                    key.to_string(),
+                    "local".to_string(),
+                    origin,
+                    None,
+                )
+                .unwrap();
+        }
+
+        std::fs::write(&path, "FROM_BACKEND=3\n").unwrap();
+        assert_eq!(server.reload().await, 0);
+        let _ = std::fs::remove_file(&path);
+
+        let store = server.store.read().unwrap();
+        assert_eq!(
+            store.get_secret(&namespace, "FROM_BACKEND").as_deref(),
+            Some("3")
+        );
+        assert!(!store.contains(&namespace, "DROPPED"));
+        for key in ["STORED", "IMPORTED", "GENERATED"] {
+            assert_eq!(store.get_secret(&namespace, key).as_deref(), Some("local"));
+        }
+    }
+}
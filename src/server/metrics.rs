@@ -0,0 +1,414 @@
+use http_body_util::Full;
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use log::{error, info};
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+use crate::server::lifecycle::{stopped, Inflight, StopSignal};
+use crate::server::server::SecretsServer;
+
+// Upper bounds, in seconds, of the latency histogram buckets
+const BUCKETS: [f64; 10] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+#[derive(Clone, Default)]
+struct Histogram {
+    // Cumulative counts per bucket, as Prometheus expects
+    buckets: [u64; BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        for (bucket, bound) in self.buckets.iter_mut().zip(BUCKETS) {
+            if seconds <= bound {
+                *bucket += 1;
+            }
+        }
+        self.sum += seconds;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let separator = if labels.is_empty() { "" } else { "," };
+        for (count, bound) in self.buckets.iter().zip(BUCKETS) {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{}{}le=\"{}\"}} {}",
+                name, labels, separator, bound, count
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{}{}le=\"+Inf\"}} {}",
+            name, labels, separator, self.count
+        );
+        let labels = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{}}}", labels)
+        };
+        let _ = writeln!(out, "{}_sum{} {}", name, labels, self.sum);
+        let _ = writeln!(out, "{}_count{} {}", name, labels, self.count);
+    }
+}
+
+// Counters and histograms collected while the daemon runs. Gauges such as
+// the number of stored secrets are read from the store at scrape time.
+#[derive(Default)]
+pub struct Metrics {
+    commands: Mutex<BTreeMap<(&'static str, &'static str), u64>>,
+    command_latency: Mutex<BTreeMap<&'static str, Histogram>>,
+    handshake_failures: AtomicU64,
+    rate_limited: Mutex<BTreeMap<&'static str, u64>>,
+    bans: AtomicU64,
+    key_rotations: AtomicU64,
+    fetch_successes: AtomicU64,
+    fetch_failures: AtomicU64,
+    sync_duration: Mutex<Histogram>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics::default()
+    }
+
+    // `outcome` is one of "ok", "error" or "refused"
+    pub fn record_command(&self, command: &'static str, outcome: &'static str) {
+        if let Ok(mut commands) = self.commands.lock() {
+            *commands.entry((command, outcome)).or_default() += 1;
+        }
+    }
+
+    pub fn observe_command(&self, command: &'static str, elapsed: Duration) {
+        if let Ok(mut latency) = self.command_latency.lock() {
+            latency.entry(command).or_default().observe(elapsed);
+        }
+    }
+
+    pub fn record_handshake_failure(&self) {
+        self.handshake_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // `limit` is "connection" or "command"
+    pub fn record_rate_limited(&self, limit: &'static str) {
+        if let Ok(mut rate_limited) = self.rate_limited.lock() {
+            *rate_limited.entry(limit).or_default() += 1;
+        }
+    }
+
+    pub fn record_ban(&self) {
+        self.bans.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_key_rotation(&self) {
+        self.key_rotations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_fetch(&self, succeeded: bool, elapsed: Duration) {
+        if succeeded {
+            self.fetch_successes.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.fetch_failures.fetch_add(1, Ordering::Relaxed);
+        }
+        if let Ok(mut histogram) = self.sync_duration.lock() {
+            histogram.observe(elapsed);
+        }
+    }
+}
+
+impl SecretsServer {
+    pub fn with_metrics_addr(mut self, addr: SocketAddr) -> Self {
+        self.metrics_addr = Some(addr);
+        self
+    }
+
+    // Everything in the Prometheus text exposition format
+    pub fn render_metrics(&self) -> Result<String, String> {
+        let metrics = &self.metrics;
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "shinobi_commands_total",
+            "counter",
+            "Commands handled, by command and outcome",
+        );
+        let commands = metrics
+            .commands
+            .lock()
+            .map_err(|e| format!("Error locking metrics: {}", e))?
+            .clone();
+        for ((command, outcome), count) in commands {
+            let _ = writeln!(
+                out,
+                "shinobi_commands_total{{command=\"{}\",outcome=\"{}\"}} {}",
+                command, outcome, count
+            );
+        }
+
+        header(
+            &mut out,
+            "shinobi_command_duration_seconds",
+            "histogram",
+            "Time taken to handle a command",
+        );
+        let latency = metrics
+            .command_latency
+            .lock()
+            .map_err(|e| format!("Error locking metrics: {}", e))?
+            .clone();
+        for (command, histogram) in latency {
+            histogram.render(
+                &mut out,
+                "shinobi_command_duration_seconds",
+                &format!("command=\"{}\"", command),
+            );
+        }
+
+        header(
+            &mut out,
+            "shinobi_handshake_failures_total",
+            "counter",
+            "Connections dropped during the key exchange",
+        );
+        let _ = writeln!(
+            out,
+            "shinobi_handshake_failures_total {}",
+            metrics.handshake_failures.load(Ordering::Relaxed)
+        );
+
+        header(
+            &mut out,
+            "shinobi_rate_limited_total",
+            "counter",
+            "Connections and commands refused by a rate limit or ban",
+        );
+        let rate_limited = metrics
+            .rate_limited
+            .lock()
+            .map_err(|e| format!("Error locking metrics: {}", e))?
+            .clone();
+        for (limit, count) in rate_limited {
+            let _ = writeln!(
+                out,
+                "shinobi_rate_limited_total{{limit=\"{}\"}} {}",
+                limit, count
+            );
+        }
+
+        header(
+            &mut out,
+            "shinobi_bans_total",
+            "counter",
+            "Callers banned after repeated failures",
+        );
+        let _ = writeln!(
+            out,
+            "shinobi_bans_total {}",
+            metrics.bans.load(Ordering::Relaxed)
+        );
+
+        header(
+            &mut out,
+            "shinobi_banned_callers",
+            "gauge",
+            "Callers currently banned",
+        );
+        let _ = writeln!(out, "shinobi_banned_callers {}", self.rate_limiter.banned());
+
+        header(
+            &mut out,
+            "shinobi_key_rotations_total",
+            "counter",
+            "Times every secret was resealed under fresh keys",
+        );
+        let _ = writeln!(
+            out,
+            "shinobi_key_rotations_total {}",
+            metrics.key_rotations.load(Ordering::Relaxed)
+        );
+
+        header(
+            &mut out,
+            "shinobi_backend_fetches_total",
+            "counter",
+            "Project fetches from the secret source, by outcome",
+        );
+        let _ = writeln!(
+            out,
+            "shinobi_backend_fetches_total{{outcome=\"ok\"}} {}",
+            metrics.fetch_successes.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            out,
+            "shinobi_backend_fetches_total{{outcome=\"error\"}} {}",
+            metrics.fetch_failures.load(Ordering::Relaxed)
+        );
+
+        header(
+            &mut out,
+            "shinobi_sync_duration_seconds",
+            "histogram",
+            "Time taken to fetch and apply a project",
+        );
+        metrics
+            .sync_duration
+            .lock()
+            .map_err(|e| format!("Error locking metrics: {}", e))?
+            .render(&mut out, "shinobi_sync_duration_seconds", "");
+
+        let (counts, (blocks, bytes)) = {
+            let store = self
+                .store
+                .read()
+                .map_err(|e| format!("Error locking store: {}", e))?;
+            let counts: BTreeMap<String, usize> = store
+                .namespaces()
+                .map(|namespace| (namespace.to_string(), store.key_count(namespace)))
+                .collect();
+            (counts, store.memory_usage())
+        };
+
+        header(
+            &mut out,
+            "shinobi_secrets",
+            "gauge",
+            "Live secrets, by namespace",
+        );
+        for (namespace, count) in counts {
+            let _ = writeln!(
+                out,
+                "shinobi_secrets{{namespace=\"{}\"}} {}",
+                escape_label(&namespace),
+                count
+            );
+        }
+
+        header(
+            &mut out,
+            "shinobi_secure_memory_bytes",
+            "gauge",
+            "Bytes of mapped secure memory holding secret versions",
+        );
+        let _ = writeln!(out, "shinobi_secure_memory_bytes {}", bytes);
+
+        header(
+            &mut out,
+            "shinobi_secure_memory_blocks",
+            "gauge",
+            "Mapped secure memory blocks holding secret versions",
+        );
+        let _ = writeln!(out, "shinobi_secure_memory_blocks {}", blocks);
+
+        header(
+            &mut out,
+            "shinobi_ready",
+            "gauge",
+            "1 once every project has been loaded",
+        );
+        let _ = writeln!(
+            out,
+            "shinobi_ready {}",
+            u8::from(self.ready.load(Ordering::SeqCst))
+        );
+
+        Ok(out)
+    }
+
+    // Serves `GET /metrics`. Only loopback addresses are accepted, since the
+    // output names every namespace.
+    pub async fn serve_metrics(
+        self: Arc<Self>,
+        addr: SocketAddr,
+        mut stop: StopSignal,
+    ) -> std::io::Result<()> {
+        if !addr.ip().is_loopback() {
+            return Err(std::io::Error::other(format!(
+                "Refusing to serve metrics on non-local address {}",
+                addr
+            )));
+        }
+
+        let listener = TcpListener::bind(addr).await?;
+        info!("Metrics available on http://{}/metrics", addr);
+        self.add_listener(format!("http://{}/metrics", listener.local_addr()?));
+
+        let mut connections = Inflight::default();
+        let deadline = loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+                deadline = stopped(&mut stop) => break deadline,
+            };
+            let stream = match accepted {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    error!("Connection failed: {}", e);
+                    continue;
+                }
+            };
+
+            let server = Arc::clone(&self);
+            connections.spawn(async move {
+                let service = service_fn(|request: Request<Incoming>| {
+                    let server = Arc::clone(&server);
+                    async move { Ok::<_, Infallible>(server.metrics_response(&request)) }
+                });
+
+                if let Err(e) = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await
+                {
+                    error!("Error handling metrics client: {}", e);
+                }
+            });
+        };
+
+        drop(listener);
+        connections.drain(deadline).await;
+        Ok(())
+    }
+
+    fn metrics_response(&self, request: &Request<Incoming>) -> Response<Full<Bytes>> {
+        let (status, body) = match (request.method(), request.uri().path()) {
+            (&Method::GET, "/metrics") => match self.render_metrics() {
+                Ok(body) => (StatusCode::OK, body),
+                Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e),
+            },
+            _ => (StatusCode::NOT_FOUND, "Not found\n".to_string()),
+        };
+
+        let mut response = Response::new(Full::new(Bytes::from(body)));
+        *response.status_mut() = status;
+        response.headers_mut().insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("text/plain; version=0.0.4"),
+        );
+        response
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
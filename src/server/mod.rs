@@ -1,3 +1,23 @@
+pub mod admin;
+pub mod audit;
+pub mod formats;
+pub mod generate;
+pub mod http;
+pub mod identity;
 pub mod key_exchange;
+pub mod lifecycle;
+pub mod metrics;
+pub mod policy;
+pub mod rate_limit;
+pub mod reference;
+pub mod rotation;
+#[allow(clippy::module_inception)]
 pub mod server;
+pub mod snapshot;
+pub mod source;
+pub mod status;
 pub mod store;
+pub mod template;
+pub mod tls;
+pub mod validation;
+pub mod write_back;
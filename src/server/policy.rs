@@ -0,0 +1,654 @@
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+
+use crate::types::admin::Permission;
+use crate::types::namespace::Namespace;
+
+// Who is on the other end of a connection
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Principal {
+    Peer(IpAddr),
+    // A loopback TCP peer and the uid that owns its socket. Grants name it
+    // as `peer:IP`; the uid keeps local processes apart in rate limits and
+    // bans, since every one of them connects from the same address.
+    LocalPeer(IpAddr, u32),
+    // A local process on the Unix socket, identified by the kernel
+    Uid(u32),
+    // A TLS client, by the common name of its verified certificate
+    Cert(String),
+    // A client that signed the key exchange with its registered identity key
+    Client(String),
+}
+
+impl fmt::Display for Principal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Principal::Peer(ip) => write!(f, "peer:{}", ip),
+            Principal::LocalPeer(ip, uid) => write!(f, "peer:{}/uid:{}", ip, uid),
+            Principal::Uid(uid) => write!(f, "uid:{}", uid),
+            Principal::Cert(name) => write!(f, "cert:{}", name),
+            Principal::Client(name) => write!(f, "client:{}", name),
+        }
+    }
+}
+
+impl FromStr for Principal {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.split_once(':') {
+            Some(("peer", ip)) => ip
+                .parse()
+                .map(Principal::Peer)
+                .map_err(|_| format!("Invalid peer address '{}'", ip)),
+            Some(("uid", uid)) => uid
+                .parse()
+                .map(Principal::Uid)
+                .map_err(|_| format!("Invalid uid '{}'", uid)),
+            Some(("cert", name)) if !name.is_empty() => Ok(Principal::Cert(name.to_string())),
+            Some(("client", name)) if !name.is_empty() => Ok(Principal::Client(name.to_string())),
+            _ => Err(format!("Unknown principal '{}'", value)),
+        }
+    }
+}
+
+impl Principal {
+    // The caller behind a TCP connection from `peer` to `local`. Loopback
+    // peers get the uid the kernel lists for their socket, if it can be
+    // found.
+    pub fn tcp_peer(peer: SocketAddr, local: SocketAddr) -> Principal {
+        if !peer.ip().to_canonical().is_loopback() {
+            return Principal::Peer(peer.ip());
+        }
+
+        let table = if peer.is_ipv4() {
+            "/proc/net/tcp"
+        } else {
+            "/proc/net/tcp6"
+        };
+        std::fs::read_to_string(table)
+            .ok()
+            .and_then(|table| socket_owner(&table, peer, local))
+            .map_or(Principal::Peer(peer.ip()), |uid| {
+                Principal::LocalPeer(peer.ip(), uid)
+            })
+    }
+
+    // How grants name this caller
+    fn granted_as(&self) -> Option<Principal> {
+        match self {
+            Principal::LocalPeer(ip, _) => Some(Principal::Peer(*ip)),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn is_loopback_peer(&self) -> bool {
+        match self {
+            Principal::Peer(ip) => ip.to_canonical().is_loopback(),
+            Principal::LocalPeer(..) => true,
+            _ => false,
+        }
+    }
+}
+
+// The uid owning the established socket connected from `local` to `remote`,
+// in the format of /proc/net/tcp and /proc/net/tcp6
+fn socket_owner(table: &str, local: SocketAddr, remote: SocketAddr) -> Option<u32> {
+    table.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let established = fields.get(3) == Some(&"01");
+        (established
+            && parse_socket(fields.get(1)?) == Some(local)
+            && parse_socket(fields.get(2)?) == Some(remote))
+        .then(|| fields.get(7)?.parse().ok())
+        .flatten()
+    })
+}
+
+// `ADDRESS:PORT` in hex. The address is printed as native-endian 32-bit
+// words holding the bytes in network order.
+fn parse_socket(field: &str) -> Option<SocketAddr> {
+    let (address, port) = field.split_once(':')?;
+    let port = u16::from_str_radix(port, 16).ok()?;
+    let mut bytes = Vec::with_capacity(16);
+    for start in (0..address.len()).step_by(8) {
+        let word = u32::from_str_radix(address.get(start..start + 8)?, 16).ok()?;
+        bytes.extend_from_slice(&word.to_ne_bytes());
+    }
+
+    let ip = match bytes.len() {
+        4 => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(bytes).ok()?)),
+        16 => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(bytes).ok()?)),
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+// Whether `caller` is among `callers`, as itself or as grants name it
+fn listed(callers: &HashSet<Principal>, caller: &Principal) -> bool {
+    callers.contains(caller)
+        || caller
+            .granted_as()
+            .is_some_and(|granted| callers.contains(&granted))
+}
+
+// Tracks which token unlocks which namespace, so a client holding one
+// project's token cannot read another project's keys.
+#[derive(Default)]
+pub struct AccessPolicy {
+    tokens: HashMap<Namespace, String>,
+    protected_environments: HashSet<String>,
+    protected_callers: HashSet<Principal>,
+    export_callers: HashSet<Principal>,
+    trusted_callers: HashSet<Principal>,
+    // Admin commands need this token, or a caller listed in `admin_callers`
+    admin_token: Option<String>,
+    admin_callers: HashSet<Principal>,
+}
+
+// The policy as admins see it, without any tokens
+#[derive(Debug, Serialize)]
+pub struct PolicyView {
+    pub namespaces: BTreeSet<String>,
+    pub protected_environments: BTreeSet<String>,
+    pub trusted: BTreeSet<String>,
+    pub export: BTreeSet<String>,
+    pub protected: BTreeSet<String>,
+    pub admins: BTreeSet<String>,
+}
+
+impl AccessPolicy {
+    pub fn new() -> Self {
+        AccessPolicy {
+            tokens: HashMap::new(),
+            protected_environments: HashSet::new(),
+            protected_callers: HashSet::new(),
+            export_callers: HashSet::new(),
+            trusted_callers: HashSet::new(),
+            admin_token: None,
+            admin_callers: HashSet::new(),
+        }
+    }
+
+    pub fn register(&mut self, namespace: Namespace, token: String) {
+        self.tokens.insert(namespace, token);
+    }
+
+    // Guardrail: secrets in a protected environment (e.g. "prod") are only
+    // served to callers that have been explicitly allowed.
+    pub fn protect_environment(&mut self, environment: impl Into<String>) {
+        self.protected_environments.insert(environment.into());
+    }
+
+    pub fn allow_protected(&mut self, caller: Principal) {
+        self.protected_callers.insert(caller);
+    }
+
+    pub fn is_protected(&self, namespace: &Namespace) -> bool {
+        self.protected_environments.contains(&namespace.environment)
+    }
+
+    // Bulk export hands out every value at once, so nobody may export until
+    // they are explicitly allowed to.
+    pub fn allow_export(&mut self, caller: Principal) {
+        self.export_callers.insert(caller);
+    }
+
+    pub fn authorize_export(
+        &self,
+        namespace: &Namespace,
+        caller: &Principal,
+    ) -> Result<(), String> {
+        if listed(&self.export_callers, caller) {
+            Ok(())
+        } else {
+            Err(format!(
+                "{} is not allowed to export '{}'",
+                caller, namespace
+            ))
+        }
+    }
+
+    // Peer-credential auth: a caller the kernel vouches for, such as a uid on
+    // the Unix socket, may leave out the project token
+    pub fn trust(&mut self, caller: Principal) {
+        self.trusted_callers.insert(caller);
+    }
+
+    pub fn unprotect_environment(&mut self, environment: &str) -> bool {
+        self.protected_environments.remove(environment)
+    }
+
+    pub fn grant(&mut self, permission: Permission, caller: Principal) {
+        self.callers(permission).insert(caller);
+    }
+
+    pub fn revoke(&mut self, permission: Permission, caller: &Principal) -> bool {
+        self.callers(permission).remove(caller)
+    }
+
+    fn callers(&mut self, permission: Permission) -> &mut HashSet<Principal> {
+        match permission {
+            Permission::Trust => &mut self.trusted_callers,
+            Permission::Export => &mut self.export_callers,
+            Permission::Protected => &mut self.protected_callers,
+        }
+    }
+
+    // Admin commands are refused until an admin token or admin caller is set
+    pub fn set_admin_token(&mut self, token: String) {
+        self.admin_token = Some(token);
+    }
+
+    pub fn allow_admin(&mut self, caller: Principal) {
+        self.admin_callers.insert(caller);
+    }
+
+    // Project tokens never unlock admin commands, and trusting a caller for
+    // its namespaces does not make it an admin
+    pub fn authorize_admin(&self, token: Option<&str>, caller: &Principal) -> Result<(), String> {
+        let token_ok = match (token, &self.admin_token) {
+            (Some(token), Some(expected)) => {
+                constant_time_eq(token.as_bytes(), expected.as_bytes())
+            }
+            _ => false,
+        };
+
+        if token_ok || listed(&self.admin_callers, caller) {
+            Ok(())
+        } else {
+            Err(format!("{} is not an admin", caller))
+        }
+    }
+
+    pub fn describe(&self) -> PolicyView {
+        let names =
+            |callers: &HashSet<Principal>| callers.iter().map(Principal::to_string).collect();
+        PolicyView {
+            namespaces: self.tokens.keys().map(Namespace::to_string).collect(),
+            protected_environments: self.protected_environments.iter().cloned().collect(),
+            trusted: names(&self.trusted_callers),
+            export: names(&self.export_callers),
+            protected: names(&self.protected_callers),
+            admins: names(&self.admin_callers),
+        }
+    }
+
+    pub fn token(&self, namespace: &Namespace) -> Option<String> {
+        self.tokens.get(namespace).cloned()
+    }
+
+    pub fn namespaces(&self) -> impl Iterator<Item = &Namespace> {
+        self.tokens.keys()
+    }
+
+    // Resolves the namespace a request targets and checks its token. A request
+    // that names no namespace resolves to the only one it can match. Only a
+    // `legacy` array request from a loopback peer may omit the token, and
+    // only while the daemon serves a single namespace, which keeps old
+    // clients on the TCP port working. Everyone else needs the token or a
+    // trust grant.
+    pub fn authorize(
+        &self,
+        namespace: Option<&str>,
+        environment: Option<&str>,
+        token: Option<&str>,
+        legacy: bool,
+        caller: &Principal,
+    ) -> Result<Namespace, String> {
+        let namespace = self.resolve(namespace, environment)?;
+
+        let expected = self
+            .tokens
+            .get(&namespace)
+            .ok_or_else(|| format!("Unknown namespace '{}'", namespace))?;
+
+        let legacy = legacy && self.tokens.len() == 1 && caller.is_loopback_peer();
+        match token {
+            Some(token) if constant_time_eq(token.as_bytes(), expected.as_bytes()) => {}
+            None if legacy || listed(&self.trusted_callers, caller) => {}
+            _ => return Err(format!("Not authorized for namespace '{}'", namespace)),
+        }
+
+        if self.is_protected(&namespace) && !listed(&self.protected_callers, caller) {
+            return Err(format!(
+                "{} is not allowed to read protected namespace '{}'",
+                caller, namespace
+            ));
+        }
+
+        Ok(namespace)
+    }
+
+    // Whether a secret in `from`, which the caller was authorized for, may
+    // reference one in `to`. The caller needs no token for `to`, or `to` has
+    // the same token as `from`, so the reference reveals nothing the caller
+    // could not read directly. Protected environments still need their
+    // allowance.
+    pub fn authorize_reference(
+        &self,
+        from: &Namespace,
+        to: &Namespace,
+        caller: &Principal,
+    ) -> Result<(), String> {
+        if from == to {
+            return Ok(());
+        }
+
+        let expected = self
+            .tokens
+            .get(to)
+            .ok_or_else(|| format!("Reference to unknown namespace '{}'", to))?;
+        let same_token = self
+            .tokens
+            .get(from)
+            .is_some_and(|token| constant_time_eq(token.as_bytes(), expected.as_bytes()));
+        if !same_token && !listed(&self.trusted_callers, caller) {
+            return Err(format!("{} may not reference secrets in '{}'", caller, to));
+        }
+
+        if self.is_protected(to) && !listed(&self.protected_callers, caller) {
+            return Err(format!(
+                "{} is not allowed to read protected namespace '{}'",
+                caller, to
+            ));
+        }
+        Ok(())
+    }
+
+    fn resolve(
+        &self,
+        namespace: Option<&str>,
+        environment: Option<&str>,
+    ) -> Result<Namespace, String> {
+        match (namespace, environment) {
+            (Some(namespace), Some(environment)) => Ok(Namespace::new(
+                Namespace::parse(namespace).project,
+                environment,
+            )),
+            (Some(namespace), None) => Ok(Namespace::parse(namespace)),
+            (None, environment) => {
+                let mut candidates = self.tokens.keys().filter(|namespace| {
+                    environment.is_none_or(|environment| namespace.environment == environment)
+                });
+
+                match (candidates.next(), candidates.next()) {
+                    (Some(namespace), None) => Ok(namespace.clone()),
+                    (None, _) => Err("No namespace matches the request".to_string()),
+                    (Some(_), Some(_)) => {
+                        Err("A namespace is required when serving several projects".to_string())
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter()
+        .zip(b.iter())
+        .fold(0u8, |acc, (x, y)| acc | (x ^ y))
+        == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local() -> Principal {
+        Principal::Peer(IpAddr::V4(Ipv4Addr::LOCALHOST))
+    }
+
+    fn policy(namespaces: &[(&str, &str)]) -> AccessPolicy {
+        let mut policy = AccessPolicy::new();
+        for (namespace, token) in namespaces {
+            policy.register(Namespace::parse(namespace), token.to_string());
+        }
+        policy
+    }
+
+    #[test]
+    fn a_token_only_unlocks_its_own_namespace() {
+        let policy = policy(&[("a/dev", "token-a"), ("b/dev", "token-b")]);
+
+        assert_eq!(
+            policy.authorize(Some("a/dev"), None, Some("token-a"), false, &local()),
+            Ok(Namespace::parse("a/dev"))
+        );
+        assert!(policy
+            .authorize(Some("b/dev"), None, Some("token-a"), false, &local())
+            .is_err());
+        assert!(policy
+            .authorize(Some("a"), Some("dev"), Some("token-b"), false, &local())
+            .is_err());
+    }
+
+    #[test]
+    fn wrong_and_missing_tokens_are_refused() {
+        let policy = policy(&[("a/dev", "token-a"), ("b/dev", "token-b")]);
+
+        for token in [Some("token-"), Some("token-aa"), Some(""), None] {
+            assert!(
+                policy
+                    .authorize(Some("a/dev"), None, token, false, &local())
+                    .is_err(),
+                "{:?}",
+                token
+            );
+        }
+        // Serving several namespaces, even a legacy request needs a token
+        assert!(policy
+            .authorize(Some("a/dev"), None, None, true, &local())
+            .is_err());
+    }
+
+    #[test]
+    fn only_legacy_requests_from_loopback_may_omit_the_token() {
+        let policy = policy(&[("a/dev", "token-a")]);
+
+        for loopback in [
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            IpAddr::V6(Ipv6Addr::LOCALHOST),
+            IpAddr::V6(Ipv4Addr::LOCALHOST.to_ipv6_mapped()),
+        ] {
+            assert_eq!(
+                policy.authorize(None, None, None, true, &Principal::Peer(loopback)),
+                Ok(Namespace::parse("a/dev"))
+            );
+        }
+
+        let refused = [
+            (false, local()),
+            (
+                true,
+                Principal::Peer(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))),
+            ),
+            (true, Principal::Cert("build-farm".to_string())),
+            (true, Principal::Client("ci".to_string())),
+            (true, Principal::Uid(1000)),
+        ];
+        for (legacy, caller) in refused {
+            assert!(
+                policy.authorize(None, None, None, legacy, &caller).is_err(),
+                "{}",
+                caller
+            );
+        }
+    }
+
+    #[test]
+    fn trusted_callers_may_omit_the_token() {
+        let mut policy = policy(&[("a/dev", "token-a"), ("b/dev", "token-b")]);
+        policy.trust(Principal::Uid(1000));
+
+        assert!(policy
+            .authorize(Some("b/dev"), None, None, false, &Principal::Uid(1000))
+            .is_ok());
+        assert!(policy
+            .authorize(Some("b/dev"), None, None, false, &Principal::Uid(1001))
+            .is_err());
+    }
+
+    #[test]
+    fn local_peers_are_granted_by_address() {
+        let mut policy = policy(&[("a/dev", "token-a"), ("b/dev", "token-b")]);
+        let owned = Principal::LocalPeer(IpAddr::V4(Ipv4Addr::LOCALHOST), 1000);
+        assert!(policy
+            .authorize(Some("b/dev"), None, None, false, &owned)
+            .is_err());
+
+        // A uid grant is for the Unix socket, not for TCP from that uid
+        policy.trust(Principal::Uid(1000));
+        assert!(policy
+            .authorize(Some("b/dev"), None, None, false, &owned)
+            .is_err());
+
+        policy.trust(local());
+        assert!(policy
+            .authorize(Some("b/dev"), None, None, false, &owned)
+            .is_ok());
+        assert_eq!(owned.to_string(), "peer:127.0.0.1/uid:1000");
+    }
+
+    #[test]
+    fn socket_owner_reads_established_sockets() {
+        let hex = |octets: [u8; 4]| format!("{:08X}", u32::from_ne_bytes(octets));
+        let client = format!("{}:D431", hex([127, 0, 0, 1]));
+        let server = format!("{}:1770", hex([127, 0, 0, 1]));
+        let row = |sl: u32, local: &str, remote: &str, state: &str, uid: u32| {
+            format!(
+                "{}: {} {} {} 00000000:00000000 00:00000000 00000000 {} 0 {} 1",
+                sl, local, remote, state, uid, sl
+            )
+        };
+        let table = [
+            "sl local_address rem_address st tx_queue rx_queue tr tm->when retrnsmt uid timeout inode"
+                .to_string(),
+            row(0, &server, "00000000:0000", "0A", 0),
+            // Sockets that are closing are listed as root's
+            row(1, &client, &server, "06", 0),
+            row(2, &client, &server, "01", 1000),
+        ]
+        .join("\n");
+        let local: SocketAddr = "127.0.0.1:54321".parse().unwrap();
+        let remote: SocketAddr = "127.0.0.1:6000".parse().unwrap();
+
+        assert_eq!(socket_owner(&table, local, remote), Some(1000));
+        assert_eq!(socket_owner(&table, remote, local), None);
+        assert_eq!(socket_owner("", local, remote), None);
+    }
+
+    #[test]
+    fn tcp_peer_finds_the_owner_of_a_loopback_socket() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, peer) = listener.accept().unwrap();
+
+        let uid = unsafe { libc::getuid() };
+        assert_eq!(
+            Principal::tcp_peer(peer, stream.local_addr().unwrap()),
+            Principal::LocalPeer(peer.ip(), uid)
+        );
+
+        let remote: SocketAddr = "192.0.2.1:40000".parse().unwrap();
+        assert_eq!(
+            Principal::tcp_peer(remote, stream.local_addr().unwrap()),
+            Principal::Peer(remote.ip())
+        );
+    }
+
+    #[test]
+    fn resolve_needs_a_namespace_when_several_match() {
+        let several = policy(&[("a/dev", "token-a"), ("a/prod", "token-p"), ("b/dev", "t")]);
+
+        assert_eq!(
+            several.resolve(None, Some("prod")),
+            Ok(Namespace::parse("a/prod"))
+        );
+        assert!(several.resolve(None, Some("dev")).is_err());
+        assert!(several.resolve(None, None).is_err());
+        assert!(several.resolve(None, Some("staging")).is_err());
+        assert_eq!(
+            several.resolve(Some("b"), Some("dev")),
+            Ok(Namespace::parse("b/dev"))
+        );
+        assert_eq!(
+            several.resolve(Some("a/prod"), None),
+            Ok(Namespace::parse("a/prod"))
+        );
+
+        let single = policy(&[("a/dev", "token-a")]);
+        assert_eq!(single.resolve(None, None), Ok(Namespace::parse("a/dev")));
+    }
+
+    #[test]
+    fn protected_environments_need_an_allowance() {
+        let mut policy = policy(&[("a/dev", "token-a"), ("a/prod", "token-p")]);
+        policy.protect_environment("prod");
+        let allowed = Principal::Uid(0);
+        policy.allow_protected(allowed.clone());
+
+        assert!(policy
+            .authorize(Some("a/prod"), None, Some("token-p"), false, &allowed)
+            .is_ok());
+        assert!(policy
+            .authorize(Some("a/prod"), None, Some("token-p"), false, &local())
+            .is_err());
+        // Only the protected environment is guarded
+        assert!(policy
+            .authorize(Some("a/dev"), None, Some("token-a"), false, &local())
+            .is_ok());
+
+        assert!(policy.unprotect_environment("prod"));
+        assert!(!policy.unprotect_environment("prod"));
+        assert!(policy
+            .authorize(Some("a/prod"), None, Some("token-p"), false, &local())
+            .is_ok());
+
+        policy.protect_environment("prod");
+        assert!(policy
+            .authorize(Some("a/prod"), None, Some("token-p"), false, &local())
+            .is_err());
+    }
+
+    #[test]
+    fn references_into_a_protected_environment_need_an_allowance() {
+        // Shared tokens, so only the guardrail stands in the way
+        let mut policy = policy(&[("a/dev", "token"), ("a/prod", "token")]);
+        policy.protect_environment("prod");
+        let allowed = Principal::Uid(0);
+        policy.allow_protected(allowed.clone());
+        let dev = Namespace::parse("a/dev");
+        let prod = Namespace::parse("a/prod");
+
+        assert!(policy.authorize_reference(&dev, &prod, &allowed).is_ok());
+        assert!(policy.authorize_reference(&dev, &prod, &local()).is_err());
+        assert!(policy.authorize_reference(&prod, &dev, &local()).is_ok());
+
+        policy.unprotect_environment("prod");
+        assert!(policy.authorize_reference(&dev, &prod, &local()).is_ok());
+    }
+
+    #[test]
+    fn references_need_a_shared_token_or_trust() {
+        let mut policy = policy(&[("a/dev", "token-a"), ("b/dev", "token-b")]);
+        let a = Namespace::parse("a/dev");
+        let b = Namespace::parse("b/dev");
+
+        assert!(policy.authorize_reference(&a, &a, &local()).is_ok());
+        assert!(policy.authorize_reference(&a, &b, &local()).is_err());
+        assert!(policy
+            .authorize_reference(&a, &Namespace::parse("c/dev"), &local())
+            .is_err());
+
+        policy.trust(local());
+        assert!(policy.authorize_reference(&a, &b, &local()).is_ok());
+    }
+}
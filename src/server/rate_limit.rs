@@ -0,0 +1,435 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::error::Error;
+use crate::server::audit;
+use crate::server::policy::Principal;
+use crate::server::server::SecretsServer;
+
+// A steady refill rate plus the burst allowed on top of it
+#[derive(Clone, Copy, Debug)]
+pub struct Rate {
+    pub per_sec: f64,
+    pub burst: f64,
+}
+
+impl Rate {
+    pub fn new(per_sec: f64, burst: f64) -> Self {
+        Rate { per_sec, burst }
+    }
+
+    // Accepts `RATE` or `RATE/BURST`, or `off` for no limit. Without a burst
+    // the bucket holds one second's worth.
+    pub fn parse(value: &str) -> Result<Option<Self>, String> {
+        if value == "off" {
+            return Ok(None);
+        }
+
+        let number = |part: &str| {
+            part.parse::<f64>()
+                .ok()
+                .filter(|n| n.is_finite() && *n > 0.0)
+                .ok_or_else(|| format!("'{}' should look like RATE[/BURST] or off", value))
+        };
+        match value.split_once('/') {
+            Some((rate, burst)) => Ok(Some(Rate::new(number(rate)?, number(burst)?))),
+            None => {
+                let rate = number(value)?;
+                Ok(Some(Rate::new(rate, rate)))
+            }
+        }
+    }
+}
+
+// What a caller is being admitted for
+#[derive(Clone, Copy, Debug)]
+pub enum Limit {
+    // Every TCP connection starts with a key exchange, so this also bounds
+    // how many handshakes a peer can make us compute
+    Connection,
+    Command,
+}
+
+impl Limit {
+    pub fn name(self) -> &'static str {
+        match self {
+            Limit::Connection => "connection",
+            Limit::Command => "command",
+        }
+    }
+}
+
+// Limits for each caller and for the daemon as a whole, and how many
+// failed handshakes or refused requests get a caller banned. `None` turns a
+// limit off.
+//
+// TCP callers are told apart by address, and every local process connects
+// from loopback, so local TCP callers are also told apart by the uid owning
+// their socket. One local user failing on purpose then only gets itself
+// banned. Setting `exempt_loopback` stops loopback peers from being banned
+// at all, for hosts where every local process is trusted. The Unix socket
+// tells callers apart by uid.
+#[derive(Clone, Debug)]
+pub struct RateLimitConfig {
+    pub connections_per_peer: Option<Rate>,
+    pub connections: Option<Rate>,
+    pub commands_per_peer: Option<Rate>,
+    pub commands: Option<Rate>,
+    // Failures within `failure_window_secs` that trigger a ban; 0 never bans
+    pub ban_after: usize,
+    pub failure_window_secs: u64,
+    pub ban_secs: u64,
+    pub exempt_loopback: bool,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            connections_per_peer: Some(Rate::new(50.0, 100.0)),
+            connections: Some(Rate::new(200.0, 400.0)),
+            commands_per_peer: Some(Rate::new(100.0, 200.0)),
+            commands: Some(Rate::new(1000.0, 2000.0)),
+            ban_after: 20,
+            failure_window_secs: 60,
+            ban_secs: 300,
+            exempt_loopback: false,
+        }
+    }
+}
+
+impl RateLimitConfig {
+    // Applies one `NAME=RATE[/BURST]` setting, where NAME is connections,
+    // peer-connections, commands or peer-commands
+    pub fn with_limit(mut self, spec: &str) -> Result<Self, String> {
+        let (name, rate) = spec
+            .split_once('=')
+            .ok_or_else(|| format!("'{}' should look like NAME=RATE[/BURST]", spec))?;
+        let rate = Rate::parse(rate)?;
+
+        match name {
+            "connections" => self.connections = rate,
+            "peer-connections" => self.connections_per_peer = rate,
+            "commands" => self.commands = rate,
+            "peer-commands" => self.commands_per_peer = rate,
+            _ => return Err(format!("Unknown rate limit '{}'", name)),
+        }
+        Ok(self)
+    }
+
+    pub fn with_ban(mut self, after: usize, ban_secs: u64) -> Self {
+        self.ban_after = after;
+        self.ban_secs = ban_secs;
+        self
+    }
+
+    pub fn with_loopback_exemption(mut self, exempt_loopback: bool) -> Self {
+        self.exempt_loopback = exempt_loopback;
+        self
+    }
+
+    fn bans(&self, caller: &Principal) -> bool {
+        match caller {
+            caller if caller.is_loopback_peer() => !self.exempt_loopback,
+            _ => true,
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(now: Instant) -> Self {
+        Bucket {
+            tokens: f64::MAX,
+            updated: now,
+        }
+    }
+
+    fn take(&mut self, rate: Option<Rate>, now: Instant) -> bool {
+        let Some(rate) = rate else {
+            return true;
+        };
+
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens.min(rate.burst) + elapsed * rate.per_sec).min(rate.burst);
+        self.updated = now;
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+
+    fn is_full(&self, rate: Option<Rate>, now: Instant) -> bool {
+        rate.is_none_or(|rate| {
+            let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+            self.tokens + elapsed * rate.per_sec >= rate.burst
+        })
+    }
+}
+
+struct PeerState {
+    connections: Bucket,
+    commands: Bucket,
+    failures: VecDeque<Instant>,
+    banned_until: Option<Instant>,
+}
+
+impl PeerState {
+    fn new(now: Instant) -> Self {
+        PeerState {
+            connections: Bucket::new(now),
+            commands: Bucket::new(now),
+            failures: VecDeque::new(),
+            banned_until: None,
+        }
+    }
+}
+
+struct LimiterState {
+    connections: Bucket,
+    commands: Bucket,
+    peers: HashMap<Principal, PeerState>,
+}
+
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    state: Mutex<LimiterState>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        let now = Instant::now();
+        RateLimiter {
+            config,
+            state: Mutex::new(LimiterState {
+                connections: Bucket::new(now),
+                commands: Bucket::new(now),
+                peers: HashMap::new(),
+            }),
+        }
+    }
+
+    // Takes one token from the caller's bucket and the global one. Banned
+    // callers are refused outright.
+    pub fn check(&self, caller: &Principal, limit: Limit) -> Result<(), Error> {
+        let now = Instant::now();
+        let mut state = self
+            .state
+            .lock()
+            .map_err(|e| Error::Store(format!("Error locking rate limiter: {}", e)))?;
+        let LimiterState {
+            connections,
+            commands,
+            peers,
+        } = &mut *state;
+        let peer = peers
+            .entry(caller.clone())
+            .or_insert_with(|| PeerState::new(now));
+
+        if let Some(until) = peer.banned_until {
+            if until > now {
+                return Err(Error::RateLimited(format!(
+                    "{} is banned for another {}s",
+                    caller,
+                    (until - now).as_secs().max(1)
+                )));
+            }
+            peer.banned_until = None;
+        }
+
+        let (peer_bucket, peer_rate, global_bucket, global_rate) = match limit {
+            Limit::Connection => (
+                &mut peer.connections,
+                self.config.connections_per_peer,
+                connections,
+                self.config.connections,
+            ),
+            Limit::Command => (
+                &mut peer.commands,
+                self.config.commands_per_peer,
+                commands,
+                self.config.commands,
+            ),
+        };
+
+        if !peer_bucket.take(peer_rate, now) {
+            return Err(Error::RateLimited(format!(
+                "Too many {} attempts from {}",
+                limit.name(),
+                caller
+            )));
+        }
+        if !global_bucket.take(global_rate, now) {
+            return Err(Error::RateLimited(format!(
+                "Too many {} attempts",
+                limit.name()
+            )));
+        }
+        Ok(())
+    }
+
+    // Counts a failed handshake or refused request. Returns the failures
+    // seen in the window when this one earns the caller a ban.
+    pub fn record_failure(&self, caller: &Principal) -> Option<usize> {
+        if self.config.ban_after == 0 || !self.config.bans(caller) {
+            return None;
+        }
+
+        let now = Instant::now();
+        let window = Duration::from_secs(self.config.failure_window_secs);
+        let mut state = self.state.lock().ok()?;
+        let peer = state
+            .peers
+            .entry(caller.clone())
+            .or_insert_with(|| PeerState::new(now));
+
+        while peer
+            .failures
+            .front()
+            .is_some_and(|failed| now.saturating_duration_since(*failed) > window)
+        {
+            peer.failures.pop_front();
+        }
+        peer.failures.push_back(now);
+
+        if peer.failures.len() < self.config.ban_after {
+            return None;
+        }
+        let failures = peer.failures.len();
+        peer.failures.clear();
+        peer.banned_until = Some(now + Duration::from_secs(self.config.ban_secs));
+        Some(failures)
+    }
+
+    pub fn banned(&self) -> usize {
+        let now = Instant::now();
+        self.state.lock().map_or(0, |state| {
+            state
+                .peers
+                .values()
+                .filter(|peer| peer.banned_until.is_some_and(|until| until > now))
+                .count()
+        })
+    }
+
+    // Forgets callers with nothing left to remember: full buckets, no recent
+    // failures and no ban
+    pub fn prune(&self) {
+        let now = Instant::now();
+        let window = Duration::from_secs(self.config.failure_window_secs);
+        let Ok(mut state) = self.state.lock() else {
+            return;
+        };
+
+        state.peers.retain(|_, peer| {
+            peer.banned_until.is_some_and(|until| until > now)
+                || peer
+                    .failures
+                    .back()
+                    .is_some_and(|failed| now.saturating_duration_since(*failed) <= window)
+                || !peer
+                    .connections
+                    .is_full(self.config.connections_per_peer, now)
+                || !peer.commands.is_full(self.config.commands_per_peer, now)
+        });
+    }
+
+    pub fn ban_secs(&self) -> u64 {
+        self.config.ban_secs
+    }
+}
+
+impl SecretsServer {
+    pub fn with_rate_limits(mut self, config: RateLimitConfig) -> Self {
+        self.rate_limiter = std::sync::Arc::new(RateLimiter::new(config));
+        self
+    }
+
+    // Admits `caller` for one more connection or command, counting refusals
+    pub(crate) fn admit(&self, caller: &Principal, limit: Limit) -> Result<(), Error> {
+        let result = self.rate_limiter.check(caller, limit);
+        if let Err(e) = &result {
+            self.metrics.record_rate_limited(limit.name());
+            audit::rate_limited(caller, limit.name(), &e.to_string());
+        }
+        result
+    }
+
+    // Called for failed handshakes and refused requests; bans the caller
+    // once there have been too many
+    pub(crate) fn record_failure(&self, caller: &Principal) {
+        if let Some(failures) = self.rate_limiter.record_failure(caller) {
+            self.metrics.record_ban();
+            audit::ban(caller, failures, self.rate_limiter.ban_secs());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    fn fail(limiter: &RateLimiter, caller: &Principal, times: usize) -> Option<usize> {
+        (0..times)
+            .filter_map(|_| limiter.record_failure(caller))
+            .last()
+    }
+
+    #[test]
+    fn loopback_peers_are_banned_by_default() {
+        let limiter = RateLimiter::new(RateLimitConfig::default().with_ban(3, 60));
+        let loopback = [
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            IpAddr::V6(Ipv6Addr::LOCALHOST),
+            IpAddr::V6(Ipv4Addr::LOCALHOST.to_ipv6_mapped()),
+        ];
+
+        for ip in loopback {
+            let caller = Principal::Peer(ip);
+            assert_eq!(fail(&limiter, &caller, 3), Some(3));
+            assert!(limiter.check(&caller, Limit::Connection).is_err());
+        }
+    }
+
+    #[test]
+    fn local_peers_are_banned_by_uid() {
+        let limiter = RateLimiter::new(RateLimitConfig::default().with_ban(3, 60));
+        let loopback = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let attacker = Principal::LocalPeer(loopback, 1000);
+        let neighbour = Principal::LocalPeer(loopback, 1001);
+
+        assert_eq!(fail(&limiter, &attacker, 3), Some(3));
+        assert!(limiter.check(&attacker, Limit::Connection).is_err());
+        assert!(limiter.check(&neighbour, Limit::Connection).is_ok());
+        assert!(limiter
+            .check(&Principal::Peer(loopback), Limit::Connection)
+            .is_ok());
+    }
+
+    #[test]
+    fn exempt_loopback_spares_only_loopback_peers() {
+        let remote = Principal::Peer(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)));
+        let loopback = Principal::Peer(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        let limiter = RateLimiter::new(
+            RateLimitConfig::default()
+                .with_ban(3, 60)
+                .with_loopback_exemption(true),
+        );
+
+        let owned = Principal::LocalPeer(IpAddr::V4(Ipv4Addr::LOCALHOST), 1000);
+        for caller in [&loopback, &owned] {
+            assert_eq!(fail(&limiter, caller, 10), None);
+            assert!(limiter.check(caller, Limit::Connection).is_ok());
+        }
+        assert_eq!(fail(&limiter, &remote, 3), Some(3));
+        assert!(limiter.check(&remote, Limit::Command).is_err());
+        assert_eq!(limiter.banned(), 1);
+    }
+}
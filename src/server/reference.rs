@@ -0,0 +1,431 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::error::Error;
+use crate::server::store::SecureStore;
+use crate::server::validation::SecretTypes;
+use crate::types::change::ChangeEvent;
+use crate::types::namespace::Namespace;
+use crate::types::secret_type::SecretType;
+
+// Longest value a chain of references may expand to
+const MAX_RESOLVED_SIZE: usize = 1024 * 1024;
+
+// Expands references between secrets when they are read. Only keys declared
+// with the `template` type are expanded, so values that happen to contain
+// `${` are served as written. In a template, `${KEY}` stands for another key
+// in the same namespace and `${PROJECT[/ENV]:KEY}` for a key in another one;
+// `$${KEY}` is a literal `${KEY}`. Values are stored as written, so a
+// reference always resolves to the live version of the key it names, even
+// after that key changed or was rotated. A referenced template is expanded in
+// turn; any other key is inserted as it is.
+//
+// A reference to a key that does not exist, or that does not look like a key
+// name (e.g. the shell's `${VAR:-default}`), is left as it is, and so is a
+// `$$` in front of it.
+pub struct Resolver<'a> {
+    store: &'a SecureStore,
+    types: &'a SecretTypes,
+    // Decides whether references may reach a namespace. Asked once per
+    // namespace, since it may have to lock the policy.
+    allow: &'a dyn Fn(&Namespace) -> Result<(), Error>,
+    allowed: HashSet<Namespace>,
+    resolved: HashMap<(Namespace, String), String>,
+}
+
+impl<'a> Resolver<'a> {
+    pub fn new(
+        store: &'a SecureStore,
+        types: &'a SecretTypes,
+        allow: &'a dyn Fn(&Namespace) -> Result<(), Error>,
+    ) -> Self {
+        Resolver {
+            store,
+            types,
+            allow,
+            allowed: HashSet::new(),
+            resolved: HashMap::new(),
+        }
+    }
+
+    // Expands `value`, which `key` in `namespace` holds, possibly at an older
+    // version
+    pub fn resolve(
+        &mut self,
+        namespace: &Namespace,
+        key: &str,
+        value: &str,
+    ) -> Result<String, Error> {
+        if !is_template(self.types, namespace, key) {
+            return Ok(value.to_string());
+        }
+        let mut stack = vec![(namespace.clone(), key.to_string())];
+        self.expand(namespace, value, &mut stack)
+    }
+
+    fn expand(
+        &mut self,
+        namespace: &Namespace,
+        value: &str,
+        stack: &mut Vec<(Namespace, String)>,
+    ) -> Result<String, Error> {
+        let mut expanded = String::new();
+        let mut rest = value;
+
+        while let Some(start) = rest.find('$') {
+            expanded.push_str(&rest[..start]);
+            rest = &rest[start..];
+
+            // `$$` only escapes what would otherwise be read as a reference
+            if rest.starts_with("$$") {
+                if let Some(length) = reference_length(namespace, &rest[1..]) {
+                    expanded.push_str(&rest[1..1 + length]);
+                    rest = &rest[1 + length..];
+                    continue;
+                }
+            }
+
+            let Some(end) = rest.strip_prefix("${").and_then(|body| body.find('}')) else {
+                expanded.push('$');
+                rest = &rest[1..];
+                continue;
+            };
+            let reference = &rest[2..2 + end];
+            rest = &rest[3 + end..];
+
+            match self.lookup(namespace, reference, stack)? {
+                Some(value) => expanded.push_str(&value),
+                None => {
+                    expanded.push_str("${");
+                    expanded.push_str(reference);
+                    expanded.push('}');
+                }
+            }
+
+            if expanded.len() > MAX_RESOLVED_SIZE {
+                return Err(Error::Validation(format!(
+                    "References in '{}' expand to more than {} bytes",
+                    stack[0].1, MAX_RESOLVED_SIZE
+                )));
+            }
+        }
+
+        expanded.push_str(rest);
+        Ok(expanded)
+    }
+
+    fn lookup(
+        &mut self,
+        namespace: &Namespace,
+        reference: &str,
+        stack: &mut Vec<(Namespace, String)>,
+    ) -> Result<Option<String>, Error> {
+        let Some(id) = parse(namespace, reference) else {
+            return Ok(None);
+        };
+        if !self.allowed.contains(&id.0) {
+            (self.allow)(&id.0)?;
+            self.allowed.insert(id.0.clone());
+        }
+
+        if let Some(value) = self.resolved.get(&id) {
+            return Ok(Some(value.clone()));
+        }
+        if let Some(position) = stack.iter().position(|entry| *entry == id) {
+            let cycle: Vec<String> = stack[position..]
+                .iter()
+                .chain(std::iter::once(&id))
+                .map(|(namespace, key)| format!("{}:{}", namespace, key))
+                .collect();
+            return Err(Error::Validation(format!(
+                "Reference cycle: {}",
+                cycle.join(" -> ")
+            )));
+        }
+
+        let Some(raw) = self.store.get_secret(&id.0, &id.1) else {
+            return Ok(None);
+        };
+        if !is_template(self.types, &id.0, &id.1) {
+            return Ok(Some(raw));
+        }
+        stack.push(id.clone());
+        let value = self.expand(&id.0, &raw, stack);
+        stack.pop();
+
+        let value = value?;
+        self.resolved.insert(id, value.clone());
+        Ok(Some(value))
+    }
+}
+
+// Events for the templates in `namespace` whose expansion `event` changes:
+// those that reference its key, directly or through other templates. Change
+// events otherwise only cover keys that were written, and a template's own
+// value stays the same when a key it references rotates.
+pub fn dependents(
+    store: &SecureStore,
+    types: &SecretTypes,
+    namespace: &Namespace,
+    event: &ChangeEvent,
+) -> Vec<ChangeEvent> {
+    let changed = (event.namespace.clone(), event.key.clone());
+    let mut dependents: Vec<ChangeEvent> = types
+        .keys(namespace, SecretType::Template)
+        .into_iter()
+        .filter(|key| (namespace, key) != (&changed.0, &changed.1))
+        .filter(|key| depends_on(store, types, namespace, key, &changed))
+        .filter_map(|key| {
+            let version = store.get_metadata(namespace, &key)?.version;
+            Some(ChangeEvent::referenced(namespace, &key, version, event))
+        })
+        .collect();
+
+    dependents.sort_by(|a, b| a.key.cmp(&b.key));
+    dependents
+}
+
+// Whether expanding the template `key` reads `changed`
+fn depends_on(
+    store: &SecureStore,
+    types: &SecretTypes,
+    namespace: &Namespace,
+    key: &str,
+    changed: &(Namespace, String),
+) -> bool {
+    let mut seen = HashSet::new();
+    let mut pending = vec![(namespace.clone(), key.to_string())];
+    while let Some(id) = pending.pop() {
+        if !seen.insert(id.clone()) {
+            continue;
+        }
+        let Some(value) = store.get_secret(&id.0, &id.1) else {
+            continue;
+        };
+        for reference in references(&id.0, &value) {
+            if reference == *changed {
+                return true;
+            }
+            if is_template(types, &reference.0, &reference.1) {
+                pending.push(reference);
+            }
+        }
+    }
+    false
+}
+
+// The keys `value` references, read the way `Resolver::expand` reads them
+fn references(namespace: &Namespace, value: &str) -> Vec<(Namespace, String)> {
+    let mut found = Vec::new();
+    let mut rest = value;
+
+    while let Some(start) = rest.find('$') {
+        rest = &rest[start..];
+
+        if rest.starts_with("$$") {
+            if let Some(length) = reference_length(namespace, &rest[1..]) {
+                rest = &rest[1 + length..];
+                continue;
+            }
+        }
+
+        let Some(end) = rest.strip_prefix("${").and_then(|body| body.find('}')) else {
+            rest = &rest[1..];
+            continue;
+        };
+        found.extend(parse(namespace, &rest[2..2 + end]));
+        rest = &rest[3 + end..];
+    }
+    found
+}
+
+pub fn is_template(types: &SecretTypes, namespace: &Namespace, key: &str) -> bool {
+    types.get(namespace, key) == Some(SecretType::Template)
+}
+
+// The key a reference written in `namespace` names, if it looks like one
+fn parse(namespace: &Namespace, reference: &str) -> Option<(Namespace, String)> {
+    let (target, key) = match reference.split_once(':') {
+        Some((target, key)) if is_namespace_name(target) => (Namespace::parse(target), key),
+        Some(_) => return None,
+        None => (namespace.clone(), reference),
+    };
+    is_key_name(key).then(|| (target, key.to_string()))
+}
+
+// How much of `text` a reference at its start takes up, `${` to `}`
+fn reference_length(namespace: &Namespace, text: &str) -> Option<usize> {
+    let end = text.strip_prefix("${")?.find('}')?;
+    parse(namespace, &text[2..2 + end]).map(|_| end + 3)
+}
+
+fn is_key_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|first| first.is_ascii_alphabetic() || first == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'))
+}
+
+fn is_namespace_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-' | '/'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::policy::{AccessPolicy, Principal};
+    use crate::types::metadata::SecretOrigin;
+
+    fn fixture(secrets: &[(&str, &str)], templates: &[&str]) -> (SecureStore, SecretTypes) {
+        let namespace = Namespace::parse("app/dev");
+        let mut store = SecureStore::new();
+        for (key, value) in secrets {
+            store
+                .store_secret(
+                    &namespace,
+                    key.to_string(),
+                    value.to_string(),
+                    SecretOrigin::StoreEnv,
+                    None,
+                )
+                .unwrap();
+        }
+        let mut types = SecretTypes::new();
+        for key in templates {
+            types.declare(&namespace, key.to_string(), SecretType::Template);
+        }
+        (store, types)
+    }
+
+    fn resolve(store: &SecureStore, types: &SecretTypes, key: &str) -> Result<String, Error> {
+        let namespace = Namespace::parse("app/dev");
+        let allow = |_: &Namespace| Ok(());
+        let value = store.get_secret(&namespace, key).unwrap();
+        Resolver::new(store, types, &allow).resolve(&namespace, key, &value)
+    }
+
+    #[test]
+    fn only_templates_are_expanded() {
+        let (store, types) = fixture(
+            &[
+                ("USER", "app"),
+                ("URL", "postgres://${USER}@db"),
+                ("SCRIPT", "echo ${USER}"),
+            ],
+            &["URL"],
+        );
+        assert_eq!(resolve(&store, &types, "URL").unwrap(), "postgres://app@db");
+        assert_eq!(resolve(&store, &types, "SCRIPT").unwrap(), "echo ${USER}");
+    }
+
+    #[test]
+    fn referenced_values_are_inserted_as_written() {
+        let (store, types) = fixture(
+            &[
+                ("RAW", "a${B}"),
+                ("B", "b"),
+                ("INNER", "[${B}]"),
+                ("OUTER", "${RAW} ${INNER}"),
+            ],
+            &["INNER", "OUTER"],
+        );
+        assert_eq!(resolve(&store, &types, "OUTER").unwrap(), "a${B} [b]");
+    }
+
+    #[test]
+    fn escapes_only_apply_to_references() {
+        let (store, types) = fixture(
+            &[
+                ("USER", "app"),
+                ("T", "$${USER} ${USER} $${X:-d} $$ $${ ${MISSING}"),
+            ],
+            &["T"],
+        );
+        assert_eq!(
+            resolve(&store, &types, "T").unwrap(),
+            "${USER} app $${X:-d} $$ $${ ${MISSING}"
+        );
+    }
+
+    #[test]
+    fn cycles_between_templates_are_refused() {
+        let (store, types) = fixture(&[("A", "${B}"), ("B", "${A}")], &["A", "B"]);
+        assert!(matches!(
+            resolve(&store, &types, "A"),
+            Err(Error::Validation(_))
+        ));
+
+        // A cycle through a plain value is just text
+        let (store, types) = fixture(&[("A", "${B}"), ("B", "${A}")], &["A"]);
+        assert_eq!(resolve(&store, &types, "A").unwrap(), "${A}");
+    }
+
+    #[test]
+    fn templates_follow_the_keys_they_reference() {
+        let namespace = Namespace::parse("app/dev");
+        let (store, types) = fixture(
+            &[
+                ("PASSWORD", "p"),
+                ("HOST", "h"),
+                ("DSN", "${PASSWORD}@${HOST}"),
+                ("URL", "db://${DSN}"),
+                ("ESCAPED", "$${PASSWORD}"),
+                ("PLAIN", "${PASSWORD}"),
+            ],
+            &["DSN", "URL", "ESCAPED"],
+        );
+
+        let event = ChangeEvent::changed(&namespace, "PASSWORD", 2);
+        let keys: Vec<String> = dependents(&store, &types, &namespace, &event)
+            .into_iter()
+            .map(|dependent| {
+                assert_eq!(dependent.reference.as_deref(), Some("app/dev:PASSWORD"));
+                dependent.key
+            })
+            .collect();
+        assert_eq!(keys, ["DSN", "URL"]);
+
+        let event = ChangeEvent::changed(&namespace, "URL", 2);
+        assert!(dependents(&store, &types, &namespace, &event).is_empty());
+    }
+
+    #[test]
+    fn references_into_a_protected_environment_follow_the_policy() {
+        let dev = Namespace::parse("app/dev");
+        let prod = Namespace::parse("app/prod");
+        let (mut store, types) = fixture(&[("URL", "db://${app/prod:PASSWORD}")], &["URL"]);
+        store
+            .store_secret(
+                &prod,
+                "PASSWORD".to_string(),
+                "p".to_string(),
+                SecretOrigin::Backend,
+                None,
+            )
+            .unwrap();
+
+        let mut policy = AccessPolicy::new();
+        policy.register(dev.clone(), "token".to_string());
+        policy.register(prod.clone(), "token".to_string());
+        policy.protect_environment("prod");
+        policy.allow_protected(Principal::Uid(0));
+
+        let expand = |caller: &Principal| {
+            let allow = |target: &Namespace| {
+                policy
+                    .authorize_reference(&dev, target, caller)
+                    .map_err(Error::Policy)
+            };
+            let value = store.get_secret(&dev, "URL").unwrap();
+            Resolver::new(&store, &types, &allow).resolve(&dev, "URL", &value)
+        };
+        assert_eq!(expand(&Principal::Uid(0)).unwrap(), "db://p");
+        assert!(matches!(
+            expand(&Principal::Uid(1000)),
+            Err(Error::Policy(_))
+        ));
+    }
+}
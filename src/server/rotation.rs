@@ -0,0 +1,53 @@
+use log::{error, info};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::error::Error;
+use crate::server::audit;
+use crate::server::server::SecretsServer;
+
+// Every secret is sealed under its own key, generated when the value is
+// stored. A long-running daemon would otherwise keep that key material for
+// as long as the value stays unchanged, so keys are rotated on a schedule
+// and on demand with the `rotate_keys` admin command.
+impl SecretsServer {
+    pub fn with_key_rotation(mut self, interval_secs: u64) -> Self {
+        self.key_rotation_secs = Some(interval_secs);
+        self
+    }
+
+    // Reseals every stored version under fresh keys while holding the store
+    // exclusively, so readers never see a half-rotated store. `trigger` is
+    // audited: the schedule, or the caller of the admin command.
+    pub fn rotate_keys(&self, trigger: &str) -> Result<usize, Error> {
+        let result = self
+            .store
+            .write()
+            .map_err(|e| Error::Store(format!("Error locking store: {}", e)))
+            .and_then(|mut store| store.rotate_keys().map_err(Error::Store));
+
+        match &result {
+            Ok(blocks) => {
+                info!("Rotated the keys of {} secure blocks", blocks);
+                self.metrics.record_key_rotation();
+                audit::key_rotation(trigger, Ok(*blocks));
+            }
+            Err(e) => audit::key_rotation(trigger, Err(&e.to_string())),
+        }
+        result
+    }
+
+    pub(crate) fn spawn_key_rotation(self: &Arc<Self>) {
+        let Some(interval_secs) = self.key_rotation_secs else {
+            return;
+        };
+
+        let server = Arc::clone(self);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(Duration::from_secs(interval_secs));
+            if let Err(e) = server.rotate_keys("schedule") {
+                error!("Failed to rotate keys: {}", e);
+            }
+        });
+    }
+}
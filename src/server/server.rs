@@ -1,37 +1,106 @@
 use byteorder::{NetworkEndian, WriteBytesExt};
-use daemonize::Daemonize;
 use env_logger;
 use log::{error, info};
-use num_bigint::{BigUint, RandBigInt};
-use reqwest::{
-    header::{HeaderMap, HeaderValue, AUTHORIZATION},
-    Client,
-};
+use num_bigint::BigUint;
+use reqwest::Client;
 use serde::Serialize;
 use serde_json::{self, Value};
-use std::collections::HashMap;
-use std::error::Error;
-use std::fs::File;
+use std::collections::{BTreeMap, HashMap};
 use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream};
-use std::sync::{Arc, Mutex};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::task::JoinSet;
 
-use crate::server::key_exchange::DHKeyExchange;
+use crate::error::Error;
+use crate::server::audit;
+use crate::server::formats::{export, import};
+use crate::server::identity::{self, ClientRegistry};
+use crate::server::key_exchange::{DHKeyExchange, SessionCipher};
+use crate::server::lifecycle::{set_timeouts, stopped, Inflight};
+use crate::server::metrics::Metrics;
+use crate::server::policy::{AccessPolicy, Principal};
+use crate::server::rate_limit::{Limit, RateLimitConfig, RateLimiter};
+use crate::server::reference::{self, Resolver};
+use crate::server::snapshot::{format_timestamp, SnapshotConfig, SnapshotEntry};
+use crate::server::source::{FetchedSecrets, SecretSource, ShinobiApiSource};
+use crate::server::status::{StatusScope, SyncRecord};
 use crate::server::store::SecureStore;
+use crate::server::template::{TemplateConfig, TemplateRenderer};
+use crate::server::tls::TlsConfig;
+use crate::server::validation::{describe_rejected, SecretTypes};
+use crate::server::write_back::{WriteBackConfig, WriteQueue};
+use crate::types::change::{ChangeEvent, Subscription};
+use crate::types::key_filter::KeyFilter;
+use crate::types::metadata::{unix_now, SecretOrigin};
+use crate::types::namespace::Namespace;
 use crate::types::protected_secret::ProtectedSecret;
+use crate::types::request::{Command, Request};
+
+// How often the background reaper wipes expired secrets
+const REAP_INTERVAL_SECS: u64 = 5;
+// How often templates check the store for changes
+const TEMPLATE_POLL_SECS: u64 = 1;
+// How long a watch connection may stay silent before an empty keep-alive
+// frame is sent, which is also how a closed watcher gets noticed
+const WATCH_HEARTBEAT_SECS: u64 = 30;
 
 #[derive(Clone)]
 pub struct SecretsServer {
-    pub store: Arc<Mutex<SecureStore>>,
+    // Readers such as get_env share the lock; only changes take it exclusively
+    pub store: Arc<RwLock<SecureStore>>,
+    pub policy: Arc<Mutex<AccessPolicy>>,
+    pub source: Arc<dyn SecretSource>,
     pub client: Client,
     pub base_url: String,
     pub token: String,
+    pub snapshot: Option<SnapshotConfig>,
+    // Namespaces currently served from the snapshot, with its creation time
+    pub cached_namespaces: Arc<Mutex<HashMap<Namespace, u64>>>,
+    pub write_back: Option<WriteBackConfig>,
+    pub write_queue: Arc<Mutex<WriteQueue>>,
+    pub templates: Vec<TemplateConfig>,
+    // Forces a re-render even if no secret changed, e.g. after a reload
+    pub templates_stale: Arc<AtomicBool>,
+    pub http_socket: Option<PathBuf>,
+    pub started_at: u64,
+    // Set once every project passed to `run` has been loaded
+    pub ready: Arc<AtomicBool>,
+    pub sync_records: Arc<Mutex<HashMap<Namespace, SyncRecord>>>,
+    pub listeners: Arc<Mutex<Vec<String>>>,
+    pub metrics: Arc<Metrics>,
+    pub metrics_addr: Option<SocketAddr>,
+    pub rate_limiter: Arc<RateLimiter>,
+    pub tls: Option<TlsConfig>,
+    pub identities: Arc<RwLock<ClientRegistry>>,
+    pub secret_types: Arc<RwLock<SecretTypes>>,
+    // The projects passed to `run`, which a reload fetches again
+    pub projects: Vec<GetKeysInput>,
+    pub key_rotation_secs: Option<u64>,
+    // Bounds on the TCP port, which TLS connections get from `TlsConfig`
+    pub connection_timeout_secs: u64,
+    pub max_connections: usize,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Clone, Debug, Serialize)]
 pub struct GetKeysInput {
     pub project_name: String,
     pub token: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub environment: Option<String>,
+}
+
+impl GetKeysInput {
+    pub fn namespace(&self) -> Namespace {
+        match &self.environment {
+            Some(environment) => Namespace::new(&self.project_name, environment),
+            None => Namespace::project(&self.project_name),
+        }
+    }
 }
 
 impl SecretsServer {
@@ -40,73 +109,337 @@ impl SecretsServer {
         let client = Client::new();
 
         SecretsServer {
-            store: Arc::new(Mutex::new(store)),
+            store: Arc::new(RwLock::new(store)),
+            policy: Arc::new(Mutex::new(AccessPolicy::new())),
+            source: Arc::new(ShinobiApiSource::new(
+                client.clone(),
+                base_url.clone(),
+                token.clone(),
+            )),
             client,
             base_url,
             token,
+            snapshot: None,
+            cached_namespaces: Arc::new(Mutex::new(HashMap::new())),
+            write_back: None,
+            write_queue: Arc::new(Mutex::new(WriteQueue::new())),
+            templates: Vec::new(),
+            templates_stale: Arc::new(AtomicBool::new(false)),
+            http_socket: None,
+            started_at: unix_now(),
+            ready: Arc::new(AtomicBool::new(false)),
+            sync_records: Arc::new(Mutex::new(HashMap::new())),
+            listeners: Arc::new(Mutex::new(Vec::new())),
+            metrics: Arc::new(Metrics::new()),
+            metrics_addr: None,
+            rate_limiter: Arc::new(RateLimiter::new(RateLimitConfig::default())),
+            tls: None,
+            identities: Arc::new(RwLock::new(ClientRegistry::new())),
+            secret_types: Arc::new(RwLock::new(SecretTypes::new())),
+            projects: Vec::new(),
+            key_rotation_secs: None,
+            connection_timeout_secs: 30,
+            max_connections: 256,
+        }
+    }
+
+    // Every read and write on a TCP connection gets `timeout_secs`, the key
+    // exchange included, and connections past `max_connections` are turned
+    // away, so stalled clients cannot tie up the blocking pool
+    pub fn with_connection_limits(mut self, timeout_secs: u64, max_connections: usize) -> Self {
+        self.connection_timeout_secs = timeout_secs;
+        self.max_connections = max_connections;
+        self
+    }
+
+    pub fn with_snapshot(mut self, snapshot: SnapshotConfig) -> Self {
+        self.snapshot = Some(snapshot);
+        self
+    }
+
+    // Replaces the Shinobi backend with another source, such as a
+    // `LayeredSource` combining files and the environment
+    pub fn with_source(mut self, source: impl SecretSource + 'static) -> Self {
+        self.source = Arc::new(source);
+        self
+    }
+
+    pub fn with_template(mut self, template: TemplateConfig) -> Self {
+        self.templates.push(template);
+        self
+    }
+
+    // Secrets synced or stored into `namespace` without a TTL of their own
+    // expire after `ttl_secs`
+    pub fn with_namespace_ttl(self, namespace: &Namespace, ttl_secs: u64) -> Self {
+        if let Ok(mut store) = self.store.write() {
+            store.set_namespace_ttl(namespace, Some(ttl_secs));
         }
+        self
+    }
+
+    // Renders the templates once the initial sync is done, then again
+    // whenever the store changes, e.g. after a refresh or a store_env
+    fn spawn_template_renderer(&self) {
+        let mut renderer = TemplateRenderer::new(self.templates.clone());
+        if renderer.is_empty() {
+            return;
+        }
+
+        let store = Arc::clone(&self.store);
+        let types = Arc::clone(&self.secret_types);
+        let stale = Arc::clone(&self.templates_stale);
+        let namespaces = renderer.namespaces();
+        std::thread::spawn(move || {
+            let mut rendered_generation = None;
+            loop {
+                let values: HashMap<Namespace, HashMap<String, String>> = match store.read() {
+                    Ok(store)
+                        if stale.swap(false, Ordering::SeqCst)
+                            || rendered_generation != Some(store.generation()) =>
+                    {
+                        rendered_generation = Some(store.generation());
+                        match types.read() {
+                            Ok(types) => namespaces
+                                .iter()
+                                .filter_map(|namespace| {
+                                    match resolve_own(&store, &types, namespace) {
+                                        Ok(values) => Some((namespace.clone(), values)),
+                                        Err(e) => {
+                                            error!(
+                                                "Not rendering templates for {}: {}",
+                                                namespace, e
+                                            );
+                                            None
+                                        }
+                                    }
+                                })
+                                .collect(),
+                            Err(e) => {
+                                error!("Error locking secret types: {}", e);
+                                HashMap::new()
+                            }
+                        }
+                    }
+                    Ok(_) => HashMap::new(),
+                    Err(e) => {
+                        error!("Error locking store: {}", e);
+                        HashMap::new()
+                    }
+                };
+
+                if !values.is_empty() {
+                    renderer.refresh(|namespace, key| values.get(namespace)?.get(key).cloned());
+                }
+
+                std::thread::sleep(std::time::Duration::from_secs(TEMPLATE_POLL_SECS));
+            }
+        });
     }
 
     pub async fn build_project(
         &self,
         input: GetKeysInput,
-    ) -> Result<serde_json::Value, Box<dyn Error>> {
-        let url = format!("{}/projects/getkeys", self.base_url);
+    ) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+        ShinobiApiSource::new(
+            self.client.clone(),
+            self.base_url.clone(),
+            self.token.clone(),
+        )
+        .build_project(&input)
+        .await
+    }
 
-        let mut headers = HeaderMap::new();
-        headers.insert(
-            AUTHORIZATION,
-            HeaderValue::from_str(&format!("Bearer {}", self.token))?,
+    // Fetches one project's keys from the configured source and merges them
+    // into its namespace, leaving every other namespace untouched.
+    pub async fn sync_project(&self, input: GetKeysInput) -> Result<Namespace, Error> {
+        let namespace = input.namespace();
+        let started = Instant::now();
+        let result = self.apply_project(input).await;
+        self.metrics.record_fetch(result.is_ok(), started.elapsed());
+        self.record_sync(
+            &namespace,
+            result.as_ref().map(|_| ()).map_err(|e| e.to_string()),
         );
+        result
+    }
 
-        let response = self
-            .client
-            .post(&url)
-            .headers(headers)
-            .json(&input)
-            .send()
-            .await?;
-
-        if response.status() == reqwest::StatusCode::OK {
-            let project: Value = response.json().await?;
-            info!("{:?}", project.get("keys"));
-            Ok(project)
-        } else {
-            let error_msg: Value = response.json().await?;
-            Err(format!("{:?}", error_msg).into())
+    async fn apply_project(&self, input: GetKeysInput) -> Result<Namespace, Error> {
+        let namespace = input.namespace();
+
+        let FetchedSecrets {
+            mut secrets,
+            versions,
+        } = self.source.fetch(&input).await.map_err(Error::Backend)?;
+        let token = input.token;
+
+        let mut store = self
+            .store
+            .write()
+            .map_err(|e| Error::Store(format!("Error locking store: {}", e)))?;
+
+        // Local changes that have not reached the backend yet win over the
+        // fetched values until they are written back
+        if self.write_back.is_some() {
+            let mut queue = self
+                .write_queue
+                .lock()
+                .map_err(|e| Error::Store(format!("Error locking write queue: {}", e)))?;
+            for key in queue.keys(&namespace) {
+                if queue.is_pending(&namespace, &key) {
+                    match store.get_secret(&namespace, &key) {
+                        Some(value) => secrets.insert(key, value),
+                        None => secrets.remove(&key),
+                    };
+                }
+            }
+            queue.record_backend_versions(&namespace, versions);
+        }
+
+        // A fetched value that does not match its key's type keeps the
+        // version already stored, or stays out if there is none. The rest of
+        // the namespace is still applied.
+        let rejected = self
+            .secret_types
+            .read()
+            .map_err(|e| Error::Store(format!("Error locking secret types: {}", e)))?
+            .rejected(&namespace, &secrets, &HashMap::new());
+        for (key, _) in &rejected {
+            match store.get_secret(&namespace, key) {
+                Some(value) => secrets.insert(key.clone(), value),
+                None => secrets.remove(key),
+            };
+        }
+
+        store
+            .merge_namespace(&namespace, secrets, SecretOrigin::Backend)
+            .map_err(Error::Store)?;
+        drop(store);
+
+        self.policy
+            .lock()
+            .map_err(|e| Error::Policy(format!("Error locking policy: {}", e)))?
+            .register(namespace.clone(), token);
+
+        self.cached_namespaces
+            .lock()
+            .map_err(|e| Error::Store(format!("Error locking snapshot state: {}", e)))?
+            .remove(&namespace);
+
+        if let Err(e) = self.write_snapshot() {
+            error!("Failed to write snapshot: {}", e);
+        }
+
+        if !rejected.is_empty() {
+            return Err(Error::Validation(describe_rejected(&rejected)));
         }
+        Ok(namespace)
     }
 
-    pub async fn handle_client(self: &Self, mut stream: TcpStream) -> std::io::Result<()> {
-        info!(
-            "Handling client connection on {}:{}",
-            stream.peer_addr().unwrap().ip(),
-            stream.peer_addr().unwrap().port()
-        );
+    // Persists every namespace along with when it was last synced. Namespaces
+    // still served from an older snapshot keep their original sync time so
+    // they do not look fresher than they are.
+    pub fn write_snapshot(&self) -> Result<(), String> {
+        let Some(snapshot) = &self.snapshot else {
+            return Ok(());
+        };
+
+        let cached = self
+            .cached_namespaces
+            .lock()
+            .map_err(|e| format!("Error locking snapshot state: {}", e))?
+            .clone();
+
+        let store = self
+            .store
+            .read()
+            .map_err(|e| format!("Error locking store: {}", e))?;
+        let now = unix_now();
+        let namespaces = store
+            .namespaces()
+            .map(|namespace| {
+                let entry = SnapshotEntry {
+                    synced_at: cached.get(namespace).copied().unwrap_or(now),
+                    secrets: store.get_namespace(namespace),
+                };
+                (namespace.clone(), entry)
+            })
+            .collect();
+        drop(store);
+
+        snapshot.write(namespaces)
+    }
 
-        let dh_exchange = DHKeyExchange::new();
-        let server_public_key = dh_exchange.get_public_key().to_bytes_be();
+    // Falls back to the snapshot for a project the backend could not serve.
+    // Returns when the cached data was last synced.
+    pub fn restore_project(&self, namespace: &Namespace, token: String) -> Result<u64, String> {
+        let config = self.snapshot.as_ref().ok_or("No snapshot configured")?;
+        let snapshot = config.load()?;
 
-        // Send server's public key
-        stream.write_all(&(server_public_key.len() as u32).to_be_bytes())?;
-        stream.write_all(&server_public_key)?;
+        let entry = snapshot
+            .namespaces
+            .get(namespace)
+            .cloned()
+            .ok_or_else(|| format!("Snapshot has no data for {}", namespace))?;
+        config.check_age(entry.synced_at)?;
 
-        // Read client's public key
-        let mut client_key_length = [0u8; 4];
-        stream.read_exact(&mut client_key_length)?;
-        let client_key_length = u32::from_be_bytes(client_key_length) as usize;
+        self.store
+            .write()
+            .map_err(|e| format!("Error locking store: {}", e))?
+            .merge_namespace(namespace, entry.secrets, SecretOrigin::Snapshot)?;
 
-        let mut client_public_key_bytes = vec![0u8; client_key_length];
-        stream.read_exact(&mut client_public_key_bytes)?;
+        self.policy
+            .lock()
+            .map_err(|e| format!("Error locking policy: {}", e))?
+            .register(namespace.clone(), token);
 
-        let client_public_key = BigUint::from_bytes_be(&client_public_key_bytes);
+        self.cached_namespaces
+            .lock()
+            .map_err(|e| format!("Error locking snapshot state: {}", e))?
+            .insert(namespace.clone(), entry.synced_at);
 
-        // Compute shared secret
-        let shared_secret = dh_exchange.compute_shared_secret(&client_public_key);
+        Ok(entry.synced_at)
+    }
 
-        let mut buffer = [0; 1024];
-        let n = match stream.read(&mut buffer) {
-            Ok(n) if n > 0 => n,
+    pub async fn handle_client(
+        &self,
+        mut stream: TcpStream,
+        caller: Principal,
+    ) -> std::io::Result<()> {
+        let peer = stream.peer_addr()?;
+        info!(
+            "Handling client connection on {}:{}",
+            peer.ip(),
+            peer.port()
+        );
+
+        let session = match handshake(&mut stream) {
+            Ok(session) => session,
+            Err(e) => {
+                self.metrics.record_handshake_failure();
+                self.record_failure(&caller);
+                return Err(e);
+            }
+        };
+
+        self.serve_request(stream, caller, Some(session)).await
+    }
+
+    // Reads one request from an established connection and answers it.
+    // Responses are sealed under the key exchange's shared secret; TLS
+    // connections have no `session`, since TLS already protects them.
+    pub(crate) async fn serve_request<C: Connection>(
+        &self,
+        mut stream: C,
+        caller: Principal,
+        session: Option<Session>,
+    ) -> std::io::Result<()> {
+        let shared_secret = session
+            .as_ref()
+            .map(|session| session.shared_secret.as_slice());
+
+        let buffer = match read_request(&mut stream) {
+            Ok(buffer) if !buffer.is_empty() => buffer,
             Ok(_) => {
                 error!("Received empty data from client");
                 return Ok(()); // Exit gracefully
@@ -116,211 +449,1157 @@ impl SecretsServer {
                 return Err(e);
             }
         };
-        match serde_json::from_slice::<Vec<String>>(&buffer[..n]) {
-            Ok(commands) if !commands.is_empty() && commands[0] == "get_env" => {
-                info!("GET_ENV");
-                let keys = &commands[1..];
 
-                let mut response = HashMap::new();
+        let request = match Request::parse(&buffer) {
+            Ok(request) => request,
+            Err(e) => return send_error(&mut stream, shared_secret, &Error::Protocol(e)),
+        };
 
-                let store = self.store.lock();
-                match store {
-                    Ok(store) => {
-                        for key in keys {
-                            if let Some(secret) = Some(ProtectedSecret::new(store.get_secret(key)))
-                            {
-                                response.insert(key.clone(), secret);
-                            }
-                        }
-                    }
-                    Err(e) => error!("Error locking store: {}", e),
-                }
+        let transcript = session
+            .as_ref()
+            .map(|session| session.transcript.as_slice());
+        let caller = match self.identify(&request, &buffer, caller, transcript) {
+            Ok(caller) => caller,
+            Err(e) => return send_error(&mut stream, shared_secret, &e),
+        };
+
+        // Answered to anyone, but with detail only for authorized callers
+        if let Command::Status = request.command {
+            if let Err(e) = self.admit(&caller, Limit::Command) {
+                return send_error(&mut stream, shared_secret, &e);
+            }
+            let scope = self.status_scope(
+                request.namespace.as_deref(),
+                request.environment.as_deref(),
+                request.token.as_deref(),
+                &caller,
+            );
+            let status = self.status(&scope);
+            self.metrics
+                .record_command("status", if status.is_ok() { "ok" } else { "error" });
+            return match status {
+                Ok(status) => send_response(&mut stream, shared_secret, &status),
+                Err(e) => send_error(&mut stream, shared_secret, &Error::Store(e)),
+            };
+        }
+
+        if let Command::Admin {
+            admin_token,
+            action,
+        } = request.command
+        {
+            let result = self
+                .administer(action, admin_token.as_deref(), &caller)
+                .await;
+            return send_result(&mut stream, shared_secret, result);
+        }
+
+        let namespace = match self.authorize(&request, &caller) {
+            Ok(namespace) => namespace,
+            Err(e) => return send_error(&mut stream, shared_secret, &e),
+        };
 
-                info!("response: {:?}", response);
-
-                let response_json = serde_json::to_string(&response)?;
-
-                let encrypted_response =
-                    DHKeyExchange::encrypt(&shared_secret, response_json.as_bytes());
-
-                //     match response_json {
-                //         Ok(response_json) => {
-                //             let mut response_buffer = Vec::new();
-                //             response_buffer.write_u32::<NetworkEndian>(response_json.len() as u32)?;
-                //             response_buffer.extend_from_slice(response_json.as_bytes());
-
-                //             let result = stream.write_all(&response_buffer);
-                //             match result {
-                //                 Ok(_) => {
-                //                     info!("Response sent successfully");
-                //                     stream.flush()?;
-                //                     stream.shutdown(std::net::Shutdown::Write)?;
-                //                 }
-                //                 Err(e) => {
-                //                     error!("Error sending response: {}", e);
-                //                     stream.flush()?;
-                //                     stream.shutdown(std::net::Shutdown::Write)?;
-                //                 }
-                //             }
-                //         }
-                //         Err(e) => {
-                //             error!("Error serializing response: {}", e);
-                //             stream.flush()?;
-                //             stream.shutdown(std::net::Shutdown::Write)?;
-                //         }
-                //     }
-
-                let mut response_buffer = Vec::new();
-                response_buffer.write_u32::<NetworkEndian>(encrypted_response.len() as u32)?;
-                response_buffer.extend_from_slice(&encrypted_response);
-
-                let result = stream.write_all(&response_buffer);
-                match result {
-                    Ok(_) => {
-                        info!("Encrypted response sent successfully");
-                        stream.flush()?;
-                        stream.shutdown(std::net::Shutdown::Write)?;
+        match request.command {
+            Command::Watch { subscription } => {
+                info!("WATCH {}", namespace);
+                audit::record(&caller, Some(&namespace), "watch", Ok(()));
+                self.metrics.record_command("watch", "ok");
+
+                let (events, current) = match self.store.write() {
+                    Ok(mut store) => {
+                        let current: Vec<ChangeEvent> = store
+                            .list_keys(&namespace, &KeyFilter::default())
+                            .into_iter()
+                            .filter(|info| subscription.matches(&info.key))
+                            .map(|info| {
+                                ChangeEvent::changed(&namespace, &info.key, info.metadata.version)
+                            })
+                            .collect();
+                        (store.subscribe(), current)
                     }
                     Err(e) => {
-                        error!("Error sending response: {}", e);
-                        stream.flush()?;
-                        stream.shutdown(std::net::Shutdown::Write)?;
+                        let e = Error::Store(format!("Error locking store: {}", e));
+                        return send_error(&mut stream, shared_secret, &e);
                     }
+                };
+
+                let cipher = match shared_secret.map(SessionCipher::new).transpose() {
+                    Ok(cipher) => cipher,
+                    Err(e) => return send_error(&mut stream, shared_secret, &e),
+                };
+                // Templates change with the keys they reference
+                let dependents =
+                    |event: &ChangeEvent| match (self.store.read(), self.secret_types.read()) {
+                        (Ok(store), Ok(types)) => {
+                            reference::dependents(&store, &types, &namespace, event)
+                        }
+                        _ => Vec::new(),
+                    };
+                // Handlers run on the blocking pool, so the watch streams from
+                // this one and shutdown drains it like any other connection
+                let write_timeout = std::time::Duration::from_secs(WATCH_HEARTBEAT_SECS);
+                let result = stream.hold_open(write_timeout).and_then(|()| {
+                    stream_changes(
+                        stream,
+                        cipher,
+                        &namespace,
+                        &subscription,
+                        current,
+                        events,
+                        dependents,
+                    )
+                });
+                if let Err(e) = result {
+                    info!("Watcher for {} disconnected: {}", namespace, e);
                 }
             }
 
-            Ok(commands) if !commands.is_empty() && commands[0].as_str() == "store_env" => {
-                info!("STORE_ENV");
-                if let Some(data) = commands.get(1) {
-                    let secrets: HashMap<String, String> = match Some(data.as_str()) {
-                        Some(s) => serde_json::from_str(s).map_err(|_| {
-                            std::io::Error::new(
-                                std::io::ErrorKind::InvalidData,
-                                "Invalid secrets data",
-                            )
-                        })?,
-                        None => {
-                            return Err(std::io::Error::new(
-                                std::io::ErrorKind::InvalidData,
-                                "Invalid secrets data",
-                            ))
-                        }
+            command => {
+                let result = self.execute(&namespace, command, &caller).await;
+                send_result(&mut stream, shared_secret, result)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Resolves the namespace a request targets and checks the caller may use
+    // it. Refusals are audited here; everything else is audited by `execute`.
+    pub fn authorize(&self, request: &Request, caller: &Principal) -> Result<Namespace, Error> {
+        let result = self.admit(caller, Limit::Command).and_then(|()| {
+            self.policy
+                .lock()
+                .map_err(|e| Error::Store(format!("Error locking policy: {}", e)))?
+                .authorize(
+                    request.namespace.as_deref(),
+                    request.environment.as_deref(),
+                    request.token.as_deref(),
+                    request.legacy,
+                    caller,
+                )
+                .map_err(Error::Policy)
+        });
+
+        if let Err(e) = &result {
+            if let Error::Policy(_) = e {
+                self.record_failure(caller);
+            }
+            audit::record(caller, None, request.command.name(), Err(e));
+            self.metrics.record_command(
+                request.command.name(),
+                if e.is_refusal() { "refused" } else { "error" },
+            );
+        }
+        result
+    }
+
+    // Runs a request/response command for a caller already authorized for
+    // `namespace`. The TCP protocol and the HTTP API both go through here.
+    // `None` means there is nothing to return beyond an acknowledgement.
+    pub async fn execute(
+        &self,
+        namespace: &Namespace,
+        command: Command,
+        caller: &Principal,
+    ) -> Result<Option<Value>, Error> {
+        let action = command.name();
+        info!("{} {}", action.to_uppercase(), namespace);
+
+        let started = Instant::now();
+        let result = self.run_command(namespace, command, caller).await;
+        self.metrics.observe_command(action, started.elapsed());
+        let outcome = match &result {
+            Ok(_) => "ok",
+            Err(e) if e.is_refusal() => "refused",
+            Err(_) => "error",
+        };
+        self.metrics.record_command(action, outcome);
+        audit::record(caller, Some(namespace), action, result.as_ref().map(|_| ()));
+        result
+    }
+
+    async fn run_command(
+        &self,
+        namespace: &Namespace,
+        command: Command,
+        caller: &Principal,
+    ) -> Result<Option<Value>, Error> {
+        match command {
+            Command::GetEnv { keys, versions } => {
+                let store = self
+                    .store
+                    .read()
+                    .map_err(|e| Error::Store(format!("Error locking store: {}", e)))?;
+                let types = self
+                    .secret_types
+                    .read()
+                    .map_err(|e| Error::Store(format!("Error locking secret types: {}", e)))?;
+                let allow =
+                    |target: &Namespace| self.authorize_reference(namespace, target, caller);
+                let mut resolver = Resolver::new(&store, &types, &allow);
+
+                let mut response = HashMap::new();
+                for key in keys {
+                    let found = match versions.get(&key) {
+                        Some(version) => store.get_secret_version(namespace, &key, *version),
+                        None => store
+                            .get_secret(namespace, &key)
+                            .zip(store.get_metadata(namespace, &key)),
                     };
-                    let store = self.store.lock();
-                    match store {
-                        Ok(mut store) => {
-                            for (key, value) in secrets {
-                                store
-                                    .store_secret(key, value)
-                                    .expect("Failed to store secret");
-                            }
-                        }
-                        Err(e) => error!("Error locking store: {}", e),
+                    // The expiry of the version returned, not of the live one
+                    let expires_at = found.as_ref().and_then(|(_, metadata)| metadata.expires_at);
+                    let value = found
+                        .map(|(value, _)| resolver.resolve(namespace, &key, &value))
+                        .transpose()?;
+                    response.insert(key, ProtectedSecret::new(value).with_expiry(expires_at));
+                }
+
+                to_response(&response)
+            }
+
+            Command::StoreEnv {
+                secrets,
+                ttl,
+                types,
+            } => {
+                let keys: Vec<String> = secrets.keys().cloned().collect();
+                self.write_checked(namespace, secrets, types, |store, secrets| {
+                    for (key, value) in secrets {
+                        store
+                            .store_secret(namespace, key, value, SecretOrigin::StoreEnv, ttl)
+                            .map_err(Error::Store)?;
                     }
+                    Ok(())
+                })?;
 
-                    stream.write_all(&[0, 0, 0, 0])?;
-                    stream.flush()?;
-                    stream.shutdown(std::net::Shutdown::Write)?;
-                } else {
-                    stream.write_all(&[0, 0, 0, 0])?; // Empty response
-                    stream.flush()?;
-                    stream.shutdown(std::net::Shutdown::Write)?;
+                self.write_back_changes(namespace, keys).await;
+
+                Ok(None)
+            }
+
+            Command::DeleteEnv { keys } => {
+                let response: HashMap<String, bool> = {
+                    let mut store = self
+                        .store
+                        .write()
+                        .map_err(|e| Error::Store(format!("Error locking store: {}", e)))?;
+                    keys.into_iter()
+                        .map(|key| {
+                            let deleted = store.delete_secret(namespace, &key);
+                            (key, deleted)
+                        })
+                        .collect()
+                };
+
+                let deleted = response
+                    .iter()
+                    .filter(|(_, deleted)| **deleted)
+                    .map(|(key, _)| key.clone())
+                    .collect();
+                self.write_back_changes(namespace, deleted).await;
+
+                to_response(&response)
+            }
+
+            Command::ListKeys { filter } => {
+                let mut keys = self
+                    .store
+                    .read()
+                    .map_err(|e| Error::Store(format!("Error locking store: {}", e)))?
+                    .list_keys(namespace, &filter);
+
+                let types = self
+                    .secret_types
+                    .read()
+                    .map_err(|e| Error::Store(format!("Error locking secret types: {}", e)))?;
+                for info in &mut keys {
+                    info.value_type = types.get(namespace, &info.key);
                 }
+
+                to_response(&keys)
             }
-            _ => {
-                stream.write_all(&[0, 0, 0, 0])?; // Empty response
-                stream.flush()?;
-                error!("Invalid command");
+
+            Command::Exists { keys } => {
+                let store = self
+                    .store
+                    .read()
+                    .map_err(|e| Error::Store(format!("Error locking store: {}", e)))?;
+
+                let response: HashMap<String, bool> = keys
+                    .into_iter()
+                    .map(|key| {
+                        let exists = store.contains(namespace, &key);
+                        (key, exists)
+                    })
+                    .collect();
+
+                to_response(&response)
             }
-        }
 
-        Ok(())
+            Command::Import {
+                format,
+                content,
+                ttl,
+            } => {
+                let secrets = import(format, &content).map_err(Error::Protocol)?;
+                let imported =
+                    self.write_checked(namespace, secrets, HashMap::new(), |store, secrets| {
+                        let mut imported = Vec::new();
+                        for (key, value) in secrets {
+                            store
+                                .store_secret(
+                                    namespace,
+                                    key.clone(),
+                                    value,
+                                    SecretOrigin::Import,
+                                    ttl,
+                                )
+                                .map_err(Error::Store)?;
+                            imported.push(key);
+                        }
+                        imported.sort();
+                        Ok(imported)
+                    })?;
+
+                self.write_back_changes(namespace, imported.clone()).await;
+
+                to_response(&imported)
+            }
+
+            Command::Export { format, filter } => {
+                self.policy
+                    .lock()
+                    .map_err(|e| Error::Policy(format!("Error locking policy: {}", e)))?
+                    .authorize_export(namespace, caller)
+                    .map_err(Error::Policy)?;
+
+                let store = self
+                    .store
+                    .read()
+                    .map_err(|e| Error::Store(format!("Error locking store: {}", e)))?;
+                let types = self
+                    .secret_types
+                    .read()
+                    .map_err(|e| Error::Store(format!("Error locking secret types: {}", e)))?;
+                let allow =
+                    |target: &Namespace| self.authorize_reference(namespace, target, caller);
+                let mut resolver = Resolver::new(&store, &types, &allow);
+
+                let secrets: BTreeMap<String, String> = store
+                    .get_namespace(namespace)
+                    .into_iter()
+                    .filter(|(key, _)| filter.matches(key))
+                    .map(|(key, value)| {
+                        let value = resolver.resolve(namespace, &key, &value)?;
+                        Ok((key, value))
+                    })
+                    .collect::<Result<_, Error>>()?;
+
+                to_response(&export(format, &secrets).map_err(Error::Store)?)
+            }
+
+            Command::SyncStatus { keys } => {
+                let queue = self
+                    .write_queue
+                    .lock()
+                    .map_err(|e| Error::Store(format!("Error locking write queue: {}", e)))?;
+
+                let keys = if keys.is_empty() {
+                    queue.keys(namespace)
+                } else {
+                    keys
+                };
+                let response: HashMap<String, _> = keys
+                    .into_iter()
+                    .map(|key| {
+                        let status = queue.status(namespace, &key);
+                        (key, status)
+                    })
+                    .collect();
+
+                to_response(&response)
+            }
+
+            Command::History { key } => {
+                let history = self
+                    .store
+                    .read()
+                    .map_err(|e| Error::Store(format!("Error locking store: {}", e)))?
+                    .get_history(namespace, &key);
+
+                to_response(&history)
+            }
+
+            Command::Rollback { key, version } => {
+                let metadata = self
+                    .store
+                    .write()
+                    .map_err(|e| Error::Store(format!("Error locking store: {}", e)))?
+                    .rollback(namespace, &key, version)
+                    // Almost always a key or version the caller got wrong
+                    .map_err(Error::Protocol)?;
+
+                // The restored value is a local change like any other, so the
+                // next sync does not undo it
+                self.write_back_changes(namespace, vec![key]).await;
+
+                to_response(&metadata)
+            }
+
+            Command::Generate {
+                key,
+                generator,
+                ttl,
+                replace,
+                write_back,
+            } => {
+                self.generate_secret(namespace, key, generator, ttl, replace, write_back)
+                    .await
+            }
+
+            Command::Status => to_response(
+                &self
+                    .status(&StatusScope::Namespace(namespace.clone()))
+                    .map_err(Error::Store)?,
+            ),
+
+            Command::Watch { .. } => Err(Error::Protocol(
+                "watch needs a streaming connection".to_string(),
+            )),
+            Command::Admin { .. } => Err(Error::Protocol(
+                "admin commands do not run against a namespace".to_string(),
+            )),
+        }
     }
 
+    // Returns every secret in the project's namespace, provided the token
+    // matches the one the namespace was registered with.
     pub fn get_keys(
         &self,
         project_name: String,
+        environment: Option<String>,
         token: String,
-    ) -> Result<HashMap<String, String>, String> {
-        let mut keys = HashMap::new();
-
-        let store = self.store.lock();
-        match store {
-            Ok(store) => {
-                let key = format!("{}_{}", project_name, token);
-                if let Some(secret) = store.get_secret(&key) {
-                    keys.insert("keys".to_string(), secret);
-                }
-            }
-            Err(e) => return Err(format!("Error locking store: {}", e)),
-        }
+        caller: &Principal,
+    ) -> Result<HashMap<String, String>, Error> {
+        let namespace = self
+            .policy
+            .lock()
+            .map_err(|e| Error::Policy(format!("Error locking policy: {}", e)))?
+            .authorize(
+                Some(&project_name),
+                environment.as_deref(),
+                Some(&token),
+                false,
+                caller,
+            )
+            .map_err(Error::Policy)?;
+
+        let store = self
+            .store
+            .read()
+            .map_err(|e| Error::Store(format!("Error locking store: {}", e)))?;
+        let types = self
+            .secret_types
+            .read()
+            .map_err(|e| Error::Store(format!("Error locking secret types: {}", e)))?;
+        let allow = |target: &Namespace| self.authorize_reference(&namespace, target, caller);
+        let mut resolver = Resolver::new(&store, &types, &allow);
 
-        Ok(keys)
+        store
+            .get_namespace(&namespace)
+            .into_iter()
+            .map(|(key, value)| {
+                let value = resolver.resolve(&namespace, &key, &value)?;
+                Ok((key, value))
+            })
+            .collect()
     }
 
-    pub async fn run(self, input: GetKeysInput) -> std::io::Result<()> {
+    pub async fn run(mut self, projects: Vec<GetKeysInput>) -> std::io::Result<()> {
         env_logger::init();
 
+        // Registered first so a signal during the initial sync is not lost
+        let mut terminate = signal(SignalKind::terminate())?;
+        let mut interrupt = signal(SignalKind::interrupt())?;
+        let mut hangup = signal(SignalKind::hangup())?;
+
         let listener = TcpListener::bind("127.0.0.1:6000")?;
         info!("Server started successfully on port 6000");
 
+        self.projects = projects.clone();
         let server = Arc::new(self);
+        server.add_listener(format!("tcp://{}", listener.local_addr()?));
 
-        match server.build_project(input).await {
-            Ok(project) => {
-                info!("Pull keys from server");
-
-                // Extract and store the secrets
-                if let Some(keys) = project.get("keys").and_then(|keys| keys.as_object()) {
-                    let store = server.store.lock();
-                    if let Ok(mut store) = store {
-                        for (key, value) in keys {
-                            if let Some(value_str) = value.as_str() {
-                                store
-                                    .store_secret(key.clone(), value_str.to_string())
-                                    .unwrap_or_else(|e| {
-                                        error!("Failed to store key '{}': {}", key, e);
-                                    });
-                            }
+        let reaper_store = Arc::clone(&server.store);
+        let reaper_limiter = Arc::clone(&server.rate_limiter);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(std::time::Duration::from_secs(REAP_INTERVAL_SECS));
+            reaper_limiter.prune();
+            match reaper_store.write() {
+                Ok(mut store) => {
+                    let purged = store.purge_expired();
+                    if purged > 0 {
+                        info!("Purged {} expired secrets", purged);
+                    }
+                }
+                Err(e) => error!("Error locking store: {}", e),
+            }
+        });
+
+        for input in projects.iter().cloned() {
+            let namespace = input.namespace();
+            let token = input.token.clone();
+
+            match server.sync_project(input).await {
+                Ok(namespace) => info!("Pull keys from server for {}", namespace),
+                // The valid keys were loaded; the rejected ones stay missing
+                Err(e @ Error::Validation(_)) => {
+                    error!("Loaded {} without some keys: {}", namespace, e)
+                }
+                Err(e) if server.snapshot.is_some() => {
+                    error!("Failed to build project: {}", e);
+                    match server.restore_project(&namespace, token) {
+                        Ok(created_at) => info!(
+                            "Serving cached data for {} from {}",
+                            namespace,
+                            format_timestamp(created_at)
+                        ),
+                        Err(e) => {
+                            error!("Failed to load snapshot: {}", e);
+                            return Err(std::io::Error::other(e));
                         }
-                    } else {
-                        error!("Failed to lock store for storing secrets");
                     }
-                } else {
-                    error!("No valid keys found in the project response");
+                }
+                Err(e) => {
+                    error!("Failed to build project: {}", e);
+                    return Err(std::io::Error::other(e.to_string()));
                 }
             }
-            Err(e) => {
-                error!("Failed to build project: {}", e);
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    e.to_string(),
-                ));
+        }
+
+        match server.restore_write_queue() {
+            Ok((restored, errors)) => {
+                if restored > 0 {
+                    info!("Restored {} queued writes", restored);
+                }
+                for e in errors {
+                    error!("{}", e);
+                }
             }
+            Err(e) => error!("Failed to restore write queue: {}", e),
+        }
+
+        server.ready.store(true, Ordering::SeqCst);
+        server.spawn_template_renderer();
+        server.spawn_key_rotation();
+
+        // Everything that must be finished before shutdown wipes the store.
+        // Each task stops once `stop` carries the shutdown deadline.
+        let (stop, stop_signal) = tokio::sync::watch::channel(None);
+        let mut tasks = JoinSet::new();
+
+        if let Some(addr) = server.metrics_addr {
+            let metrics = Arc::clone(&server);
+            let stop = stop_signal.clone();
+            tasks.spawn(async move {
+                if let Err(e) = metrics.serve_metrics(addr, stop).await {
+                    error!("Metrics endpoint failed on {}: {}", addr, e);
+                }
+            });
+        }
+
+        if let Some(path) = server.http_socket.clone() {
+            let http = Arc::clone(&server);
+            let stop = stop_signal.clone();
+            tasks.spawn(async move {
+                if let Err(e) = http.serve_http(&path, stop).await {
+                    error!("HTTP API failed on {}: {}", path.display(), e);
+                }
+            });
         }
 
-        for stream in listener.incoming() {
-            info!("Server listening on port 127.0.0.1:6000");
-            match stream {
-                Ok(stream) => {
+        if let Some(config) = server.tls.clone() {
+            let tls = Arc::clone(&server);
+            let stop = stop_signal.clone();
+            tasks.spawn(async move {
+                let addr = config.addr;
+                if let Err(e) = tls.serve_tls(config, stop).await {
+                    error!("TLS listener failed on {}: {}", addr, e);
+                }
+            });
+        }
+
+        if let Some(config) = &server.write_back {
+            let flusher = Arc::clone(&server);
+            let interval = std::time::Duration::from_secs(config.flush_interval);
+            let mut stop = stop_signal.clone();
+            tasks.spawn(async move {
+                loop {
+                    tokio::select! {
+                        _ = tokio::time::sleep(interval) => {}
+                        _ = stopped(&mut stop) => return,
+                    }
+                    // Shutdown flushes one last time itself
+                    tokio::select! {
+                        result = flusher.flush_writes() => {
+                            if let Err(e) = result {
+                                error!("Failed to write back: {}", e);
+                            }
+                        }
+                        _ = stopped(&mut stop) => return,
+                    }
+                }
+            });
+        }
+
+        listener.set_nonblocking(true)?;
+        let listener = tokio::net::TcpListener::from_std(listener)?;
+        let mut connections = Inflight::default();
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => {
+                    let (stream, peer) = match accepted {
+                        Ok(connection) => connection,
+                        Err(e) => {
+                            error!("Connection failed: {}", e);
+                            continue;
+                        }
+                    };
+
+                    if connections.len() >= server.max_connections {
+                        info!(
+                            "Dropped connection from {}: {} connections already open",
+                            peer.ip(),
+                            connections.len()
+                        );
+                        continue;
+                    }
+
+                    // Turned away before the key exchange, which is the
+                    // expensive part of a connection
+                    let caller = match stream.local_addr() {
+                        Ok(local) => Principal::tcp_peer(peer, local),
+                        Err(_) => Principal::Peer(peer.ip()),
+                    };
+                    if let Err(e) = server.admit(&caller, Limit::Connection) {
+                        info!("Dropped connection: {}", e);
+                        continue;
+                    }
+
+                    let stream = match stream.into_std().and_then(|stream| {
+                        // Requests are served with blocking reads and writes
+                        stream.set_nonblocking(false)?;
+                        set_timeouts(&stream, server.connection_timeout_secs)?;
+                        Ok(stream)
+                    }) {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            error!("Connection failed: {}", e);
+                            continue;
+                        }
+                    };
+
+                    // Handlers block on the socket, so they run on the blocking
+                    // pool and leave the workers free for signals and timers
                     let server_clone = Arc::clone(&server);
-                    tokio::spawn(async move {
-                        if let Err(e) = server_clone.handle_client(stream).await {
+                    let runtime = tokio::runtime::Handle::current();
+                    connections.spawn_blocking(stream, move |stream| {
+                        if let Err(e) = runtime.block_on(server_clone.handle_client(stream, caller)) {
                             error!("Error handling client: {}", e);
                         }
                     });
                 }
-                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                    // This error is expected in non-blocking mode
-                    std::thread::sleep(std::time::Duration::from_millis(100));
-                    continue;
-                }
-                Err(e) => {
-                    error!("Connection failed: {}", e);
+                Some(_) = connections.join_next(), if !connections.is_empty() => {}
+                Some(_) = tasks.join_next(), if !tasks.is_empty() => {}
+                _ = hangup.recv() => {
+                    let reloader = Arc::clone(&server);
+                    tasks.spawn(async move {
+                        reloader.reload().await;
+                    });
                 }
+                _ = terminate.recv() => break,
+                _ = interrupt.recv() => break,
             }
         }
 
+        drop(listener);
+        server.shutdown(stop, connections, tasks).await;
+
         Ok(())
     }
 }
+
+// Every value in `namespace` with its references expanded. Templates have
+// no caller to check a policy for, so they may only reference their own
+// namespace.
+fn resolve_own(
+    store: &SecureStore,
+    types: &SecretTypes,
+    namespace: &Namespace,
+) -> Result<HashMap<String, String>, Error> {
+    let allow = |target: &Namespace| {
+        if target == namespace {
+            Ok(())
+        } else {
+            Err(Error::Policy(format!(
+                "Templates for '{}' cannot reference '{}'",
+                namespace, target
+            )))
+        }
+    };
+    let mut resolver = Resolver::new(store, types, &allow);
+
+    store
+        .get_namespace(namespace)
+        .into_iter()
+        .map(|(key, value)| {
+            let value = resolver.resolve(namespace, &key, &value)?;
+            Ok((key, value))
+        })
+        .collect()
+}
+
+// Largest request body accepted from a client
+pub(crate) const MAX_REQUEST_SIZE: usize = 1024 * 1024;
+// Far larger than any public key for the group the daemon uses
+const MAX_PUBLIC_KEY_SIZE: usize = 4096;
+
+// A stream requests are served over: a TCP connection after the key
+// exchange, or a TLS session
+pub(crate) trait Connection: Read + Write + Send + 'static {
+    // Ends the response; clients read until the write half closes
+    fn close_write(&mut self) -> std::io::Result<()>;
+
+    // Readies the connection for a watch, which never reads again and is
+    // quiet between heartbeats: no read timeout, and `write_timeout` for
+    // every frame, so a watcher that stops reading is dropped instead of
+    // holding its handler
+    fn hold_open(&mut self, write_timeout: std::time::Duration) -> std::io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn close_write(&mut self) -> std::io::Result<()> {
+        self.shutdown(std::net::Shutdown::Write)
+    }
+
+    fn hold_open(&mut self, write_timeout: std::time::Duration) -> std::io::Result<()> {
+        self.set_read_timeout(None)?;
+        self.set_write_timeout(Some(write_timeout))
+    }
+}
+
+// Reads one JSON request. Clients do not frame requests, so keep reading
+// until the bytes form a complete JSON value, the value turns out to be
+// malformed, the client stops sending or the size limit is reached. The
+// bytes are parsed once as they arrive rather than again after every read.
+fn read_request(stream: &mut impl Read) -> std::io::Result<Vec<u8>> {
+    let mut recorder = Recorder {
+        inner: std::io::BufReader::new(stream.take(MAX_REQUEST_SIZE as u64)),
+        recorded: Vec::new(),
+        error: None,
+    };
+
+    let mut deserializer = serde_json::Deserializer::from_reader(&mut recorder);
+    let _ = serde::Deserialize::deserialize(&mut deserializer).map(|_: serde::de::IgnoredAny| ());
+
+    match recorder.error {
+        Some(e) => Err(e),
+        None => Ok(recorder.recorded),
+    }
+}
+
+// Keeps every byte read through it, and the read error serde_json would
+// otherwise turn into a parse error
+struct Recorder<R> {
+    inner: R,
+    recorded: Vec<u8>,
+    error: Option<std::io::Error>,
+}
+
+impl<R: Read> Read for Recorder<R> {
+    fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+        match self.inner.read(buffer) {
+            Ok(n) => {
+                self.recorded.extend_from_slice(&buffer[..n]);
+                Ok(n)
+            }
+            Err(e) => {
+                let kind = e.kind();
+                self.error = Some(e);
+                Err(kind.into())
+            }
+        }
+    }
+}
+
+// What the key exchange established: the key replies are sealed under, and
+// the transcript client identities sign
+pub(crate) struct Session {
+    shared_secret: Vec<u8>,
+    transcript: Vec<u8>,
+}
+
+// Runs the server side of the key exchange
+fn handshake(stream: &mut (impl Read + Write)) -> std::io::Result<Session> {
+    let dh_exchange = DHKeyExchange::new();
+    let server_public_key = dh_exchange.get_public_key().to_bytes_be();
+
+    // Send server's public key
+    stream.write_all(&(server_public_key.len() as u32).to_be_bytes())?;
+    stream.write_all(&server_public_key)?;
+
+    // Read client's public key
+    let mut client_key_length = [0u8; 4];
+    stream.read_exact(&mut client_key_length)?;
+    let client_key_length = u32::from_be_bytes(client_key_length) as usize;
+    if client_key_length > MAX_PUBLIC_KEY_SIZE {
+        return Err(std::io::Error::other(format!(
+            "Client public key of {} bytes is too large",
+            client_key_length
+        )));
+    }
+
+    let mut client_public_key_bytes = vec![0u8; client_key_length];
+    stream.read_exact(&mut client_public_key_bytes)?;
+
+    let client_public_key = BigUint::from_bytes_be(&client_public_key_bytes);
+    dh_exchange
+        .check_public_key(&client_public_key)
+        .map_err(std::io::Error::other)?;
+
+    Ok(Session {
+        shared_secret: dh_exchange.compute_shared_secret(&client_public_key),
+        transcript: identity::transcript(&server_public_key, &client_public_key_bytes),
+    })
+}
+
+// Pushes the current versions of the subscribed keys, then every later change
+// to them, each in its own frame, sealed unless TLS carries the stream
+fn stream_changes(
+    mut stream: impl Connection,
+    mut cipher: Option<SessionCipher>,
+    namespace: &Namespace,
+    subscription: &Subscription,
+    current: Vec<ChangeEvent>,
+    events: Receiver<ChangeEvent>,
+    dependents: impl Fn(&ChangeEvent) -> Vec<ChangeEvent>,
+) -> std::io::Result<()> {
+    for event in current {
+        send_frame(&mut stream, cipher.as_mut(), &event)?;
+    }
+
+    loop {
+        match events.recv_timeout(std::time::Duration::from_secs(WATCH_HEARTBEAT_SECS)) {
+            Ok(event) => {
+                if &event.namespace == namespace && subscription.matches(&event.key) {
+                    send_frame(&mut stream, cipher.as_mut(), &event)?;
+                }
+                for dependent in dependents(&event) {
+                    if subscription.matches(&dependent.key) {
+                        send_frame(&mut stream, cipher.as_mut(), &dependent)?;
+                    }
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                stream.write_all(&[0, 0, 0, 0])?;
+                stream.flush()?;
+            }
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+    }
+}
+
+fn send_frame<T: Serialize>(
+    stream: &mut impl Write,
+    cipher: Option<&mut SessionCipher>,
+    payload: &T,
+) -> std::io::Result<()> {
+    let mut frame = serde_json::to_vec(payload)?;
+    if let Some(cipher) = cipher {
+        frame = cipher.seal(&frame).map_err(std::io::Error::other)?;
+    }
+
+    stream.write_all(&(frame.len() as u32).to_be_bytes())?;
+    stream.write_all(&frame)?;
+    stream.flush()
+}
+
+pub(crate) fn to_response<T: Serialize>(response: &T) -> Result<Option<Value>, Error> {
+    serde_json::to_value(response)
+        .map(Some)
+        .map_err(|e| Error::Store(e.to_string()))
+}
+
+// Answers a command: its response, a bare empty frame as the
+// acknowledgement when there is nothing to return, or the error
+fn send_result(
+    stream: &mut impl Connection,
+    shared_secret: Option<&[u8]>,
+    result: Result<Option<Value>, Error>,
+) -> std::io::Result<()> {
+    match result {
+        Ok(Some(response)) => send_response(stream, shared_secret, &response),
+        Ok(None) => {
+            stream.write_all(&[0, 0, 0, 0])?;
+            stream.flush()?;
+            stream.close_write()
+        }
+        Err(e) => send_error(stream, shared_secret, &e),
+    }
+}
+
+// Failures keep the empty frame older clients read as "no response", then
+// add an `ErrorResponse` that says what went wrong
+fn send_error(
+    stream: &mut impl Connection,
+    shared_secret: Option<&[u8]>,
+    e: &Error,
+) -> std::io::Result<()> {
+    error!("{}", e);
+    stream.write_all(&[0, 0, 0, 0])?;
+    send_response(stream, shared_secret, &e.to_response())
+}
+
+// Serializes `payload`, seals it under the shared secret if there is one and
+// writes it as a length-prefixed frame before closing the write half
+fn send_response<T: Serialize>(
+    stream: &mut impl Connection,
+    shared_secret: Option<&[u8]>,
+    payload: &T,
+) -> std::io::Result<()> {
+    let mut response = serde_json::to_vec(payload)?;
+    if let Some(shared_secret) = shared_secret {
+        response =
+            DHKeyExchange::encrypt(shared_secret, &response).map_err(std::io::Error::other)?;
+    }
+
+    let mut response_buffer = Vec::new();
+    response_buffer.write_u32::<NetworkEndian>(response.len() as u32)?;
+    response_buffer.extend_from_slice(&response);
+
+    let result = stream.write_all(&response_buffer);
+    match result {
+        Ok(_) => {
+            info!("Response sent successfully");
+            stream.flush()?;
+            stream.close_write()?;
+        }
+        Err(e) => {
+            error!("Error sending response: {}", e);
+            stream.flush()?;
+            stream.close_write()?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::io::Cursor;
+
+    // Feeds `input` to the server and collects what it writes back
+    struct Scripted {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Scripted {
+        fn new(input: Vec<u8>) -> Self {
+            Scripted {
+                input: Cursor::new(input),
+                output: Vec::new(),
+            }
+        }
+    }
+
+    impl Read for Scripted {
+        fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+            self.input.read(buffer)
+        }
+    }
+
+    impl Write for Scripted {
+        fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+            self.output.write(data)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Connection for Scripted {
+        fn close_write(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+
+        fn hold_open(&mut self, _: std::time::Duration) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // A client that never stops sending
+    struct Endless(u8);
+
+    impl Read for Endless {
+        fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+            buffer.fill(self.0);
+            Ok(buffer.len())
+        }
+    }
+
+    fn with_length(length: u32, key: &[u8]) -> Vec<u8> {
+        let mut input = length.to_be_bytes().to_vec();
+        input.extend_from_slice(key);
+        input
+    }
+
+    // Hands out one byte per read, like a slow client
+    struct Trickle(Cursor<Vec<u8>>);
+
+    impl Read for Trickle {
+        fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+            let len = buffer.len().min(1);
+            self.0.read(&mut buffer[..len])
+        }
+    }
+
+    #[tokio::test]
+    async fn get_env_reports_the_expiry_of_the_pinned_version() {
+        let server = SecretsServer::new(String::new(), String::new());
+        let namespace = Namespace::parse("app/dev");
+        let caller = Principal::Uid(0);
+        for (value, ttl) in [("old", Some(3600)), ("new", None)] {
+            let command = Command::StoreEnv {
+                secrets: HashMap::from([("KEY".to_string(), value.to_string())]),
+                ttl,
+                types: HashMap::new(),
+            };
+            server.execute(&namespace, command, &caller).await.unwrap();
+        }
+
+        let get = |versions: HashMap<String, u64>| Command::GetEnv {
+            keys: vec!["KEY".to_string()],
+            versions,
+        };
+        let live = server
+            .execute(&namespace, get(HashMap::new()), &caller)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(live["KEY"]["value"], "new");
+        assert!(live["KEY"].get("expires_at").is_none());
+
+        let pinned = server
+            .execute(
+                &namespace,
+                get(HashMap::from([("KEY".to_string(), 1)])),
+                &caller,
+            )
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(pinned["KEY"]["value"], "old");
+        assert!(pinned["KEY"]["expires_at"].as_u64().is_some());
+    }
+
+    #[test]
+    fn watch_finishes_once_subscriptions_end() {
+        let mut store = SecureStore::new();
+        let namespace = Namespace::parse("app/dev");
+        let events = store.subscribe();
+        store.end_subscriptions();
+
+        let current = vec![ChangeEvent::changed(&namespace, "KEY", 1)];
+        let result = stream_changes(
+            Scripted::new(Vec::new()),
+            None,
+            &namespace,
+            &Subscription::default(),
+            current,
+            events,
+            |_| Vec::new(),
+        );
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn read_request_stops_at_a_complete_value() {
+        let data = br#"{"command":"status"}{"never":"read"}"#.to_vec();
+        let mut stream = Trickle(Cursor::new(data));
+        let request = read_request(&mut stream).unwrap();
+        assert_eq!(request, br#"{"command":"status"}"#);
+        assert!(Request::parse(&request).is_ok());
+    }
+
+    #[test]
+    fn read_request_gives_up_on_oversized_input() {
+        for byte in [b'[', b'"', b' ', 0xff] {
+            let request = read_request(&mut Endless(byte)).unwrap();
+            assert!(request.len() <= MAX_REQUEST_SIZE + 1024);
+        }
+    }
+
+    #[test]
+    fn read_request_survives_garbage() {
+        let mut rng = StdRng::seed_from_u64(41);
+        for _ in 0..2000 {
+            let data: Vec<u8> = (0..rng.gen_range(0..2048)).map(|_| rng.gen()).collect();
+            let request = read_request(&mut Cursor::new(data)).unwrap();
+            let _ = Request::parse(&request);
+        }
+    }
+
+    #[test]
+    fn handshake_refuses_empty_and_degenerate_keys() {
+        for key in [&[][..], &[0][..], &[1][..]] {
+            let mut stream = Scripted::new(with_length(key.len() as u32, key));
+            assert!(handshake(&mut stream).is_err());
+        }
+    }
+
+    #[test]
+    fn handshake_refuses_oversized_key_lengths() {
+        for length in [MAX_PUBLIC_KEY_SIZE as u32 + 1, u32::MAX] {
+            let mut stream = Scripted::new(with_length(length, &[]));
+            assert!(handshake(&mut stream).is_err());
+        }
+    }
+
+    #[test]
+    fn handshake_refuses_truncated_input() {
+        let key = DHKeyExchange::new().get_public_key().to_bytes_be();
+        let complete = with_length(key.len() as u32, &key);
+        for len in 0..complete.len() {
+            let mut stream = Scripted::new(complete[..len].to_vec());
+            assert!(handshake(&mut stream).is_err());
+        }
+
+        let mut stream = Scripted::new(complete);
+        assert!(handshake(&mut stream).is_ok());
+    }
+
+    #[test]
+    fn handshake_gives_up_on_a_client_that_never_speaks() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let _silent = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+
+        let (mut stream, _) = listener.accept().unwrap();
+        set_timeouts(&stream, 1).unwrap();
+        let started = std::time::Instant::now();
+        assert!(handshake(&mut stream).is_err());
+        assert!(started.elapsed() < std::time::Duration::from_secs(5));
+    }
+
+    #[test]
+    fn handshake_survives_garbage() {
+        let mut rng = StdRng::seed_from_u64(42);
+        for _ in 0..200 {
+            let data: Vec<u8> = (0..rng.gen_range(0..600)).map(|_| rng.gen()).collect();
+            let _ = handshake(&mut Scripted::new(data));
+        }
+    }
+}
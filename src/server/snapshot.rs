@@ -0,0 +1,426 @@
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use byteorder::{NetworkEndian, ReadBytesExt};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{Cursor, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use crate::types::metadata::unix_now;
+use crate::types::namespace::Namespace;
+
+const MAGIC: &[u8; 8] = b"SHNSNAP1";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const PBKDF2_ROUNDS: u32 = 600_000;
+
+// Where the key sealing on-disk state comes from
+#[derive(Clone)]
+pub enum SnapshotKey {
+    // Stretched with PBKDF2-HMAC-SHA256 under a per-file salt
+    Passphrase(String),
+    // Stand-in for an OS keyring: a random key kept in a 0600 file that is
+    // created on first use
+    Keyring(PathBuf),
+    // Existing key material, hashed down to 32 bytes
+    KeyFile(PathBuf),
+}
+
+impl SnapshotKey {
+    // Encrypts `plaintext` into `path` behind a header carrying the write time
+    // and salt, which is authenticated along with the payload. The file is
+    // written next to `path` and renamed over it, so readers never observe a
+    // partial write.
+    pub fn seal_to_file(&self, path: &Path, plaintext: &[u8]) -> Result<(), String> {
+        let created_at = unix_now();
+        let mut salt = [0u8; SALT_LEN];
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut salt);
+        rand::thread_rng().fill_bytes(&mut nonce);
+
+        let header = header(created_at, &salt);
+        let key = self.derive_key(&salt)?;
+
+        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key));
+        let ciphertext = cipher
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: plaintext,
+                    aad: &header,
+                },
+            )
+            .map_err(|_| format!("Failed to seal {}", path.display()))?;
+
+        let mut contents = header;
+        contents.extend_from_slice(&nonce);
+        contents.extend_from_slice(&ciphertext);
+
+        let tmp_path = path.with_extension("tmp");
+        write_private(&tmp_path, &contents)?;
+        fs::rename(&tmp_path, path).map_err(|e| e.to_string())
+    }
+
+    // Returns the write time and the decrypted payload of a sealed file
+    pub fn open_file(&self, path: &Path) -> Result<(u64, Vec<u8>), String> {
+        let contents = fs::read(path).map_err(|e| e.to_string())?;
+        let header_len = MAGIC.len() + 8 + SALT_LEN;
+        if contents.len() < header_len + NONCE_LEN || &contents[..MAGIC.len()] != MAGIC {
+            return Err(format!("{} is not a sealed file", path.display()));
+        }
+
+        let mut cursor = Cursor::new(&contents[MAGIC.len()..]);
+        let created_at = cursor
+            .read_u64::<NetworkEndian>()
+            .map_err(|e| e.to_string())?;
+        let mut salt = [0u8; SALT_LEN];
+        cursor.read_exact(&mut salt).map_err(|e| e.to_string())?;
+
+        let key = self.derive_key(&salt)?;
+        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key));
+        let nonce = &contents[header_len..header_len + NONCE_LEN];
+        let plaintext = cipher
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: &contents[header_len + NONCE_LEN..],
+                    aad: &contents[..header_len],
+                },
+            )
+            .map_err(|_| format!("{} could not be decrypted", path.display()))?;
+
+        Ok((created_at, plaintext))
+    }
+
+    fn derive_key(&self, salt: &[u8]) -> Result<[u8; 32], String> {
+        match self {
+            SnapshotKey::Passphrase(passphrase) => {
+                let mut key = [0u8; 32];
+                pbkdf2::pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), salt, PBKDF2_ROUNDS, &mut key);
+                Ok(key)
+            }
+            SnapshotKey::Keyring(path) => {
+                if !path.exists() {
+                    let mut material = [0u8; 32];
+                    rand::thread_rng().fill_bytes(&mut material);
+                    write_private(path, &material)?;
+                }
+                hash_key_file(path)
+            }
+            SnapshotKey::KeyFile(path) => hash_key_file(path),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct SnapshotConfig {
+    pub path: PathBuf,
+    pub key: SnapshotKey,
+    // Snapshots older than this many seconds are refused at startup
+    pub max_age: Option<u64>,
+}
+
+// One namespace as it was last synced from the backend
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SnapshotEntry {
+    pub synced_at: u64,
+    pub secrets: HashMap<String, String>,
+}
+
+// Decrypted snapshot contents
+pub struct Snapshot {
+    pub created_at: u64,
+    pub namespaces: HashMap<Namespace, SnapshotEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SnapshotBody {
+    namespaces: Vec<(Namespace, SnapshotEntry)>,
+}
+
+impl SnapshotConfig {
+    pub fn new(path: impl Into<PathBuf>, key: SnapshotKey) -> Self {
+        SnapshotConfig {
+            path: path.into(),
+            key,
+            max_age: None,
+        }
+    }
+
+    pub fn with_max_age(mut self, max_age: u64) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    // Seals `namespaces` and atomically replaces the snapshot file with it.
+    pub fn write(&self, namespaces: HashMap<Namespace, SnapshotEntry>) -> Result<(), String> {
+        let body = SnapshotBody {
+            namespaces: namespaces.into_iter().collect(),
+        };
+        let mut plaintext = serde_json::to_vec(&body).map_err(|e| e.to_string())?;
+
+        let result = self.key.seal_to_file(&self.path, &plaintext);
+        plaintext.fill(0);
+        result
+    }
+
+    pub fn load(&self) -> Result<Snapshot, String> {
+        let (created_at, mut plaintext) = self.key.open_file(&self.path)?;
+
+        let body = serde_json::from_slice::<SnapshotBody>(&plaintext);
+        plaintext.fill(0);
+        let body = body.map_err(|e| e.to_string())?;
+
+        Ok(Snapshot {
+            created_at,
+            namespaces: body.namespaces.into_iter().collect(),
+        })
+    }
+
+    // Refuses cached data that was synced longer than `max_age` seconds ago
+    pub fn check_age(&self, synced_at: u64) -> Result<(), String> {
+        match self.max_age {
+            Some(max_age) if unix_now().saturating_sub(synced_at) > max_age => Err(format!(
+                "Cached data from {} is older than the allowed {}s",
+                format_timestamp(synced_at),
+                max_age
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn header(created_at: u64, salt: &[u8]) -> Vec<u8> {
+    let mut header = MAGIC.to_vec();
+    header.extend_from_slice(&created_at.to_be_bytes());
+    header.extend_from_slice(salt);
+    header
+}
+
+fn hash_key_file(path: &Path) -> Result<[u8; 32], String> {
+    let mut material = fs::read(path).map_err(|e| e.to_string())?;
+    if material.is_empty() {
+        return Err(format!("Key file {} is empty", path.display()));
+    }
+
+    let key = Sha256::digest(&material).into();
+    material.fill(0);
+    Ok(key)
+}
+
+fn write_private(path: &Path, contents: &[u8]) -> Result<(), String> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .map_err(|e| e.to_string())?;
+
+    file.write_all(contents).map_err(|e| e.to_string())?;
+    file.sync_all().map_err(|e| e.to_string())
+}
+
+// Renders a unix timestamp as an RFC 3339 UTC string for status messages
+pub fn format_timestamp(timestamp: u64) -> String {
+    let days = (timestamp / 86_400) as i64;
+    let seconds = timestamp % 86_400;
+
+    // Civil-from-days, after Howard Hinnant
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        seconds / 3_600,
+        (seconds % 3_600) / 60,
+        seconds % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::server::SecretsServer;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("snapshot-{}-{}", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn config(name: &str) -> SnapshotConfig {
+        SnapshotConfig::new(
+            temp_path(name),
+            SnapshotKey::Keyring(temp_path(&format!("{}-key", name))),
+        )
+    }
+
+    fn namespaces() -> HashMap<Namespace, SnapshotEntry> {
+        HashMap::from([(
+            Namespace::parse("app/dev"),
+            SnapshotEntry {
+                synced_at: 1_700_000_000,
+                secrets: HashMap::from([
+                    ("DB_PASSWORD".to_string(), "hunter2".to_string()),
+                    ("MULTILINE".to_string(), "a\nb".to_string()),
+                ]),
+            },
+        )])
+    }
+
+    fn assert_round_trip(config: &SnapshotConfig) {
+        config.write(namespaces()).unwrap();
+        let snapshot = config.load().unwrap();
+        assert!(unix_now() - snapshot.created_at < 60);
+        let entry = &snapshot.namespaces[&Namespace::parse("app/dev")];
+        assert_eq!(entry.synced_at, 1_700_000_000);
+        assert_eq!(
+            entry.secrets,
+            namespaces()
+                .remove(&Namespace::parse("app/dev"))
+                .unwrap()
+                .secrets
+        );
+    }
+
+    #[test]
+    fn snapshots_round_trip_without_plaintext_on_disk() {
+        let config = config("round-trip");
+        assert_round_trip(&config);
+
+        let contents = fs::read(&config.path).unwrap();
+        assert!(contents.starts_with(MAGIC));
+        assert!(!contents.windows(7).any(|window| window == b"hunter2"));
+        let mode = fs::metadata(&config.path).unwrap().permissions();
+        assert_eq!(
+            std::os::unix::fs::PermissionsExt::mode(&mode) & 0o777,
+            0o600
+        );
+    }
+
+    #[test]
+    fn passphrase_and_key_file_snapshots_round_trip() {
+        assert_round_trip(&SnapshotConfig::new(
+            temp_path("passphrase"),
+            SnapshotKey::Passphrase("correct horse".to_string()),
+        ));
+
+        let key_file = temp_path("key-file-key");
+        fs::write(&key_file, b"some key material").unwrap();
+        assert_round_trip(&SnapshotConfig::new(
+            temp_path("key-file"),
+            SnapshotKey::KeyFile(key_file),
+        ));
+    }
+
+    #[test]
+    fn tampered_snapshots_are_refused() {
+        let config = config("tamper");
+        config.write(namespaces()).unwrap();
+        let sealed = fs::read(&config.path).unwrap();
+        let header_len = MAGIC.len() + 8 + SALT_LEN;
+
+        // A ciphertext byte, the authenticated write time, the salt, the
+        // nonce and the tag each break the seal
+        for index in [
+            sealed.len() / 2 + header_len / 2,
+            MAGIC.len() + 7,
+            MAGIC.len() + 8,
+            header_len,
+            sealed.len() - 1,
+        ] {
+            let mut tampered = sealed.clone();
+            tampered[index] ^= 0x01;
+            fs::write(&config.path, &tampered).unwrap();
+            assert!(config.load().is_err(), "byte {} was not checked", index);
+        }
+
+        fs::write(&config.path, &sealed[..header_len + NONCE_LEN - 1]).unwrap();
+        assert!(config.load().is_err());
+        fs::write(&config.path, &sealed).unwrap();
+        assert!(config.load().is_ok());
+    }
+
+    #[test]
+    fn snapshots_need_the_key_they_were_sealed_with() {
+        let config = config("wrong-key");
+        config.write(namespaces()).unwrap();
+
+        let other = SnapshotConfig::new(
+            config.path.clone(),
+            SnapshotKey::Keyring(temp_path("wrong-key-other")),
+        );
+        assert!(other.load().is_err());
+    }
+
+    #[test]
+    fn cached_data_past_the_max_age_is_refused() {
+        let now = unix_now();
+        let unlimited = config("max-age");
+        assert!(unlimited.check_age(0).is_ok());
+
+        let limited = unlimited.with_max_age(3600);
+        assert!(limited.check_age(now).is_ok());
+        assert!(limited.check_age(now - 3600).is_ok());
+        assert!(limited.check_age(now - 3601).is_err());
+        assert!(limited.check_age(0).is_err());
+        // A clock that went backwards is not in the past
+        assert!(limited.check_age(now + 3600).is_ok());
+    }
+
+    #[test]
+    fn timestamps_render_as_rfc_3339() {
+        assert_eq!(format_timestamp(0), "1970-01-01T00:00:00Z");
+        assert_eq!(format_timestamp(951_782_400), "2000-02-29T00:00:00Z");
+        assert_eq!(format_timestamp(1_700_000_000), "2023-11-14T22:13:20Z");
+    }
+
+    #[test]
+    fn a_stale_snapshot_is_not_served() {
+        let namespace = Namespace::parse("app/dev");
+        let config = config("stale").with_max_age(3600);
+        config.write(namespaces()).unwrap();
+        let server = SecretsServer::new(String::new(), String::new()).with_snapshot(config.clone());
+
+        assert!(server
+            .restore_project(&namespace, "token".to_string())
+            .is_err());
+        assert!(!server
+            .store
+            .read()
+            .unwrap()
+            .contains(&namespace, "DB_PASSWORD"));
+        assert!(server.policy.lock().unwrap().token(&namespace).is_none());
+
+        let mut fresh = namespaces();
+        fresh.get_mut(&namespace).unwrap().synced_at = unix_now();
+        config.write(fresh).unwrap();
+        assert!(server
+            .restore_project(&namespace, "token".to_string())
+            .is_ok());
+        assert_eq!(
+            server
+                .store
+                .read()
+                .unwrap()
+                .get_secret(&namespace, "DB_PASSWORD")
+                .as_deref(),
+            Some("hunter2")
+        );
+    }
+}
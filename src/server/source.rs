@@ -0,0 +1,302 @@
+use log::{info, warn};
+use reqwest::{
+    header::{HeaderMap, HeaderValue, AUTHORIZATION},
+    Client,
+};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::error::Error;
+use std::future::Future;
+use std::path::PathBuf;
+use std::pin::Pin;
+
+use crate::server::formats::parse_dotenv;
+use crate::server::server::GetKeysInput;
+use crate::server::snapshot::SnapshotConfig;
+
+pub type SourceFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<FetchedSecrets, String>> + Send + 'a>>;
+
+// What a source returned for one project
+#[derive(Default)]
+pub struct FetchedSecrets {
+    pub secrets: HashMap<String, String>,
+    // Backend versions, for sources that track them
+    pub versions: HashMap<String, u64>,
+}
+
+// Somewhere the daemon can load a project's secrets from
+pub trait SecretSource: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn fetch<'a>(&'a self, input: &'a GetKeysInput) -> SourceFuture<'a>;
+}
+
+// The Shinobi backend: `POST {base_url}/projects/getkeys`
+pub struct ShinobiApiSource {
+    client: Client,
+    base_url: String,
+    token: String,
+}
+
+impl ShinobiApiSource {
+    pub fn new(client: Client, base_url: String, token: String) -> Self {
+        ShinobiApiSource {
+            client,
+            base_url,
+            token,
+        }
+    }
+
+    pub async fn build_project(&self, input: &GetKeysInput) -> Result<Value, Box<dyn Error>> {
+        let url = format!("{}/projects/getkeys", self.base_url);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            AUTHORIZATION,
+            HeaderValue::from_str(&format!("Bearer {}", self.token))?,
+        );
+
+        let response = self
+            .client
+            .post(&url)
+            .headers(headers)
+            .json(input)
+            .send()
+            .await?;
+
+        if response.status() == reqwest::StatusCode::OK {
+            let project: Value = response.json().await?;
+            // Only the names; the values must never reach the log
+            let mut keys: Vec<&String> = project
+                .get("keys")
+                .and_then(Value::as_object)
+                .map(|keys| keys.keys().collect())
+                .unwrap_or_default();
+            keys.sort();
+            info!("Fetched keys {:?} for {}", keys, input.namespace());
+            Ok(project)
+        } else {
+            let error_msg: Value = response.json().await?;
+            Err(format!("{:?}", error_msg).into())
+        }
+    }
+}
+
+impl SecretSource for ShinobiApiSource {
+    fn name(&self) -> &str {
+        "shinobi"
+    }
+
+    fn fetch<'a>(&'a self, input: &'a GetKeysInput) -> SourceFuture<'a> {
+        Box::pin(async move {
+            let project = self.build_project(input).await.map_err(|e| e.to_string())?;
+
+            let keys = project
+                .get("keys")
+                .and_then(|keys| keys.as_object())
+                .ok_or("No valid keys found in the project response")?;
+
+            let mut secrets = HashMap::new();
+            for (key, value) in keys {
+                if let Some(value_str) = value.as_str() {
+                    secrets.insert(key.clone(), value_str.to_string());
+                }
+            }
+
+            let versions = project
+                .get("versions")
+                .and_then(|versions| serde_json::from_value(versions.clone()).ok())
+                .unwrap_or_default();
+
+            Ok(FetchedSecrets { secrets, versions })
+        })
+    }
+}
+
+// A sealed snapshot file, as written by the daemon itself. Handy for CI,
+// where a snapshot can be provisioned instead of reaching the backend.
+pub struct EncryptedFileSource {
+    config: SnapshotConfig,
+}
+
+impl EncryptedFileSource {
+    pub fn new(config: SnapshotConfig) -> Self {
+        EncryptedFileSource { config }
+    }
+}
+
+impl SecretSource for EncryptedFileSource {
+    fn name(&self) -> &str {
+        "encrypted-file"
+    }
+
+    fn fetch<'a>(&'a self, input: &'a GetKeysInput) -> SourceFuture<'a> {
+        Box::pin(async move {
+            let namespace = input.namespace();
+            let snapshot = self.config.load()?;
+            let entry = snapshot.namespaces.get(&namespace).ok_or_else(|| {
+                format!(
+                    "{} has no data for {}",
+                    self.config.path.display(),
+                    namespace
+                )
+            })?;
+            self.config.check_age(entry.synced_at)?;
+
+            Ok(FetchedSecrets {
+                secrets: entry.secrets.clone(),
+                versions: HashMap::new(),
+            })
+        })
+    }
+}
+
+// A plain `.env` file, served to every project
+pub struct DotEnvSource {
+    path: PathBuf,
+}
+
+impl DotEnvSource {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        DotEnvSource { path: path.into() }
+    }
+}
+
+impl SecretSource for DotEnvSource {
+    fn name(&self) -> &str {
+        "dotenv"
+    }
+
+    fn fetch<'a>(&'a self, _input: &'a GetKeysInput) -> SourceFuture<'a> {
+        Box::pin(async move {
+            let content = std::fs::read_to_string(&self.path)
+                .map_err(|e| format!("{}: {}", self.path.display(), e))?;
+            let secrets =
+                parse_dotenv(&content).map_err(|e| format!("{}: {}", self.path.display(), e))?;
+
+            Ok(FetchedSecrets {
+                secrets,
+                versions: HashMap::new(),
+            })
+        })
+    }
+}
+
+// Passes through the daemon's own environment variables that start with
+// `prefix`, with the prefix stripped from the key names.
+pub struct EnvSource {
+    prefix: String,
+}
+
+impl EnvSource {
+    pub fn new(prefix: impl Into<String>) -> Self {
+        EnvSource {
+            prefix: prefix.into(),
+        }
+    }
+}
+
+impl SecretSource for EnvSource {
+    fn name(&self) -> &str {
+        "env"
+    }
+
+    fn fetch<'a>(&'a self, _input: &'a GetKeysInput) -> SourceFuture<'a> {
+        Box::pin(async move {
+            let secrets = std::env::vars()
+                .filter_map(|(key, value)| {
+                    key.strip_prefix(&self.prefix)
+                        .filter(|key| !key.is_empty())
+                        .map(|key| (key.to_string(), value))
+                })
+                .collect();
+
+            Ok(FetchedSecrets {
+                secrets,
+                versions: HashMap::new(),
+            })
+        })
+    }
+}
+
+impl SecretSource for Box<dyn SecretSource> {
+    fn name(&self) -> &str {
+        (**self).name()
+    }
+
+    fn fetch<'a>(&'a self, input: &'a GetKeysInput) -> SourceFuture<'a> {
+        (**self).fetch(input)
+    }
+}
+
+struct Layer {
+    source: Box<dyn SecretSource>,
+    required: bool,
+}
+
+// Stacks several sources. Layers are applied in the order they were added,
+// so a later layer overrides keys from the ones below it. A required layer
+// that fails fails the whole fetch; an optional one is skipped with a warning.
+#[derive(Default)]
+pub struct LayeredSource {
+    layers: Vec<Layer>,
+}
+
+impl LayeredSource {
+    pub fn new() -> Self {
+        LayeredSource { layers: Vec::new() }
+    }
+
+    pub fn with_layer(mut self, source: impl SecretSource + 'static) -> Self {
+        self.layers.push(Layer {
+            source: Box::new(source),
+            required: true,
+        });
+        self
+    }
+
+    pub fn with_optional_layer(mut self, source: impl SecretSource + 'static) -> Self {
+        self.layers.push(Layer {
+            source: Box::new(source),
+            required: false,
+        });
+        self
+    }
+}
+
+impl SecretSource for LayeredSource {
+    fn name(&self) -> &str {
+        "layered"
+    }
+
+    fn fetch<'a>(&'a self, input: &'a GetKeysInput) -> SourceFuture<'a> {
+        Box::pin(async move {
+            let mut merged = FetchedSecrets::default();
+            let mut loaded = 0;
+
+            for layer in &self.layers {
+                match layer.source.fetch(input).await {
+                    Ok(fetched) => {
+                        for key in fetched.secrets.keys() {
+                            merged.versions.remove(key);
+                        }
+                        merged.secrets.extend(fetched.secrets);
+                        merged.versions.extend(fetched.versions);
+                        loaded += 1;
+                    }
+                    Err(e) if layer.required => {
+                        return Err(format!("{} source: {}", layer.source.name(), e));
+                    }
+                    Err(e) => warn!("Skipping {} source: {}", layer.source.name(), e),
+                }
+            }
+
+            if loaded == 0 {
+                return Err("No source could be loaded".to_string());
+            }
+
+            Ok(merged)
+        })
+    }
+}
@@ -0,0 +1,279 @@
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::Ordering;
+
+use crate::server::policy::Principal;
+use crate::server::server::SecretsServer;
+use crate::types::metadata::unix_now;
+use crate::types::namespace::Namespace;
+
+// Outcome of the most recent sync of one namespace
+#[derive(Clone, Debug, Default)]
+pub struct SyncRecord {
+    pub last_attempt_at: Option<u64>,
+    pub last_synced_at: Option<u64>,
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct NamespaceStatus {
+    pub keys: usize,
+    pub last_attempt_at: Option<u64>,
+    pub last_synced_at: Option<u64>,
+    pub last_error: Option<String>,
+    // Set while the namespace is served from the snapshot, to when that data
+    // was synced
+    pub snapshot_synced_at: Option<u64>,
+    pub pending_writes: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MemoryUsage {
+    // Separately mapped secure memory blocks holding secret versions,
+    // including retained history. They are not mlocked.
+    pub blocks: usize,
+    pub bytes: usize,
+}
+
+// What the daemon reports about itself. Counts and timestamps only; values
+// never appear here. Anyone may see readiness and uptime, which is all a
+// probe needs; the rest is left out unless the caller is authorized.
+#[derive(Debug, Serialize)]
+pub struct Status {
+    pub version: &'static str,
+    pub started_at: u64,
+    pub uptime_secs: u64,
+    // Every configured project has been loaded, from the backend or a snapshot
+    pub ready: bool,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<StatusDetail>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StatusDetail {
+    pub listeners: Vec<String>,
+    // Only the namespaces the caller is authorized for, unless it is an admin
+    pub namespaces: BTreeMap<String, NamespaceStatus>,
+    pub secure_memory: MemoryUsage,
+    // When every secret was last resealed under fresh keys
+    pub keys_rotated_at: Option<u64>,
+}
+
+// How much of the status a caller gets to see
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StatusScope {
+    // Readiness and uptime only
+    Probe,
+    // Daemon-wide detail, plus the state of one namespace
+    Namespace(Namespace),
+    // Everything, for admins
+    Full,
+}
+
+impl SecretsServer {
+    // Picks the scope for a status request. An admin credential, given as the
+    // token, shows everything; a project token, or a trusted caller, shows its
+    // namespace. A caller without a token gets the probe view. A token that
+    // unlocks nothing counts as a failure, like any other refused token.
+    pub fn status_scope(
+        &self,
+        namespace: Option<&str>,
+        environment: Option<&str>,
+        token: Option<&str>,
+        caller: &Principal,
+    ) -> StatusScope {
+        let Ok(policy) = self.policy.lock() else {
+            return StatusScope::Probe;
+        };
+
+        if policy.authorize_admin(token, caller).is_ok() {
+            return StatusScope::Full;
+        }
+        match policy.authorize(namespace, environment, token, false, caller) {
+            Ok(namespace) => StatusScope::Namespace(namespace),
+            Err(_) => {
+                drop(policy);
+                if token.is_some() {
+                    self.record_failure(caller);
+                }
+                StatusScope::Probe
+            }
+        }
+    }
+
+    pub fn status(&self, scope: &StatusScope) -> Result<Status, String> {
+        let now = unix_now();
+        let detail = match scope {
+            StatusScope::Probe => None,
+            StatusScope::Namespace(namespace) => Some(self.status_detail(Some(namespace))?),
+            StatusScope::Full => Some(self.status_detail(None)?),
+        };
+
+        Ok(Status {
+            version: env!("CARGO_PKG_VERSION"),
+            started_at: self.started_at,
+            uptime_secs: now.saturating_sub(self.started_at),
+            ready: self.ready.load(Ordering::SeqCst),
+            detail,
+        })
+    }
+
+    // Detail for `only`, or for every namespace
+    fn status_detail(&self, only: Option<&Namespace>) -> Result<StatusDetail, String> {
+        let visible = |namespace: &Namespace| only.is_none_or(|only| only == namespace);
+
+        let (mut counts, memory, keys_rotated_at): (HashMap<Namespace, usize>, MemoryUsage, _) = {
+            let store = self
+                .store
+                .read()
+                .map_err(|e| format!("Error locking store: {}", e))?;
+            let counts = store
+                .namespaces()
+                .filter(|namespace| visible(namespace))
+                .map(|namespace| (namespace.clone(), store.key_count(namespace)))
+                .collect();
+            let (blocks, bytes) = store.memory_usage();
+            (
+                counts,
+                MemoryUsage { blocks, bytes },
+                store.keys_rotated_at(),
+            )
+        };
+
+        let records = self
+            .sync_records
+            .lock()
+            .map_err(|e| format!("Error locking sync state: {}", e))?
+            .clone();
+        let cached = self
+            .cached_namespaces
+            .lock()
+            .map_err(|e| format!("Error locking snapshot state: {}", e))?
+            .clone();
+        let pending = self
+            .write_queue
+            .lock()
+            .map_err(|e| format!("Error locking write queue: {}", e))?
+            .pending_by_namespace();
+
+        let mut namespaces: Vec<Namespace> = counts
+            .keys()
+            .chain(records.keys().filter(|namespace| visible(namespace)))
+            .cloned()
+            .collect();
+        namespaces.sort();
+        namespaces.dedup();
+
+        let namespaces = namespaces
+            .into_iter()
+            .map(|namespace| {
+                let record = records.get(&namespace).cloned().unwrap_or_default();
+                let status = NamespaceStatus {
+                    keys: counts.remove(&namespace).unwrap_or(0),
+                    last_attempt_at: record.last_attempt_at,
+                    last_synced_at: record.last_synced_at,
+                    last_error: record.last_error,
+                    snapshot_synced_at: cached.get(&namespace).copied(),
+                    pending_writes: pending.get(&namespace).map_or(0, Vec::len),
+                };
+                (namespace.to_string(), status)
+            })
+            .collect();
+
+        Ok(StatusDetail {
+            listeners: self
+                .listeners
+                .lock()
+                .map_err(|e| format!("Error locking listeners: {}", e))?
+                .clone(),
+            namespaces,
+            secure_memory: memory,
+            keys_rotated_at,
+        })
+    }
+
+    pub(crate) fn record_sync(&self, namespace: &Namespace, result: Result<(), String>) {
+        let Ok(mut records) = self.sync_records.lock() else {
+            return;
+        };
+
+        let now = unix_now();
+        let record = records.entry(namespace.clone()).or_default();
+        record.last_attempt_at = Some(now);
+        match result {
+            Ok(()) => {
+                record.last_synced_at = Some(now);
+                record.last_error = None;
+            }
+            Err(e) => record.last_error = Some(e),
+        }
+    }
+
+    pub(crate) fn add_listener(&self, address: String) {
+        if let Ok(mut listeners) = self.listeners.lock() {
+            listeners.push(address);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    fn server() -> SecretsServer {
+        let server = SecretsServer::new(String::new(), String::new()).with_admin_token("admin");
+        {
+            let mut policy = server.policy.lock().unwrap();
+            policy.register(
+                Namespace::parse("secret-project/dev"),
+                "token-a".to_string(),
+            );
+            policy.register(Namespace::parse("other/dev"), "token-b".to_string());
+        }
+        server.record_sync(
+            &Namespace::parse("secret-project/dev"),
+            Err("backend said: bad credentials".to_string()),
+        );
+        server.record_sync(&Namespace::parse("other/dev"), Ok(()));
+        server
+    }
+
+    fn shown(server: &SecretsServer, namespace: Option<&str>, token: Option<&str>) -> String {
+        let caller = Principal::Peer(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        let scope = server.status_scope(namespace, None, token, &caller);
+        serde_json::to_string(&server.status(&scope).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn probes_only_see_readiness() {
+        let server = server();
+        for token in [None, Some("wrong")] {
+            let status = shown(&server, Some("secret-project/dev"), token);
+            assert!(status.contains("\"ready\":false"));
+            for hidden in ["secret-project", "other", "bad credentials", "listeners"] {
+                assert!(!status.contains(hidden), "{} in {}", hidden, status);
+            }
+        }
+    }
+
+    #[test]
+    fn project_tokens_see_only_their_namespace() {
+        let server = server();
+        let status = shown(&server, Some("other/dev"), Some("token-b"));
+        assert!(status.contains("other/dev"));
+        assert!(!status.contains("secret-project"));
+        assert!(!status.contains("bad credentials"));
+
+        let status = shown(&server, Some("other/dev"), Some("token-a"));
+        assert!(!status.contains("other/dev"));
+    }
+
+    #[test]
+    fn admins_see_every_namespace() {
+        let status = shown(&server(), None, Some("admin"));
+        assert!(status.contains("secret-project/dev"));
+        assert!(status.contains("other/dev"));
+        assert!(status.contains("bad credentials"));
+    }
+}
@@ -1,20 +1,31 @@
 use libc::{c_void, mmap, mprotect, munmap, PROT_NONE, PROT_READ, PROT_WRITE};
-use log::warn;
 use page_size;
 use rand::RngCore;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::ptr;
 use std::slice;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+
+use crate::types::change::ChangeEvent;
+use crate::types::key_filter::KeyFilter;
+use crate::types::metadata::{unix_now, KeyInfo, SecretMetadata, SecretOrigin};
+use crate::types::namespace::Namespace;
+
+pub const DEFAULT_HISTORY_LIMIT: usize = 5;
+// Events a subscriber may leave unread before it is dropped, so a watcher
+// that stops reading cannot grow the daemon's memory
+pub const SUBSCRIBER_BACKLOG: usize = 256;
 
 struct SecureMemoryBlock {
     ptr: *mut u8,
     size: usize,
+    len: usize,
 }
 
 impl SecureMemoryBlock {
     pub fn new(size: usize) -> Result<Self, std::io::Error> {
         let page_size = page_size::get();
-        let aligned_size = ((size + page_size - 1) / page_size) * page_size;
+        let aligned_size = size.div_ceil(page_size) * page_size;
 
         let ptr = unsafe {
             mmap(
@@ -34,6 +45,7 @@ impl SecureMemoryBlock {
         Ok(SecureMemoryBlock {
             ptr: ptr as *mut u8,
             size: aligned_size,
+            len: 0,
         })
     }
 
@@ -47,26 +59,25 @@ impl SecureMemoryBlock {
         unsafe {
             ptr::copy_nonoverlapping(data.as_ptr(), self.ptr, data.len());
         }
+        self.len = data.len();
 
         Ok(())
     }
 
+    // Ciphertext may legitimately contain zero bytes, so read back exactly
+    // what was written rather than stopping at the first NUL.
     pub fn read(&self) -> Vec<u8> {
-        unsafe {
-            slice::from_raw_parts(self.ptr, self.size)
-                .to_vec()
-                .into_iter()
-                .take_while(|&x| x != 0)
-                .collect()
-        }
+        unsafe { slice::from_raw_parts(self.ptr, self.len).to_vec() }
     }
 
     pub fn clear(&mut self) {
         unsafe {
             ptr::write_bytes(self.ptr, 0, self.size);
         }
+        self.len = 0;
     }
 
+    #[allow(dead_code)]
     pub fn lock(&self) -> Result<(), std::io::Error> {
         let result = unsafe { mprotect(self.ptr as *mut c_void, self.size, PROT_NONE) };
 
@@ -76,61 +87,590 @@ impl SecureMemoryBlock {
             Ok(())
         }
     }
-}
 
-impl Drop for SecureMemoryBlock {
-    fn drop(&mut self) {
-        self.clear();
+    // Zeroes the whole mapping with volatile writes, so the compiler cannot
+    // drop them as dead stores, then unmaps it. Safe to call more than once.
+    pub fn wipe(&mut self) {
+        if self.ptr.is_null() {
+            return;
+        }
+
+        for offset in 0..self.size {
+            unsafe {
+                ptr::write_volatile(self.ptr.add(offset), 0);
+            }
+        }
 
         unsafe {
             munmap(self.ptr as *mut c_void, self.size);
         }
+        self.ptr = ptr::null_mut();
+        self.len = 0;
+    }
+}
+
+impl Drop for SecureMemoryBlock {
+    fn drop(&mut self) {
+        self.wipe();
     }
 }
 
 unsafe impl Send for SecureMemoryBlock {}
 unsafe impl Sync for SecureMemoryBlock {}
 
-pub struct SecureStore {
-    blocks: HashMap<String, SecureMemoryBlock>,
-    pub keys: HashMap<String, Vec<u8>>,
+struct SecretVersion {
+    block: SecureMemoryBlock,
+    key: Vec<u8>,
+    metadata: SecretMetadata,
 }
 
-impl SecureStore {
-    pub fn new() -> Self {
-        SecureStore {
-            blocks: HashMap::new(),
-            keys: HashMap::new(),
-        }
+impl SecretVersion {
+    fn seal(value: String, metadata: SecretMetadata) -> Result<Self, String> {
+        Self::seal_bytes(&value.into_bytes(), metadata)
     }
 
-    pub fn store_secret(&mut self, key: String, value: String) -> Result<(), String> {
+    fn seal_bytes(value_bytes: &[u8], metadata: SecretMetadata) -> Result<Self, String> {
         let mut encryption_key = vec![0u8; 32];
         rand::thread_rng().fill_bytes(&mut encryption_key);
 
-        let value_bytes = value.into_bytes();
-        let encrypted_data = Self::encrypt(&value_bytes, &encryption_key);
+        let encrypted_data = SecureStore::encrypt(value_bytes, &encryption_key);
         let block_size = encrypted_data.len() + 32;
 
         let mut block = SecureMemoryBlock::new(block_size).map_err(|e| e.to_string())?;
 
         block.write(&encrypted_data).map_err(|e| e.to_string())?;
 
-        self.blocks.insert(key.clone(), block);
-        self.keys.insert(key, encryption_key);
+        Ok(SecretVersion {
+            block,
+            key: encryption_key,
+            metadata,
+        })
+    }
+
+    fn open(&self) -> String {
+        let encrypted_data = self.block.read();
+        let decrypted_data = SecureStore::decrypt(&encrypted_data, &self.key);
+        String::from_utf8(decrypted_data).unwrap_or_default()
+    }
+
+    // The same value and metadata sealed under a fresh key in a new block
+    fn rekey(&self) -> Result<Self, String> {
+        let mut value = SecureStore::decrypt(&self.block.read(), &self.key);
+        let resealed = Self::seal_bytes(&value, self.metadata.clone());
+        value.fill(0);
+        resealed
+    }
+
+    fn wipe(&mut self) {
+        self.block.wipe();
+        self.key.fill(0);
+    }
+}
+
+// The live version of a secret plus a bounded, newest-first list of the
+// versions it replaced.
+struct SecretHistory {
+    current: SecretVersion,
+    previous: VecDeque<SecretVersion>,
+}
+
+impl SecretHistory {
+    fn next_version(&self) -> u64 {
+        self.current.metadata.version + 1
+    }
+
+    fn push(&mut self, version: SecretVersion, limit: usize) {
+        let replaced = std::mem::replace(&mut self.current, version);
+        self.previous.push_front(replaced);
+        self.previous.truncate(limit);
+    }
+
+    // Each version expires on its own, so a pin cannot reach one whose TTL
+    // has run out even while the live version is still valid
+    fn find(&self, version: u64, now: u64) -> Option<&SecretVersion> {
+        std::iter::once(&self.current)
+            .chain(self.previous.iter())
+            .find(|entry| entry.metadata.version == version && !entry.metadata.is_expired(now))
+    }
+}
+
+pub struct SecureStore {
+    namespaces: HashMap<Namespace, HashMap<String, SecretHistory>>,
+    namespace_ttls: HashMap<Namespace, u64>,
+    history_limit: usize,
+    // Bumped on every change, so watchers can tell when to re-read
+    generation: u64,
+    subscribers: Vec<SyncSender<ChangeEvent>>,
+    // Set by `end_subscriptions`; later subscribers are disconnected at once
+    subscriptions_ended: bool,
+    // Set by `wipe`; a wiped store refuses new secrets
+    wiped: bool,
+    keys_rotated_at: Option<u64>,
+}
+
+impl Default for SecureStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SecureStore {
+    pub fn new() -> Self {
+        Self::with_history_limit(DEFAULT_HISTORY_LIMIT)
+    }
+
+    pub fn with_history_limit(history_limit: usize) -> Self {
+        SecureStore {
+            namespaces: HashMap::new(),
+            namespace_ttls: HashMap::new(),
+            history_limit,
+            generation: 0,
+            subscribers: Vec::new(),
+            subscriptions_ended: false,
+            wiped: false,
+            keys_rotated_at: None,
+        }
+    }
+
+    // Default lifetime, in seconds, for secrets written to `namespace` without
+    // a TTL of their own. `None` lets them live until they are replaced.
+    pub fn set_namespace_ttl(&mut self, namespace: &Namespace, ttl: Option<u64>) {
+        match ttl {
+            Some(ttl) => self.namespace_ttls.insert(namespace.clone(), ttl),
+            None => self.namespace_ttls.remove(namespace),
+        };
+    }
+
+    // Stores `value` as the newest version of `key` and returns its metadata.
+    // `ttl` overrides the namespace default for this version only.
+    pub fn store_secret(
+        &mut self,
+        namespace: &Namespace,
+        key: String,
+        value: String,
+        origin: SecretOrigin,
+        ttl: Option<u64>,
+    ) -> Result<SecretMetadata, String> {
+        self.check_open()?;
+        let ttl = self.ttl_for(namespace, ttl);
+        let entries = self.namespaces.entry(namespace.clone()).or_default();
+
+        let metadata = match entries.get_mut(&key) {
+            Some(history) => {
+                let metadata = SecretMetadata::new(history.next_version(), origin).with_ttl(ttl);
+                let version = SecretVersion::seal(value, metadata.clone())?;
+                history.push(version, self.history_limit);
+                metadata
+            }
+            None => {
+                let metadata = SecretMetadata::new(1, origin).with_ttl(ttl);
+                let current = SecretVersion::seal(value, metadata.clone())?;
+                entries.insert(
+                    key.clone(),
+                    SecretHistory {
+                        current,
+                        previous: VecDeque::new(),
+                    },
+                );
+                metadata
+            }
+        };
+
+        self.notify(ChangeEvent::changed(namespace, &key, metadata.version));
+        Ok(metadata)
+    }
+
+    pub fn get_secret(&self, namespace: &Namespace, key: &str) -> Option<String> {
+        self.history(namespace, key)
+            .map(|history| history.current.open())
+    }
+
+    // A specific version together with its own metadata, which may expire
+    // at a different time than the live version
+    pub fn get_secret_version(
+        &self,
+        namespace: &Namespace,
+        key: &str,
+        version: u64,
+    ) -> Option<(String, SecretMetadata)> {
+        self.history(namespace, key)?
+            .find(version, unix_now())
+            .map(|version| (version.open(), version.metadata.clone()))
+    }
+
+    pub fn get_metadata(&self, namespace: &Namespace, key: &str) -> Option<SecretMetadata> {
+        self.history(namespace, key)
+            .map(|history| history.current.metadata.clone())
+    }
+
+    // Metadata for the live version followed by every retained older version
+    pub fn get_history(&self, namespace: &Namespace, key: &str) -> Vec<SecretMetadata> {
+        self.history(namespace, key)
+            .map(|history| {
+                std::iter::once(&history.current)
+                    .chain(history.previous.iter())
+                    .map(|entry| entry.metadata.clone())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    // Re-publishes an older version as the newest one, so the rollback itself
+    // shows up in the history. Without an explicit version the one directly
+    // before the live version is restored. Expired versions cannot be
+    // restored, and the restored copy expires no later than its target.
+    pub fn rollback(
+        &mut self,
+        namespace: &Namespace,
+        key: &str,
+        version: Option<u64>,
+    ) -> Result<SecretMetadata, String> {
+        self.check_open()?;
+        let ttl = self.ttl_for(namespace, None);
+        let now = unix_now();
+        let history = self
+            .namespaces
+            .get_mut(namespace)
+            .and_then(|entries| entries.get_mut(key))
+            .filter(|history| !history.current.metadata.is_expired(now))
+            .ok_or_else(|| format!("Unknown key '{}'", key))?;
+
+        let target = match version {
+            Some(version) => history
+                .previous
+                .iter()
+                .find(|v| v.metadata.version == version),
+            None => history.previous.front(),
+        }
+        .filter(|target| !target.metadata.is_expired(now))
+        .ok_or_else(|| format!("No previous version to roll back '{}' to", key))?;
+
+        let mut metadata =
+            SecretMetadata::new(history.next_version(), SecretOrigin::Rollback).with_ttl(ttl);
+        if let Some(expires_at) = target.metadata.expires_at {
+            metadata.expires_at = Some(
+                metadata
+                    .expires_at
+                    .map_or(expires_at, |at| at.min(expires_at)),
+            );
+        }
+        let restored = SecretVersion::seal(target.open(), metadata.clone())?;
+        history.push(restored, self.history_limit);
+        self.notify(ChangeEvent::changed(namespace, key, metadata.version));
+        Ok(metadata)
+    }
+
+    // Merges a freshly synced set of secrets into a namespace. Changed values
+    // become new versions, unchanged ones are left alone, and keys the source
+    // no longer returns are dropped if they came from a sync in the first
+    // place. Keys written locally, by store_env, import, generate or a
+    // rollback, are kept. Every block is sealed before anything is applied,
+    // so a failure leaves the namespace as it was.
+    pub fn merge_namespace(
+        &mut self,
+        namespace: &Namespace,
+        secrets: HashMap<String, String>,
+        origin: SecretOrigin,
+    ) -> Result<(), String> {
+        self.check_open()?;
+        let ttl = self.ttl_for(namespace, None);
+        let now = unix_now();
+        let existing = self.namespaces.get(namespace);
+        let mut staged = Vec::new();
+        for (key, value) in secrets {
+            let next_version = match existing.and_then(|entries| entries.get(&key)) {
+                Some(history)
+                    if !history.current.metadata.is_expired(now)
+                        && history.current.open() == value =>
+                {
+                    staged.push((key, None));
+                    continue;
+                }
+                Some(history) => history.next_version(),
+                None => 1,
+            };
+
+            let metadata = SecretMetadata::new(next_version, origin).with_ttl(ttl);
+            staged.push((key, Some(SecretVersion::seal(value, metadata)?)));
+        }
+
+        let mut entries = self.namespaces.remove(namespace).unwrap_or_default();
+        let mut updated = HashMap::new();
+        let mut events = Vec::new();
+        for (key, version) in staged {
+            if let Some(version) = &version {
+                events.push(ChangeEvent::changed(
+                    namespace,
+                    &key,
+                    version.metadata.version,
+                ));
+            }
+            match (entries.remove(&key), version) {
+                (Some(mut history), Some(version)) => {
+                    history.push(version, self.history_limit);
+                    updated.insert(key, history);
+                }
+                (Some(history), None) => {
+                    updated.insert(key, history);
+                }
+                (None, Some(current)) => {
+                    updated.insert(
+                        key,
+                        SecretHistory {
+                            current,
+                            previous: VecDeque::new(),
+                        },
+                    );
+                }
+                (None, None) => {}
+            }
+        }
+
+        // Whatever is left was not returned by the source any more
+        for (key, history) in entries {
+            match history.current.metadata.origin {
+                SecretOrigin::Backend | SecretOrigin::Snapshot => {
+                    events.push(ChangeEvent::deleted(namespace, &key));
+                }
+                _ => {
+                    updated.insert(key, history);
+                }
+            }
+        }
+
+        self.namespaces.insert(namespace.clone(), updated);
+        for event in events {
+            self.notify(event);
+        }
         Ok(())
     }
 
-    pub fn get_secret(&self, key: &str) -> Option<String> {
-        self.blocks.get(key).and_then(|block| {
-            self.keys.get(key).map(|encryption_key| {
-                let encrypted_data = block.read();
-                let decrypted_data = Self::decrypt(&encrypted_data, encryption_key);
-                String::from_utf8(decrypted_data).unwrap_or_default()
+    pub fn get_namespace(&self, namespace: &Namespace) -> HashMap<String, String> {
+        let now = unix_now();
+
+        self.namespaces
+            .get(namespace)
+            .map(|entries| {
+                entries
+                    .iter()
+                    .filter(|(_, history)| !history.current.metadata.is_expired(now))
+                    .map(|(key, history)| (key.clone(), history.current.open()))
+                    .collect()
             })
+            .unwrap_or_default()
+    }
+
+    // Number of live keys, without opening any values
+    pub fn key_count(&self, namespace: &Namespace) -> usize {
+        let now = unix_now();
+        self.namespaces.get(namespace).map_or(0, |entries| {
+            entries
+                .values()
+                .filter(|history| !history.current.metadata.is_expired(now))
+                .count()
         })
     }
 
+    // Secure blocks currently mapped and their total size in bytes, counting
+    // retained versions as well as live ones
+    pub fn memory_usage(&self) -> (usize, usize) {
+        self.namespaces
+            .values()
+            .flat_map(|entries| entries.values())
+            .flat_map(|history| std::iter::once(&history.current).chain(history.previous.iter()))
+            .fold((0, 0), |(blocks, bytes), version| {
+                (blocks + 1, bytes + version.block.size)
+            })
+    }
+
+    pub fn contains(&self, namespace: &Namespace, key: &str) -> bool {
+        self.history(namespace, key).is_some()
+    }
+
+    // Removes a key and all of its versions. Dropping the versions wipes and
+    // unmaps their blocks. Returns whether the key was present.
+    pub fn delete_secret(&mut self, namespace: &Namespace, key: &str) -> bool {
+        let deleted = self
+            .namespaces
+            .get_mut(namespace)
+            .and_then(|entries| entries.remove(key))
+            .is_some();
+        if deleted {
+            self.notify(ChangeEvent::deleted(namespace, key));
+        }
+        deleted
+    }
+
+    // Names and metadata of the live keys matching `filter`, sorted by name.
+    // Values are never opened.
+    pub fn list_keys(&self, namespace: &Namespace, filter: &KeyFilter) -> Vec<KeyInfo> {
+        let now = unix_now();
+        let Some(entries) = self.namespaces.get(namespace) else {
+            return Vec::new();
+        };
+
+        let mut keys: Vec<KeyInfo> = entries
+            .iter()
+            .filter(|(key, history)| {
+                !history.current.metadata.is_expired(now) && filter.matches(key)
+            })
+            .map(|(key, history)| KeyInfo {
+                key: key.clone(),
+                metadata: history.current.metadata.clone(),
+                retained_versions: history.previous.len(),
+                value_type: None,
+            })
+            .collect();
+
+        keys.sort_by(|a, b| a.key.cmp(&b.key));
+        keys
+    }
+
+    // Drops every secret whose live version has expired, together with its
+    // history, and every retained version that expired on its own. Dropping
+    // the versions wipes and unmaps their blocks. Returns the number of keys
+    // removed.
+    pub fn purge_expired(&mut self) -> usize {
+        let now = unix_now();
+        let mut events = Vec::new();
+
+        for (namespace, entries) in self.namespaces.iter_mut() {
+            entries.retain(|key, history| {
+                let expired = history.current.metadata.is_expired(now);
+                if expired {
+                    events.push(ChangeEvent::deleted(namespace, key));
+                }
+                history
+                    .previous
+                    .retain(|version| !version.metadata.is_expired(now));
+                !expired
+            });
+        }
+
+        let purged = events.len();
+        for event in events {
+            self.notify(event);
+        }
+
+        purged
+    }
+
+    // Changes whenever any secret is written, rolled back, synced or removed
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    // Delivers an event for every later change. The subscription ends when
+    // the receiver is dropped or falls `SUBSCRIBER_BACKLOG` events behind, or
+    // at once if the store is shutting down.
+    pub fn subscribe(&mut self) -> Receiver<ChangeEvent> {
+        let (sender, receiver) = sync_channel(SUBSCRIBER_BACKLOG);
+        if !self.subscriptions_ended {
+            self.subscribers.push(sender);
+        }
+        receiver
+    }
+
+    // Disconnects every subscriber, now and later, so watch streams finish
+    pub fn end_subscriptions(&mut self) {
+        self.subscriptions_ended = true;
+        self.subscribers.clear();
+    }
+
+    fn notify(&mut self, event: ChangeEvent) {
+        self.generation += 1;
+        // Never blocks: a full backlog drops the subscriber like a closed one
+        self.subscribers
+            .retain(|subscriber| subscriber.try_send(event.clone()).is_ok());
+    }
+
+    pub fn namespaces(&self) -> impl Iterator<Item = &Namespace> {
+        self.namespaces.keys()
+    }
+
+    // Expired secrets are invisible even before the reaper gets to them
+    fn history(&self, namespace: &Namespace, key: &str) -> Option<&SecretHistory> {
+        self.namespaces
+            .get(namespace)?
+            .get(key)
+            .filter(|history| !history.current.metadata.is_expired(unix_now()))
+    }
+
+    // Explicitly zeroes and unmaps every block, live and retained, and closes
+    // the store to new writes. Used on shutdown rather than trusting that
+    // destructors will run. Returns the number of blocks wiped.
+    pub fn wipe(&mut self) -> usize {
+        self.wiped = true;
+        self.end_subscriptions();
+
+        let mut wiped = 0;
+        for (_, entries) in self.namespaces.drain() {
+            for (_, mut history) in entries {
+                for version in
+                    std::iter::once(&mut history.current).chain(history.previous.iter_mut())
+                {
+                    version.wipe();
+                    wiped += 1;
+                }
+            }
+        }
+
+        wiped
+    }
+
+    // Seals every version, live and retained, under a fresh key in a new
+    // block. Everything is resealed before anything is swapped, so a failure
+    // leaves every secret under its old key; afterwards the old blocks and
+    // keys are wiped straight away. Values and versions do not change, so
+    // watchers are not notified. Returns the number of blocks rotated.
+    pub fn rotate_keys(&mut self) -> Result<usize, String> {
+        self.check_open()?;
+
+        let mut staged = Vec::new();
+        for entries in self.namespaces.values() {
+            for history in entries.values() {
+                for version in std::iter::once(&history.current).chain(history.previous.iter()) {
+                    staged.push(version.rekey()?);
+                }
+            }
+        }
+
+        // Walks the maps in the same order as above, since they have not
+        // changed in between
+        let rotated = staged.len();
+        let mut staged = staged.into_iter();
+        for entries in self.namespaces.values_mut() {
+            for history in entries.values_mut() {
+                for version in
+                    std::iter::once(&mut history.current).chain(history.previous.iter_mut())
+                {
+                    if let Some(resealed) = staged.next() {
+                        std::mem::replace(version, resealed).wipe();
+                    }
+                }
+            }
+        }
+
+        self.keys_rotated_at = Some(unix_now());
+        Ok(rotated)
+    }
+
+    pub fn keys_rotated_at(&self) -> Option<u64> {
+        self.keys_rotated_at
+    }
+
+    fn check_open(&self) -> Result<(), String> {
+        if self.wiped {
+            Err("The store has been wiped for shutdown".to_string())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn ttl_for(&self, namespace: &Namespace, ttl: Option<u64>) -> Option<u64> {
+        ttl.or_else(|| self.namespace_ttls.get(namespace).copied())
+    }
+
     pub fn encrypt(data: &[u8], key: &[u8]) -> Vec<u8> {
         data.iter()
             .zip(key.iter().cycle())
@@ -142,3 +682,321 @@ impl SecureStore {
         Self::encrypt(data, key)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::TryRecvError;
+
+    fn namespace() -> Namespace {
+        Namespace::parse("app/dev")
+    }
+
+    fn store(store: &mut SecureStore, key: &str, value: &str) -> SecretMetadata {
+        store
+            .store_secret(
+                &namespace(),
+                key.to_string(),
+                value.to_string(),
+                SecretOrigin::StoreEnv,
+                None,
+            )
+            .unwrap()
+    }
+
+    fn synced(secrets: &[(&str, &str)]) -> HashMap<String, String> {
+        secrets
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn merging_a_sync_drops_only_synced_keys() {
+        let mut secrets = SecureStore::new();
+        let events = secrets.subscribe();
+        secrets
+            .merge_namespace(
+                &namespace(),
+                synced(&[("A", "1"), ("B", "2")]),
+                SecretOrigin::Backend,
+            )
+            .unwrap();
+        store(&mut secrets, "LOCAL", "mine");
+
+        secrets
+            .merge_namespace(&namespace(), synced(&[("A", "1")]), SecretOrigin::Backend)
+            .unwrap();
+
+        assert_eq!(secrets.get_secret(&namespace(), "A").as_deref(), Some("1"));
+        assert_eq!(secrets.get_metadata(&namespace(), "A").unwrap().version, 1);
+        assert!(!secrets.contains(&namespace(), "B"));
+        assert_eq!(
+            secrets.get_secret(&namespace(), "LOCAL").as_deref(),
+            Some("mine")
+        );
+
+        let deleted: Vec<ChangeEvent> = events.try_iter().filter(|e| e.deleted).collect();
+        assert_eq!(deleted.len(), 1);
+        assert_eq!(deleted[0].key, "B");
+    }
+
+    fn store_with_ttl(secrets: &mut SecureStore, key: &str, value: &str, ttl: u64) {
+        secrets
+            .store_secret(
+                &namespace(),
+                key.to_string(),
+                value.to_string(),
+                SecretOrigin::StoreEnv,
+                Some(ttl),
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn expired_secrets_are_hidden_and_purged() {
+        let mut secrets = SecureStore::new();
+        let events = secrets.subscribe();
+        // A TTL of zero has run out as soon as it is stored
+        store_with_ttl(&mut secrets, "GONE", "x", 0);
+        store_with_ttl(&mut secrets, "LIVE", "y", 3600);
+
+        assert_eq!(secrets.get_secret(&namespace(), "GONE"), None);
+        assert!(!secrets.contains(&namespace(), "GONE"));
+        assert_eq!(secrets.key_count(&namespace()), 1);
+        assert_eq!(secrets.memory_usage().0, 2);
+
+        assert_eq!(secrets.purge_expired(), 1);
+        assert_eq!(secrets.memory_usage().0, 1);
+        assert_eq!(
+            secrets.get_secret(&namespace(), "LIVE").as_deref(),
+            Some("y")
+        );
+        let deleted: Vec<String> = events
+            .try_iter()
+            .filter(|event| event.deleted)
+            .map(|event| event.key)
+            .collect();
+        assert_eq!(deleted, ["GONE"]);
+    }
+
+    #[test]
+    fn retained_versions_expire_on_their_own() {
+        let mut secrets = SecureStore::new();
+        store_with_ttl(&mut secrets, "KEY", "old", 0);
+        store(&mut secrets, "KEY", "new");
+
+        assert!(secrets.get_secret_version(&namespace(), "KEY", 1).is_none());
+        assert!(secrets.rollback(&namespace(), "KEY", Some(1)).is_err());
+        assert_eq!(secrets.purge_expired(), 0);
+        assert_eq!(secrets.get_history(&namespace(), "KEY").len(), 1);
+    }
+
+    #[test]
+    fn a_pinned_version_carries_its_own_expiry() {
+        let mut secrets = SecureStore::new();
+        store_with_ttl(&mut secrets, "KEY", "old", 3600);
+        store(&mut secrets, "KEY", "new");
+
+        let (value, metadata) = secrets.get_secret_version(&namespace(), "KEY", 1).unwrap();
+        assert_eq!(value, "old");
+        assert!(metadata.expires_at.is_some());
+
+        let (value, metadata) = secrets.get_secret_version(&namespace(), "KEY", 2).unwrap();
+        assert_eq!(value, "new");
+        assert_eq!(metadata.expires_at, None);
+    }
+
+    #[test]
+    fn history_keeps_the_newest_versions_up_to_the_limit() {
+        let mut secrets = SecureStore::new();
+        for i in 1..=8 {
+            let metadata = store(&mut secrets, "KEY", &format!("v{}", i));
+            assert_eq!(metadata.version, i);
+        }
+
+        let versions: Vec<u64> = secrets
+            .get_history(&namespace(), "KEY")
+            .iter()
+            .map(|metadata| metadata.version)
+            .collect();
+        assert_eq!(versions, [8, 7, 6, 5, 4, 3]);
+        assert_eq!(versions.len(), DEFAULT_HISTORY_LIMIT + 1);
+
+        let mut short = SecureStore::with_history_limit(1);
+        for i in 1..=3 {
+            store(&mut short, "KEY", &format!("v{}", i));
+        }
+        assert_eq!(short.get_history(&namespace(), "KEY").len(), 2);
+    }
+
+    #[test]
+    fn specific_versions_can_be_fetched_while_retained() {
+        let mut secrets = SecureStore::new();
+        for i in 1..=7 {
+            store(&mut secrets, "KEY", &format!("v{}", i));
+        }
+
+        for version in 2..=7 {
+            let (value, metadata) = secrets
+                .get_secret_version(&namespace(), "KEY", version)
+                .unwrap();
+            assert_eq!(value, format!("v{}", version));
+            assert_eq!(metadata.version, version);
+        }
+        // Dropped past the limit, or never written
+        assert!(secrets.get_secret_version(&namespace(), "KEY", 1).is_none());
+        assert!(secrets.get_secret_version(&namespace(), "KEY", 8).is_none());
+        assert!(secrets
+            .get_secret_version(&namespace(), "OTHER", 1)
+            .is_none());
+    }
+
+    #[test]
+    fn rollback_republishes_an_older_version() {
+        let mut secrets = SecureStore::new();
+        for value in ["one", "two", "three"] {
+            store(&mut secrets, "KEY", value);
+        }
+
+        let metadata = secrets.rollback(&namespace(), "KEY", None).unwrap();
+        assert_eq!(metadata.version, 4);
+        assert_eq!(metadata.origin, SecretOrigin::Rollback);
+        assert_eq!(
+            secrets.get_secret(&namespace(), "KEY").as_deref(),
+            Some("two")
+        );
+
+        let metadata = secrets.rollback(&namespace(), "KEY", Some(1)).unwrap();
+        assert_eq!(metadata.version, 5);
+        assert_eq!(
+            secrets.get_secret(&namespace(), "KEY").as_deref(),
+            Some("one")
+        );
+        assert_eq!(secrets.get_history(&namespace(), "KEY").len(), 5);
+
+        // The live version is not a rollback target
+        assert!(secrets.rollback(&namespace(), "KEY", Some(5)).is_err());
+        assert!(secrets.rollback(&namespace(), "KEY", Some(42)).is_err());
+        assert!(secrets.rollback(&namespace(), "MISSING", None).is_err());
+
+        store(&mut secrets, "FRESH", "only");
+        assert!(secrets.rollback(&namespace(), "FRESH", None).is_err());
+    }
+
+    #[test]
+    fn deleting_a_key_drops_its_history() {
+        let mut secrets = SecureStore::new();
+        store(&mut secrets, "KEY", "one");
+        store(&mut secrets, "KEY", "two");
+        assert_eq!(secrets.memory_usage().0, 2);
+
+        assert!(secrets.delete_secret(&namespace(), "KEY"));
+        assert!(!secrets.delete_secret(&namespace(), "KEY"));
+        assert!(secrets.get_history(&namespace(), "KEY").is_empty());
+        assert_eq!(secrets.memory_usage().0, 0);
+
+        let metadata = store(&mut secrets, "KEY", "three");
+        assert_eq!(metadata.version, 1);
+    }
+
+    // Every version's encryption key and ciphertext, live and retained
+    fn sealed(secrets: &SecureStore, key: &str) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let history = &secrets.namespaces[&namespace()][key];
+        std::iter::once(&history.current)
+            .chain(history.previous.iter())
+            .map(|version| (version.key.clone(), version.block.read()))
+            .collect()
+    }
+
+    #[test]
+    fn rotation_reseals_every_version_without_changing_values() {
+        let mut secrets = SecureStore::new();
+        for value in ["one", "two", "three"] {
+            store(&mut secrets, "KEY", value);
+        }
+        store(&mut secrets, "OTHER", "solo");
+        let history = secrets.get_history(&namespace(), "KEY");
+        let before = sealed(&secrets, "KEY");
+
+        assert_eq!(secrets.keys_rotated_at(), None);
+        assert_eq!(secrets.rotate_keys().unwrap(), 4);
+        assert!(secrets.keys_rotated_at().is_some());
+
+        let after = sealed(&secrets, "KEY");
+        assert_eq!(after.len(), 3);
+        for ((old_key, old_block), (new_key, new_block)) in before.iter().zip(&after) {
+            assert_ne!(old_key, new_key);
+            assert_ne!(old_block, new_block);
+        }
+
+        assert_eq!(
+            secrets.get_secret(&namespace(), "KEY").as_deref(),
+            Some("three")
+        );
+        assert_eq!(
+            secrets.get_secret(&namespace(), "OTHER").as_deref(),
+            Some("solo")
+        );
+        for (version, value) in [(1, "one"), (2, "two")] {
+            let (found, _) = secrets
+                .get_secret_version(&namespace(), "KEY", version)
+                .unwrap();
+            assert_eq!(found, value);
+        }
+        let versions = |history: Vec<SecretMetadata>| -> Vec<(u64, u64)> {
+            history
+                .iter()
+                .map(|metadata| (metadata.version, metadata.created_at))
+                .collect()
+        };
+        assert_eq!(
+            versions(secrets.get_history(&namespace(), "KEY")),
+            versions(history)
+        );
+    }
+
+    #[test]
+    fn rotation_does_not_notify_watchers() {
+        let mut secrets = SecureStore::new();
+        store(&mut secrets, "KEY", "value");
+        let events = secrets.subscribe();
+        let generation = secrets.generation();
+
+        secrets.rotate_keys().unwrap();
+        assert_eq!(events.try_iter().count(), 0);
+        assert_eq!(secrets.generation(), generation);
+    }
+
+    #[test]
+    fn subscribers_that_fall_behind_are_dropped() {
+        let mut secrets = SecureStore::new();
+        let reader = secrets.subscribe();
+        let stalled = secrets.subscribe();
+
+        for i in 0..=SUBSCRIBER_BACKLOG {
+            store(&mut secrets, "KEY", &i.to_string());
+            assert!(reader.try_recv().is_ok());
+        }
+
+        assert_eq!(stalled.try_iter().count(), SUBSCRIBER_BACKLOG);
+        assert!(matches!(
+            stalled.try_recv(),
+            Err(TryRecvError::Disconnected)
+        ));
+        store(&mut secrets, "KEY", "more");
+        assert!(reader.try_recv().is_ok());
+    }
+
+    #[test]
+    fn ending_subscriptions_disconnects_current_and_later_watchers() {
+        let mut secrets = SecureStore::new();
+        let before = secrets.subscribe();
+        secrets.end_subscriptions();
+        let after = secrets.subscribe();
+
+        store(&mut secrets, "KEY", "value");
+        assert!(matches!(before.try_recv(), Err(TryRecvError::Disconnected)));
+        assert!(matches!(after.try_recv(), Err(TryRecvError::Disconnected)));
+    }
+}
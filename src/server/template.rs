@@ -0,0 +1,302 @@
+use libc::{c_int, pid_t};
+use log::{error, info, warn};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+
+use crate::types::namespace::Namespace;
+
+const TMPFS_MAGIC: i64 = 0x0102_1994;
+const RAMFS_MAGIC: i64 = 0x8584_58f6;
+
+// A process to poke once a template has been re-rendered
+#[derive(Clone, Debug)]
+pub enum SignalTarget {
+    Pid(pid_t, c_int),
+    // Read the pid from a file on every render, for services that restart
+    PidFile(PathBuf, c_int),
+}
+
+impl SignalTarget {
+    // Accepts `PID:SIGNAL` or `PIDFILE:SIGNAL`, e.g. `/run/app.pid:HUP`
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let (target, signal) = value
+            .rsplit_once(':')
+            .ok_or_else(|| format!("'{}' should look like PID:SIGNAL", value))?;
+        let signal = parse_signal(signal)?;
+
+        match target.parse::<pid_t>() {
+            Ok(pid) => Ok(SignalTarget::Pid(pid, signal)),
+            Err(_) => Ok(SignalTarget::PidFile(PathBuf::from(target), signal)),
+        }
+    }
+
+    pub fn send(&self) -> Result<(), String> {
+        let (pid, signal) = match self {
+            SignalTarget::Pid(pid, signal) => (*pid, *signal),
+            SignalTarget::PidFile(path, signal) => {
+                let pid = fs::read_to_string(path)
+                    .map_err(|e| format!("{}: {}", path.display(), e))?
+                    .trim()
+                    .parse::<pid_t>()
+                    .map_err(|_| format!("{} does not hold a pid", path.display()))?;
+                (pid, *signal)
+            }
+        };
+
+        if unsafe { libc::kill(pid, signal) } == -1 {
+            return Err(std::io::Error::last_os_error().to_string());
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_signal(name: &str) -> Result<c_int, String> {
+    match name.trim_start_matches("SIG") {
+        "HUP" => Ok(libc::SIGHUP),
+        "INT" => Ok(libc::SIGINT),
+        "TERM" => Ok(libc::SIGTERM),
+        "USR1" => Ok(libc::SIGUSR1),
+        "USR2" => Ok(libc::SIGUSR2),
+        other => other
+            .parse()
+            .map_err(|_| format!("Unknown signal '{}'", name)),
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TemplateConfig {
+    pub source: PathBuf,
+    pub destination: PathBuf,
+    pub namespace: Namespace,
+    pub signal: Option<SignalTarget>,
+}
+
+impl TemplateConfig {
+    pub fn new(
+        source: impl Into<PathBuf>,
+        destination: impl Into<PathBuf>,
+        namespace: Namespace,
+    ) -> Self {
+        TemplateConfig {
+            source: source.into(),
+            destination: destination.into(),
+            namespace,
+            signal: None,
+        }
+    }
+
+    pub fn with_signal(mut self, signal: SignalTarget) -> Self {
+        self.signal = Some(signal);
+        self
+    }
+}
+
+// Names referenced by `{{ secret "NAME" }}` placeholders, in order of
+// first appearance
+pub fn placeholders(template: &str) -> Result<Vec<String>, String> {
+    let mut names = Vec::new();
+    for name in parse(template)?.into_iter().filter_map(|part| match part {
+        Part::Secret(name) => Some(name),
+        Part::Text(_) => None,
+    }) {
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    Ok(names)
+}
+
+// Fills every placeholder from `lookup`. A missing secret is an error rather
+// than an empty string, so a half-rendered config is never written.
+pub fn render(template: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<String, String> {
+    let mut rendered = String::with_capacity(template.len());
+    for part in parse(template)? {
+        match part {
+            Part::Text(text) => rendered.push_str(text),
+            Part::Secret(name) => match lookup(&name) {
+                Some(value) => rendered.push_str(&value),
+                None => return Err(format!("Secret '{}' is not available", name)),
+            },
+        }
+    }
+    Ok(rendered)
+}
+
+enum Part<'a> {
+    Text(&'a str),
+    Secret(String),
+}
+
+fn parse(template: &str) -> Result<Vec<Part<'_>>, String> {
+    let mut parts = Vec::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        parts.push(Part::Text(&rest[..start]));
+        let after = &rest[start + 2..];
+        let end = after.find("}}").ok_or("Unterminated '{{' in template")?;
+        parts.push(Part::Secret(parse_placeholder(after[..end].trim())?));
+        rest = &after[end + 2..];
+    }
+    parts.push(Part::Text(rest));
+
+    Ok(parts)
+}
+
+fn parse_placeholder(expression: &str) -> Result<String, String> {
+    expression
+        .strip_prefix("secret")
+        .map(str::trim)
+        .and_then(|name| name.strip_prefix('"'))
+        .and_then(|name| name.strip_suffix('"'))
+        .filter(|name| !name.is_empty() && !name.contains('"'))
+        .map(str::to_string)
+        .ok_or_else(|| format!("Unsupported placeholder '{{{{ {} }}}}'", expression))
+}
+
+// Writes rendered output where it cannot outlive a reboot: either into an
+// existing FIFO, which a reader drains, or as a 0600 file on tmpfs/ramfs.
+// Anything else is refused rather than leaving plaintext on disk.
+pub fn write_output(destination: &Path, content: &[u8]) -> Result<(), String> {
+    if let Ok(metadata) = fs::metadata(destination) {
+        if metadata.file_type().is_fifo() {
+            let mut fifo = OpenOptions::new()
+                .write(true)
+                .open(destination)
+                .map_err(|e| format!("{}: {}", destination.display(), e))?;
+            return fifo
+                .write_all(content)
+                .map_err(|e| format!("{}: {}", destination.display(), e));
+        }
+    }
+
+    let directory = destination
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    if !is_memory_backed(directory)? {
+        return Err(format!(
+            "{} is not on tmpfs; refusing to write secrets to disk",
+            directory.display()
+        ));
+    }
+
+    let tmp_path = destination.with_extension("tmp");
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp_path)
+        .map_err(|e| format!("{}: {}", tmp_path.display(), e))?;
+    file.write_all(content)
+        .map_err(|e| format!("{}: {}", tmp_path.display(), e))?;
+    fs::rename(&tmp_path, destination).map_err(|e| format!("{}: {}", destination.display(), e))
+}
+
+fn is_memory_backed(directory: &Path) -> Result<bool, String> {
+    let path = CString::new(directory.as_os_str().as_encoded_bytes())
+        .map_err(|_| format!("Invalid path {}", directory.display()))?;
+    let mut stats: libc::statfs = unsafe { std::mem::zeroed() };
+
+    if unsafe { libc::statfs(path.as_ptr(), &mut stats) } == -1 {
+        return Err(format!(
+            "{}: {}",
+            directory.display(),
+            std::io::Error::last_os_error()
+        ));
+    }
+
+    #[allow(clippy::unnecessary_cast)]
+    let fs_type = stats.f_type as i64;
+    Ok(fs_type == TMPFS_MAGIC || fs_type == RAMFS_MAGIC)
+}
+
+// Re-renders templates when their inputs change. Only a digest of the last
+// output is kept, so the rendered plaintext does not linger in memory.
+#[derive(Default)]
+pub struct TemplateRenderer {
+    templates: Vec<TemplateConfig>,
+    digests: HashMap<PathBuf, Vec<u8>>,
+}
+
+impl TemplateRenderer {
+    pub fn new(templates: Vec<TemplateConfig>) -> Self {
+        TemplateRenderer {
+            templates,
+            digests: HashMap::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.templates.is_empty()
+    }
+
+    // Namespaces the templates draw from
+    pub fn namespaces(&self) -> Vec<Namespace> {
+        let mut namespaces: Vec<Namespace> = self
+            .templates
+            .iter()
+            .map(|template| template.namespace.clone())
+            .collect();
+        namespaces.sort();
+        namespaces.dedup();
+        namespaces
+    }
+
+    // Renders every template whose output would differ from what was last
+    // written, then signals its process if one is configured. Writing into a
+    // FIFO blocks until something opens it for reading.
+    pub fn refresh(&mut self, lookup: impl Fn(&Namespace, &str) -> Option<String>) {
+        for template in &self.templates {
+            let source = match fs::read_to_string(&template.source) {
+                Ok(source) => source,
+                Err(e) => {
+                    error!("Template {}: {}", template.source.display(), e);
+                    continue;
+                }
+            };
+
+            let rendered = match render(&source, |name| lookup(&template.namespace, name)) {
+                Ok(rendered) => rendered,
+                Err(e) => {
+                    warn!("Template {}: {}", template.source.display(), e);
+                    continue;
+                }
+            };
+
+            let mut rendered = rendered.into_bytes();
+            let digest = Sha256::digest(&rendered).to_vec();
+            if self.digests.get(&template.destination) == Some(&digest) {
+                rendered.fill(0);
+                continue;
+            }
+
+            let result = write_output(&template.destination, &rendered);
+            rendered.fill(0);
+            if let Err(e) = result {
+                error!("Template {}: {}", template.source.display(), e);
+                continue;
+            }
+
+            info!("Rendered {}", template.destination.display());
+            self.digests.insert(template.destination.clone(), digest);
+
+            if let Some(signal) = &template.signal {
+                if let Err(e) = signal.send() {
+                    error!(
+                        "Failed to signal after {}: {}",
+                        template.destination.display(),
+                        e
+                    );
+                }
+            }
+        }
+    }
+}
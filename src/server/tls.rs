@@ -0,0 +1,440 @@
+use log::{error, info};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConfig, RootCertStore, ServerConfig, ServerConnection, StreamOwned};
+use std::io::{BufReader, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::server::lifecycle::{set_timeouts, stopped, Inflight, StopSignal};
+use crate::server::policy::Principal;
+use crate::server::rate_limit::Limit;
+use crate::server::server::{Connection, SecretsServer};
+
+// A listener for clients off this host. It speaks the same requests as the
+// TCP port, with TLS in place of the key exchange, and only accepts clients
+// presenting a certificate signed by `client_ca`. The certificate's common
+// name becomes the caller, so policies refer to clients as `cert:NAME`.
+//
+// The port may face untrusted networks, so each connection gets
+// `timeout_secs` for every read and write, the handshake included, and
+// connections past `max_connections` are turned away.
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    pub addr: SocketAddr,
+    pub certificate: PathBuf,
+    pub key: PathBuf,
+    pub client_ca: PathBuf,
+    pub timeout_secs: u64,
+    pub max_connections: usize,
+}
+
+impl TlsConfig {
+    pub fn new(
+        addr: SocketAddr,
+        certificate: impl Into<PathBuf>,
+        key: impl Into<PathBuf>,
+        client_ca: impl Into<PathBuf>,
+    ) -> Self {
+        TlsConfig {
+            addr,
+            certificate: certificate.into(),
+            key: key.into(),
+            client_ca: client_ca.into(),
+            timeout_secs: 30,
+            max_connections: 256,
+        }
+    }
+
+    pub fn with_timeout_secs(mut self, timeout_secs: u64) -> Self {
+        self.timeout_secs = timeout_secs;
+        self
+    }
+
+    pub fn with_max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = max_connections;
+        self
+    }
+
+    pub fn server_config(&self) -> Result<Arc<ServerConfig>, String> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let roots = Arc::new(load_roots(&self.client_ca)?);
+        let verifier = WebPkiClientVerifier::builder_with_provider(roots, provider.clone())
+            .build()
+            .map_err(|e| format!("{}: {}", self.client_ca.display(), e))?;
+
+        ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(|e| e.to_string())?
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(load_certificates(&self.certificate)?, load_key(&self.key)?)
+            .map_err(|e| format!("{}: {}", self.certificate.display(), e))
+            .map(Arc::new)
+    }
+}
+
+// Builds what a client needs to reach the TLS listener: the CA that signed
+// the server's certificate, and the client's own certificate and key
+pub fn client_config(ca: &Path, certificate: &Path, key: &Path) -> Result<ClientConfig, String> {
+    ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?
+        .with_root_certificates(load_roots(ca)?)
+        .with_client_auth_cert(load_certificates(certificate)?, load_key(key)?)
+        .map_err(|e| format!("{}: {}", certificate.display(), e))
+}
+
+fn load_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>, String> {
+    let file = std::fs::File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let certificates = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    if certificates.is_empty() {
+        return Err(format!("{} holds no certificates", path.display()));
+    }
+    Ok(certificates)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>, String> {
+    let file = std::fs::File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .map_err(|e| format!("{}: {}", path.display(), e))?
+        .ok_or_else(|| format!("{} holds no private key", path.display()))
+}
+
+fn load_roots(path: &Path) -> Result<RootCertStore, String> {
+    let mut roots = RootCertStore::empty();
+    for certificate in load_certificates(path)? {
+        roots
+            .add(certificate)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+    }
+    Ok(roots)
+}
+
+// The caller a verified client certificate stands for
+fn certificate_principal(certificate: &CertificateDer) -> Result<Principal, String> {
+    let (_, certificate) = x509_parser::parse_x509_certificate(certificate)
+        .map_err(|e| format!("Unreadable client certificate: {}", e))?;
+    let name = certificate
+        .subject()
+        .iter_common_name()
+        .next()
+        .and_then(|name| name.as_str().ok())
+        .filter(|name| !name.is_empty())
+        .map(str::to_string);
+    name.map(Principal::Cert)
+        .ok_or_else(|| "Client certificate has no common name".to_string())
+}
+
+// Completes the TLS handshake and returns the caller the client's
+// certificate stands for
+fn tls_handshake(
+    connection: &mut ServerConnection,
+    stream: &mut TcpStream,
+) -> std::io::Result<Principal> {
+    while connection.is_handshaking() {
+        connection.complete_io(stream)?;
+    }
+
+    let certificate = connection
+        .peer_certificates()
+        .and_then(|certificates| certificates.first())
+        .ok_or_else(|| std::io::Error::other("Client sent no certificate"))?;
+    certificate_principal(certificate).map_err(std::io::Error::other)
+}
+
+impl Connection for StreamOwned<ServerConnection, TcpStream> {
+    fn close_write(&mut self) -> std::io::Result<()> {
+        self.conn.send_close_notify();
+        self.flush()?;
+        self.sock.shutdown(std::net::Shutdown::Write)
+    }
+
+    fn hold_open(&mut self, write_timeout: Duration) -> std::io::Result<()> {
+        self.sock.set_read_timeout(None)?;
+        self.sock.set_write_timeout(Some(write_timeout))
+    }
+}
+
+impl SecretsServer {
+    pub fn with_tls(mut self, config: TlsConfig) -> Self {
+        self.tls = Some(config);
+        self
+    }
+
+    pub async fn serve_tls(
+        self: Arc<Self>,
+        config: TlsConfig,
+        mut stop: StopSignal,
+    ) -> std::io::Result<()> {
+        let tls = config.server_config().map_err(std::io::Error::other)?;
+        let listener = tokio::net::TcpListener::bind(config.addr).await?;
+        info!("TLS listener on {}", config.addr);
+        self.add_listener(format!("tls://{}", listener.local_addr()?));
+
+        let mut connections = Inflight::default();
+        let deadline = loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+                deadline = stopped(&mut stop) => break deadline,
+            };
+            let (stream, peer) = match accepted {
+                Ok(connection) => connection,
+                Err(e) => {
+                    error!("Connection failed: {}", e);
+                    continue;
+                }
+            };
+
+            if connections.len() >= config.max_connections {
+                info!(
+                    "Dropped connection from {}: {} TLS connections already open",
+                    peer.ip(),
+                    connections.len()
+                );
+                continue;
+            }
+
+            // Before the TLS handshake, which is the expensive part
+            let caller = match stream.local_addr() {
+                Ok(local) => Principal::tcp_peer(peer, local),
+                Err(_) => Principal::Peer(peer.ip()),
+            };
+            if let Err(e) = self.admit(&caller, Limit::Connection) {
+                info!("Dropped connection: {}", e);
+                continue;
+            }
+
+            let stream = match stream.into_std().and_then(|stream| {
+                stream.set_nonblocking(false)?;
+                set_timeouts(&stream, config.timeout_secs)?;
+                Ok(stream)
+            }) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    error!("Connection failed: {}", e);
+                    continue;
+                }
+            };
+
+            let server = Arc::clone(&self);
+            let tls = Arc::clone(&tls);
+            let runtime = tokio::runtime::Handle::current();
+            connections.spawn_blocking(stream, move |stream| {
+                if let Err(e) = runtime.block_on(server.handle_tls_client(stream, tls, caller)) {
+                    error!("Error handling TLS client: {}", e);
+                }
+            });
+        };
+
+        drop(listener);
+        connections.drain(deadline).await;
+        Ok(())
+    }
+
+    async fn handle_tls_client(
+        &self,
+        mut stream: TcpStream,
+        tls: Arc<ServerConfig>,
+        caller: Principal,
+    ) -> std::io::Result<()> {
+        let peer = stream.peer_addr()?;
+        info!("Handling TLS connection on {}:{}", peer.ip(), peer.port());
+
+        let mut connection = ServerConnection::new(tls).map_err(std::io::Error::other)?;
+        let handshake = tls_handshake(&mut connection, &mut stream);
+
+        // The certificate names the caller from here on
+        let verified = match handshake {
+            Ok(verified) => verified,
+            Err(e) => {
+                self.metrics.record_handshake_failure();
+                self.record_failure(&caller);
+                return Err(e);
+            }
+        };
+
+        self.serve_request(StreamOwned::new(connection, stream), verified, None)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{
+        BasicConstraints, Certificate, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa,
+        KeyPair,
+    };
+    use rustls::pki_types::ServerName;
+    use rustls::ClientConnection;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::time::Instant;
+
+    struct Issued {
+        certificate: Certificate,
+        key: KeyPair,
+    }
+
+    fn authority(name: &str) -> Issued {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.distinguished_name.push(DnType::CommonName, name);
+        Issued {
+            certificate: params.self_signed(&key).unwrap(),
+            key,
+        }
+    }
+
+    fn leaf(name: &str, purpose: ExtendedKeyUsagePurpose, issuer: Option<&Issued>) -> Issued {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+        params.distinguished_name.push(DnType::CommonName, name);
+        params.extended_key_usages = vec![purpose];
+        let certificate = match issuer {
+            Some(issuer) => params
+                .signed_by(&key, &issuer.certificate, &issuer.key)
+                .unwrap(),
+            None => params.self_signed(&key).unwrap(),
+        };
+        Issued { certificate, key }
+    }
+
+    // PEM files for a server whose certificate and trusted client CA both
+    // come from one authority, which also signs the honest client
+    struct Fixture {
+        dir: PathBuf,
+        config: TlsConfig,
+        authority: Issued,
+    }
+
+    impl Fixture {
+        fn new(test: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("tls-{}-{}", test, std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+
+            let authority = authority("test-ca");
+            let server = leaf(
+                "server",
+                ExtendedKeyUsagePurpose::ServerAuth,
+                Some(&authority),
+            );
+            std::fs::write(dir.join("ca.pem"), authority.certificate.pem()).unwrap();
+            std::fs::write(dir.join("server.pem"), server.certificate.pem()).unwrap();
+            std::fs::write(dir.join("server.key"), server.key.serialize_pem()).unwrap();
+
+            let config = TlsConfig::new(
+                "127.0.0.1:0".parse().unwrap(),
+                dir.join("server.pem"),
+                dir.join("server.key"),
+                dir.join("ca.pem"),
+            );
+            Fixture {
+                dir,
+                config,
+                authority,
+            }
+        }
+
+        // A client that trusts the server's CA and presents `identity`, if any
+        fn client(&self, identity: Option<&Issued>) -> ClientConfig {
+            let ca = self.dir.join("ca.pem");
+            let Some(identity) = identity else {
+                return ClientConfig::builder_with_provider(Arc::new(
+                    rustls::crypto::ring::default_provider(),
+                ))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(load_roots(&ca).unwrap())
+                .with_no_client_auth();
+            };
+
+            let certificate = self.dir.join("client.pem");
+            let key = self.dir.join("client.key");
+            std::fs::write(&certificate, identity.certificate.pem()).unwrap();
+            std::fs::write(&key, identity.key.serialize_pem()).unwrap();
+            client_config(&ca, &certificate, &key).unwrap()
+        }
+
+        // Runs the server side of one handshake against `client`
+        fn handshake(&self, client: ClientConfig) -> std::io::Result<Principal> {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let client = std::thread::spawn(move || {
+                let server_name = ServerName::try_from("localhost").unwrap();
+                let connection = ClientConnection::new(Arc::new(client), server_name).unwrap();
+                let mut stream = StreamOwned::new(connection, TcpStream::connect(addr).unwrap());
+                // Drives the handshake; the outcome is judged on the server
+                let _ = stream.read(&mut [0; 1]);
+            });
+
+            let (mut stream, _) = listener.accept().unwrap();
+            set_timeouts(&stream, self.config.timeout_secs).unwrap();
+            let mut connection = ServerConnection::new(self.config.server_config().unwrap())
+                .map_err(std::io::Error::other)?;
+            let result = tls_handshake(&mut connection, &mut stream);
+            drop(stream);
+            client.join().unwrap();
+            result
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[test]
+    fn accepts_a_client_signed_by_the_client_ca() {
+        let fixture = Fixture::new("valid");
+        let identity = leaf(
+            "deploy",
+            ExtendedKeyUsagePurpose::ClientAuth,
+            Some(&fixture.authority),
+        );
+
+        let caller = fixture.handshake(fixture.client(Some(&identity))).unwrap();
+        assert_eq!(caller, Principal::Cert("deploy".to_string()));
+    }
+
+    #[test]
+    fn refuses_a_client_without_a_certificate() {
+        let fixture = Fixture::new("missing");
+        assert!(fixture.handshake(fixture.client(None)).is_err());
+    }
+
+    #[test]
+    fn refuses_a_self_signed_impostor() {
+        let fixture = Fixture::new("impostor");
+        // Same common name as an honest client, but no CA vouches for it
+        let impostor = leaf("deploy", ExtendedKeyUsagePurpose::ClientAuth, None);
+        assert!(fixture.handshake(fixture.client(Some(&impostor))).is_err());
+
+        // Nor does a certificate from another authority
+        let other = authority("other-ca");
+        let stranger = leaf("deploy", ExtendedKeyUsagePurpose::ClientAuth, Some(&other));
+        assert!(fixture.handshake(fixture.client(Some(&stranger))).is_err());
+    }
+
+    #[test]
+    fn gives_up_on_a_client_that_never_speaks() {
+        let fixture = Fixture::new("silent");
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _silent = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+
+        let (mut stream, _) = listener.accept().unwrap();
+        set_timeouts(&stream, 1).unwrap();
+        let mut connection =
+            ServerConnection::new(fixture.config.server_config().unwrap()).unwrap();
+        let started = Instant::now();
+        assert!(tls_handshake(&mut connection, &mut stream).is_err());
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
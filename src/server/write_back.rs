@@ -0,0 +1,810 @@
+use log::{error, info};
+use reqwest::{
+    header::{HeaderMap, HeaderValue, AUTHORIZATION},
+    StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::path::PathBuf;
+
+use crate::server::server::SecretsServer;
+use crate::server::snapshot::SnapshotKey;
+use crate::types::metadata::{unix_now, SecretOrigin};
+use crate::types::namespace::Namespace;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WriteMode {
+    // Forward every store_env to the backend before answering the client
+    WriteThrough,
+    // Answer right away and leave forwarding to the background flusher
+    WriteBack,
+}
+
+#[derive(Clone)]
+pub struct WriteBackConfig {
+    pub mode: WriteMode,
+    // Unsent writes are sealed here so they survive a restart
+    pub queue_path: PathBuf,
+    pub key: SnapshotKey,
+    // Seconds between background flush attempts
+    pub flush_interval: u64,
+}
+
+impl WriteBackConfig {
+    pub fn new(mode: WriteMode, queue_path: impl Into<PathBuf>, key: SnapshotKey) -> Self {
+        WriteBackConfig {
+            mode,
+            queue_path: queue_path.into(),
+            key,
+            flush_interval: 30,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncStatus {
+    // The backend holds the same version as the daemon
+    Synced,
+    // A local change is queued for the backend
+    Pending,
+    // The backend moved on since the local change was made; the next sync
+    // from the backend will replace the local value
+    Conflict,
+    // The backend has never seen this key
+    LocalOnly,
+}
+
+// A key with unsent changes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct PendingWrite {
+    // The backend version the change was based on
+    pub base_version: Option<u64>,
+    // Bumped on every enqueue, so a flush can tell whether the key changed
+    // again while its write was in flight
+    pub sequence: u64,
+}
+
+// Tracks which local changes still need to reach the backend. Values are
+// never kept here; they are read from the store when a write is sent.
+#[derive(Default)]
+pub struct WriteQueue {
+    pending: BTreeMap<(Namespace, String), PendingWrite>,
+    conflicts: BTreeMap<(Namespace, String), u64>,
+    backend_versions: HashMap<(Namespace, String), u64>,
+    next_sequence: u64,
+    // Writes read back from the queue file that could not be put into the
+    // store. They are kept as they were so the file does not lose them.
+    unrestored: Vec<QueuedWrite>,
+}
+
+impl WriteQueue {
+    pub fn new() -> Self {
+        WriteQueue::default()
+    }
+
+    pub fn enqueue(&mut self, namespace: &Namespace, key: String) {
+        let id = (namespace.clone(), key);
+        let base_version = self.backend_versions.get(&id).copied();
+        self.insert_pending(id, base_version);
+    }
+
+    fn insert_pending(&mut self, id: (Namespace, String), base_version: Option<u64>) {
+        self.next_sequence += 1;
+        self.conflicts.remove(&id);
+        self.unrestored
+            .retain(|write| write.namespace != id.0 || write.key != id.1);
+        self.pending.insert(
+            id,
+            PendingWrite {
+                base_version,
+                sequence: self.next_sequence,
+            },
+        );
+    }
+
+    pub fn is_pending(&self, namespace: &Namespace, key: &str) -> bool {
+        self.pending
+            .contains_key(&(namespace.clone(), key.to_string()))
+    }
+
+    // Records the versions reported by a backend fetch. Keys in conflict are
+    // resolved by this, since the fetched value replaces the local one.
+    pub fn record_backend_versions(
+        &mut self,
+        namespace: &Namespace,
+        versions: HashMap<String, u64>,
+    ) {
+        self.conflicts.retain(|(ns, _), _| ns != namespace);
+        for (key, version) in versions {
+            self.backend_versions
+                .insert((namespace.clone(), key), version);
+        }
+    }
+
+    pub fn status(&self, namespace: &Namespace, key: &str) -> SyncStatus {
+        let id = (namespace.clone(), key.to_string());
+        if self.pending.contains_key(&id) {
+            SyncStatus::Pending
+        } else if self.conflicts.contains_key(&id) {
+            SyncStatus::Conflict
+        } else if self.backend_versions.contains_key(&id) {
+            SyncStatus::Synced
+        } else {
+            SyncStatus::LocalOnly
+        }
+    }
+
+    // Every key the queue knows about in `namespace`
+    pub fn keys(&self, namespace: &Namespace) -> Vec<String> {
+        let mut keys: Vec<String> = self
+            .pending
+            .keys()
+            .chain(self.conflicts.keys())
+            .chain(self.backend_versions.keys())
+            .filter(|(ns, _)| ns == namespace)
+            .map(|(_, key)| key.clone())
+            .collect();
+        keys.sort();
+        keys.dedup();
+        keys
+    }
+
+    pub(crate) fn pending_by_namespace(&self) -> BTreeMap<Namespace, Vec<(String, PendingWrite)>> {
+        let mut grouped: BTreeMap<Namespace, Vec<(String, PendingWrite)>> = BTreeMap::new();
+        for ((namespace, key), pending) in &self.pending {
+            grouped
+                .entry(namespace.clone())
+                .or_default()
+                .push((key.clone(), *pending));
+        }
+        grouped
+    }
+
+    // Applies the backend's answer to writes taken from `pending_by_namespace`.
+    // A key enqueued again while its write was in flight stays pending, now
+    // based on the version the backend just reported, so the newer change is
+    // still sent.
+    fn complete(
+        &mut self,
+        namespace: &Namespace,
+        sent: Vec<(String, PendingWrite)>,
+        deleted: &[String],
+        output: &SetKeysOutput,
+    ) {
+        for (key, write) in sent {
+            let id = (namespace.clone(), key);
+            let superseded = self
+                .pending
+                .get(&id)
+                .is_some_and(|pending| pending.sequence != write.sequence);
+
+            if output.conflicts.contains(&id.1) {
+                error!("Write-back conflict on {} in {}", id.1, namespace);
+                if !superseded {
+                    self.pending.remove(&id);
+                    self.conflicts
+                        .insert(id, write.base_version.unwrap_or_default());
+                }
+            } else if let Some(version) = output.versions.get(&id.1) {
+                if let Some(pending) = self.pending.get_mut(&id).filter(|_| superseded) {
+                    pending.base_version = Some(*version);
+                } else {
+                    self.pending.remove(&id);
+                }
+                self.backend_versions.insert(id, *version);
+            } else {
+                let is_delete = deleted.contains(&id.1);
+                match self.pending.get_mut(&id).filter(|_| superseded) {
+                    Some(pending) if is_delete => pending.base_version = None,
+                    Some(_) => {}
+                    None => {
+                        self.pending.remove(&id);
+                    }
+                }
+                if is_delete {
+                    self.backend_versions.remove(&id);
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct QueuedWrite {
+    namespace: Namespace,
+    key: String,
+    // `None` for a key deleted locally
+    #[serde(default)]
+    value: Option<String>,
+    base_version: Option<u64>,
+    // When the queued value expires, so a restart does not extend its life
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct KeyWrite {
+    value: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    base_version: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct SetKeysInput {
+    project_name: String,
+    token: String,
+    environment: String,
+    keys: HashMap<String, KeyWrite>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    deleted: Vec<String>,
+}
+
+#[derive(Default, Deserialize)]
+struct SetKeysOutput {
+    #[serde(default)]
+    versions: HashMap<String, u64>,
+    #[serde(default)]
+    conflicts: Vec<String>,
+}
+
+impl SecretsServer {
+    pub fn with_write_back(mut self, config: WriteBackConfig) -> Self {
+        self.write_back = Some(config);
+        self
+    }
+
+    // Marks keys as changed locally. Called after a store_env or delete_env
+    // has landed in the store; a key missing from the store is sent as a
+    // delete.
+    pub fn queue_writes(&self, namespace: &Namespace, keys: Vec<String>) -> Result<(), String> {
+        if self.write_back.is_none() {
+            return Ok(());
+        }
+
+        let mut queue = self
+            .write_queue
+            .lock()
+            .map_err(|e| format!("Error locking write queue: {}", e))?;
+        for key in keys {
+            queue.enqueue(namespace, key);
+        }
+        drop(queue);
+
+        self.persist_write_queue()
+    }
+
+    // Hands keys a command just changed to write-back: queues them and, in
+    // write-through mode, sends them before the command returns. Every
+    // command that changes the store goes through here. Failures are only
+    // logged; the keys stay queued for the next flush.
+    pub async fn write_back_changes(&self, namespace: &Namespace, keys: Vec<String>) {
+        if let Err(e) = self.queue_writes(namespace, keys) {
+            error!("Failed to queue write-back: {}", e);
+        }
+        if let Some(WriteMode::WriteThrough) = self.write_back.as_ref().map(|c| c.mode) {
+            if let Err(e) = self.flush_writes().await {
+                error!("Failed to write back: {}", e);
+            }
+        }
+    }
+
+    // Sends every pending write to the backend, one request per namespace.
+    // Writes that fail to send stay queued for the next attempt.
+    pub async fn flush_writes(&self) -> Result<(), String> {
+        if self.write_back.is_none() {
+            return Ok(());
+        }
+
+        let grouped = self
+            .write_queue
+            .lock()
+            .map_err(|e| format!("Error locking write queue: {}", e))?
+            .pending_by_namespace();
+
+        for (namespace, keys) in grouped {
+            if let Err(e) = self.flush_namespace(&namespace, keys).await {
+                error!("Failed to write back {}: {}", namespace, e);
+            }
+        }
+
+        self.persist_write_queue()
+    }
+
+    async fn flush_namespace(
+        &self,
+        namespace: &Namespace,
+        keys: Vec<(String, PendingWrite)>,
+    ) -> Result<(), Box<dyn Error>> {
+        let token = self
+            .policy
+            .lock()
+            .map_err(|e| format!("Error locking policy: {}", e))?
+            .token(namespace)
+            .ok_or_else(|| format!("No token registered for {}", namespace))?;
+
+        let mut writes = HashMap::new();
+        let mut deleted = Vec::new();
+        {
+            let store = self
+                .store
+                .read()
+                .map_err(|e| format!("Error locking store: {}", e))?;
+            for (key, write) in &keys {
+                match store.get_secret(namespace, key) {
+                    Some(value) => {
+                        writes.insert(
+                            key.clone(),
+                            KeyWrite {
+                                value,
+                                base_version: write.base_version,
+                            },
+                        );
+                    }
+                    None => deleted.push(key.clone()),
+                }
+            }
+        }
+
+        let output = self
+            .send_writes(SetKeysInput {
+                project_name: namespace.project.clone(),
+                token,
+                environment: namespace.environment.clone(),
+                keys: writes,
+                deleted: deleted.clone(),
+            })
+            .await?;
+
+        self.write_queue
+            .lock()
+            .map_err(|e| format!("Error locking write queue: {}", e))?
+            .complete(namespace, keys, &deleted, &output);
+
+        info!("Wrote back pending changes for {}", namespace);
+        Ok(())
+    }
+
+    async fn send_writes(&self, input: SetKeysInput) -> Result<SetKeysOutput, Box<dyn Error>> {
+        let url = format!("{}/projects/setkeys", self.base_url);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            AUTHORIZATION,
+            HeaderValue::from_str(&format!("Bearer {}", self.token))?,
+        );
+
+        let response = self
+            .client
+            .post(&url)
+            .headers(headers)
+            .json(&input)
+            .send()
+            .await?;
+
+        match response.status() {
+            // A body that cannot be read says nothing about which writes
+            // landed, so they all stay queued
+            StatusCode::OK | StatusCode::CONFLICT => Ok(response.json().await?),
+            _ => {
+                let error_msg: Value = response.json().await?;
+                Err(format!("{:?}", error_msg).into())
+            }
+        }
+    }
+
+    // Seals the unsent writes, with their current values, to the queue file.
+    // Like everywhere else, the store is locked before the queue.
+    pub fn persist_write_queue(&self) -> Result<(), String> {
+        let Some(config) = &self.write_back else {
+            return Ok(());
+        };
+
+        let store = self
+            .store
+            .read()
+            .map_err(|e| format!("Error locking store: {}", e))?;
+        let queue = self
+            .write_queue
+            .lock()
+            .map_err(|e| format!("Error locking write queue: {}", e))?;
+
+        // Unrestored writes go first, so a newer pending one for the same key
+        // wins when the file is read back
+        let writes: Vec<QueuedWrite> = queue
+            .unrestored
+            .iter()
+            .cloned()
+            .chain(queue.pending.iter().map(|((namespace, key), pending)| {
+                QueuedWrite {
+                    namespace: namespace.clone(),
+                    key: key.clone(),
+                    value: store.get_secret(namespace, key),
+                    base_version: pending.base_version,
+                    expires_at: store
+                        .get_metadata(namespace, key)
+                        .and_then(|metadata| metadata.expires_at),
+                }
+            }))
+            .collect();
+        drop(queue);
+        drop(store);
+
+        let mut plaintext = serde_json::to_vec(&writes).map_err(|e| e.to_string())?;
+        let result = config.key.seal_to_file(&config.queue_path, &plaintext);
+        plaintext.fill(0);
+        result
+    }
+
+    // Puts writes left over from a previous run back into the store and the
+    // queue. Only namespaces the daemon serves are restored, and values keep
+    // the expiry they had. A write that cannot be stored does not stop the
+    // rest; it stays in the queue file and its error is returned alongside
+    // the number restored.
+    pub fn restore_write_queue(&self) -> Result<(usize, Vec<String>), String> {
+        let Some(config) = &self.write_back else {
+            return Ok((0, Vec::new()));
+        };
+        if !config.queue_path.exists() {
+            return Ok((0, Vec::new()));
+        }
+
+        let (_, mut plaintext) = config.key.open_file(&config.queue_path)?;
+        let writes = serde_json::from_slice::<Vec<QueuedWrite>>(&plaintext);
+        plaintext.fill(0);
+        let writes = writes.map_err(|e| e.to_string())?;
+
+        let served: Vec<Namespace> = self
+            .policy
+            .lock()
+            .map_err(|e| format!("Error locking policy: {}", e))?
+            .namespaces()
+            .cloned()
+            .collect();
+
+        let mut store = self
+            .store
+            .write()
+            .map_err(|e| format!("Error locking store: {}", e))?;
+        let mut queue = self
+            .write_queue
+            .lock()
+            .map_err(|e| format!("Error locking write queue: {}", e))?;
+
+        let now = unix_now();
+        let mut restored = 0;
+        let mut errors = Vec::new();
+        for write in writes {
+            if !served.contains(&write.namespace) {
+                continue;
+            }
+
+            // A value that expired while the daemon was down is written back
+            // as a delete, as it would have been had the daemon stayed up
+            let value = write
+                .value
+                .clone()
+                .filter(|_| write.expires_at.is_none_or(|at| at > now));
+            let result = match value {
+                Some(value) => store
+                    .store_secret(
+                        &write.namespace,
+                        write.key.clone(),
+                        value,
+                        SecretOrigin::StoreEnv,
+                        write.expires_at.map(|at| at - now),
+                    )
+                    .map(|_| ()),
+                None => {
+                    store.delete_secret(&write.namespace, &write.key);
+                    Ok(())
+                }
+            };
+
+            match result {
+                Ok(()) => {
+                    queue.insert_pending((write.namespace, write.key), write.base_version);
+                    restored += 1;
+                }
+                Err(e) => {
+                    errors.push(format!(
+                        "Failed to restore {} in {}: {}",
+                        write.key, write.namespace, e
+                    ));
+                    queue.unrestored.push(write);
+                }
+            }
+        }
+
+        Ok((restored, errors))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::policy::Principal;
+    use crate::types::format::Format;
+    use crate::types::request::Command;
+
+    // A queue file no earlier run has left behind, sealed with a keyring key
+    // since a passphrase takes PBKDF2 rounds on every open
+    fn config(test: &str) -> WriteBackConfig {
+        let path = |name: &str| {
+            std::env::temp_dir().join(format!("{}-{}-{}", name, test, std::process::id()))
+        };
+        let _ = std::fs::remove_file(path("write-queue"));
+        WriteBackConfig::new(
+            WriteMode::WriteBack,
+            path("write-queue"),
+            SnapshotKey::Keyring(path("write-queue-key")),
+        )
+    }
+
+    fn server(config: &WriteBackConfig) -> SecretsServer {
+        let server =
+            SecretsServer::new(String::new(), String::new()).with_write_back(config.clone());
+        server
+            .policy
+            .lock()
+            .unwrap()
+            .register(Namespace::parse("app/dev"), "token".to_string());
+        server
+    }
+
+    fn store(server: &SecretsServer, key: &str, value: &str, ttl: Option<u64>) {
+        let namespace = Namespace::parse("app/dev");
+        server
+            .store
+            .write()
+            .unwrap()
+            .store_secret(
+                &namespace,
+                key.to_string(),
+                value.to_string(),
+                SecretOrigin::StoreEnv,
+                ttl,
+            )
+            .unwrap();
+        server
+            .queue_writes(&namespace, vec![key.to_string()])
+            .unwrap();
+    }
+
+    fn written(versions: &[(&str, u64)]) -> SetKeysOutput {
+        SetKeysOutput {
+            versions: versions
+                .iter()
+                .map(|(key, version)| (key.to_string(), *version))
+                .collect(),
+            conflicts: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn a_write_enqueued_during_a_flush_stays_pending() {
+        let namespace = Namespace::parse("app/dev");
+        let mut queue = WriteQueue::new();
+        queue.enqueue(&namespace, "DB_PASSWORD".to_string());
+        queue.enqueue(&namespace, "API_KEY".to_string());
+
+        let in_flight = queue.pending_by_namespace().remove(&namespace).unwrap();
+        queue.enqueue(&namespace, "DB_PASSWORD".to_string());
+        queue.complete(
+            &namespace,
+            in_flight,
+            &[],
+            &written(&[("DB_PASSWORD", 7), ("API_KEY", 3)]),
+        );
+
+        assert_eq!(queue.status(&namespace, "API_KEY"), SyncStatus::Synced);
+        assert_eq!(queue.status(&namespace, "DB_PASSWORD"), SyncStatus::Pending);
+        let pending = queue.pending_by_namespace().remove(&namespace).unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].0, "DB_PASSWORD");
+        assert_eq!(pending[0].1.base_version, Some(7));
+
+        queue.complete(&namespace, pending, &[], &written(&[("DB_PASSWORD", 8)]));
+        assert_eq!(queue.status(&namespace, "DB_PASSWORD"), SyncStatus::Synced);
+    }
+
+    #[test]
+    fn a_delete_enqueued_again_during_a_flush_stays_pending() {
+        let namespace = Namespace::parse("app/dev");
+        let mut queue = WriteQueue::new();
+        queue.enqueue(&namespace, "OLD".to_string());
+
+        let in_flight = queue.pending_by_namespace().remove(&namespace).unwrap();
+        queue.enqueue(&namespace, "OLD".to_string());
+        queue.complete(
+            &namespace,
+            in_flight,
+            &["OLD".to_string()],
+            &SetKeysOutput::default(),
+        );
+
+        assert!(queue.is_pending(&namespace, "OLD"));
+    }
+
+    #[test]
+    fn queued_writes_survive_a_restart_with_their_expiry() {
+        let namespace = Namespace::parse("app/dev");
+        let config = config("round-trip");
+        let before = server(&config);
+        store(&before, "DB_PASSWORD", "hunter2", Some(3600));
+        store(&before, "API_KEY", "abc", None);
+        store(&before, "GONE", "x", None);
+        before
+            .store
+            .write()
+            .unwrap()
+            .delete_secret(&namespace, "GONE");
+        before
+            .queue_writes(&namespace, vec!["GONE".to_string()])
+            .unwrap();
+        let expires_at = before
+            .store
+            .read()
+            .unwrap()
+            .get_metadata(&namespace, "DB_PASSWORD")
+            .unwrap()
+            .expires_at;
+
+        let after = server(&config);
+        let (restored, errors) = after.restore_write_queue().unwrap();
+        assert_eq!(restored, 3);
+        assert!(errors.is_empty());
+
+        let store = after.store.read().unwrap();
+        assert_eq!(
+            store.get_secret(&namespace, "DB_PASSWORD").as_deref(),
+            Some("hunter2")
+        );
+        assert_eq!(
+            store
+                .get_metadata(&namespace, "DB_PASSWORD")
+                .unwrap()
+                .expires_at,
+            expires_at
+        );
+        assert_eq!(
+            store.get_secret(&namespace, "API_KEY").as_deref(),
+            Some("abc")
+        );
+        assert_eq!(
+            store
+                .get_metadata(&namespace, "API_KEY")
+                .unwrap()
+                .expires_at,
+            None
+        );
+        assert!(!store.contains(&namespace, "GONE"));
+        drop(store);
+
+        let queue = after.write_queue.lock().unwrap();
+        for key in ["DB_PASSWORD", "API_KEY", "GONE"] {
+            assert!(queue.is_pending(&namespace, key));
+        }
+    }
+
+    #[test]
+    fn a_failed_restore_keeps_going_and_keeps_the_write_queued() {
+        let namespace = Namespace::parse("app/dev");
+        let config = config("partial");
+        let before = server(&config);
+        store(&before, "A", "1", None);
+        store(&before, "B", "2", None);
+
+        // A wiped store refuses every write, standing in for a failed mmap
+        let after = server(&config);
+        after.store.write().unwrap().wipe();
+        let (restored, errors) = after.restore_write_queue().unwrap();
+        assert_eq!(restored, 0);
+        assert_eq!(errors.len(), 2);
+
+        // Persisting again must not turn the unrestored values into deletes
+        after.persist_write_queue().unwrap();
+        let retry = server(&config);
+        let (restored, errors) = retry.restore_write_queue().unwrap();
+        assert_eq!((restored, errors.len()), (2, 0));
+        assert_eq!(
+            retry
+                .store
+                .read()
+                .unwrap()
+                .get_secret(&namespace, "B")
+                .as_deref(),
+            Some("2")
+        );
+    }
+
+    // A backend that answers one setkeys request with `{}`, which marks
+    // every write as landed. Yields the request body it got.
+    fn backend() -> (String, std::thread::JoinHandle<String>) {
+        use std::io::{BufRead, BufReader, Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let handle = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        length = value.trim().parse().unwrap();
+                    }
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            reader
+                .get_mut()
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: 2\r\nconnection: close\r\n\r\n{}")
+                .unwrap();
+            String::from_utf8(body).unwrap()
+        });
+        (url, handle)
+    }
+
+    #[tokio::test]
+    async fn write_through_sends_imports_before_returning() {
+        let namespace = Namespace::parse("app/dev");
+        let mut config = config("import");
+        config.mode = WriteMode::WriteThrough;
+        let (url, backend) = backend();
+        let mut server = server(&config);
+        server.base_url = url;
+
+        let command = Command::Import {
+            format: Format::Dotenv,
+            content: "IMPORTED=1\n".to_string(),
+            ttl: None,
+        };
+        server
+            .execute(&namespace, command, &Principal::Uid(0))
+            .await
+            .unwrap();
+
+        assert!(!server
+            .write_queue
+            .lock()
+            .unwrap()
+            .is_pending(&namespace, "IMPORTED"));
+        // Checked after, since the backend waits for good if nothing is sent
+        assert!(backend.join().unwrap().contains("IMPORTED"));
+    }
+
+    #[tokio::test]
+    async fn a_rollback_is_queued_for_the_backend() {
+        let namespace = Namespace::parse("app/dev");
+        let config = config("rollback");
+        let server = server(&config);
+        store(&server, "KEY", "good", None);
+        store(&server, "KEY", "bad", None);
+        // As if both versions had already reached the backend
+        server.write_queue.lock().unwrap().pending.clear();
+
+        let command = Command::Rollback {
+            key: "KEY".to_string(),
+            version: Some(1),
+        };
+        server
+            .execute(&namespace, command, &Principal::Uid(0))
+            .await
+            .unwrap();
+
+        assert!(server
+            .write_queue
+            .lock()
+            .unwrap()
+            .is_pending(&namespace, "KEY"));
+    }
+}
@@ -0,0 +1,79 @@
+use serde::Deserialize;
+
+// Operations on the daemon itself rather than on a namespace's secrets.
+// They need the admin credential instead of a project token.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum AdminAction {
+    // Fetches every project again, like SIGHUP
+    Reload,
+    // Wipes expired secrets now instead of waiting for the reaper
+    Purge,
+    // Reseals every secret under fresh keys
+    RotateKeys,
+    // Keys and metadata of every namespace, never values
+    DumpMetadata,
+    ShowPolicy,
+    Grant {
+        permission: Permission,
+        principal: String,
+    },
+    Revoke {
+        permission: Permission,
+        principal: String,
+    },
+    Protect {
+        environment: String,
+    },
+    Unprotect {
+        environment: String,
+    },
+    ListClients,
+    RegisterClient {
+        name: String,
+        public_key: String,
+    },
+    RevokeClient {
+        name: String,
+    },
+}
+
+impl AdminAction {
+    pub fn name(&self) -> &'static str {
+        match self {
+            AdminAction::Reload => "admin.reload",
+            AdminAction::Purge => "admin.purge",
+            AdminAction::RotateKeys => "admin.rotate_keys",
+            AdminAction::DumpMetadata => "admin.dump_metadata",
+            AdminAction::ShowPolicy => "admin.show_policy",
+            AdminAction::Grant { .. } => "admin.grant",
+            AdminAction::Revoke { .. } => "admin.revoke",
+            AdminAction::Protect { .. } => "admin.protect",
+            AdminAction::Unprotect { .. } => "admin.unprotect",
+            AdminAction::ListClients => "admin.list_clients",
+            AdminAction::RegisterClient { .. } => "admin.register_client",
+            AdminAction::RevokeClient { .. } => "admin.revoke_client",
+        }
+    }
+}
+
+// What a policy grant lets a caller do
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    // Leave out the project token
+    Trust,
+    Export,
+    // Read namespaces in protected environments
+    Protected,
+}
+
+impl Permission {
+    pub fn name(self) -> &'static str {
+        match self {
+            Permission::Trust => "trust",
+            Permission::Export => "export",
+            Permission::Protected => "protected",
+        }
+    }
+}
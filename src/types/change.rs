@@ -0,0 +1,93 @@
+use serde::{Deserialize, Serialize};
+
+use crate::types::namespace::Namespace;
+
+// Something happened to a key. Values are never included; a watcher that
+// needs the new value fetches it with `get_env`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChangeEvent {
+    pub namespace: Namespace,
+    pub key: String,
+    // The new live version, or `None` once the key is gone
+    pub version: Option<u64>,
+    pub deleted: bool,
+    // Set when `key` is a template that did not change itself, but whose
+    // expansion did: the key it references that changed, as
+    // `PROJECT[/ENV]:KEY`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reference: Option<String>,
+}
+
+impl ChangeEvent {
+    pub fn changed(namespace: &Namespace, key: &str, version: u64) -> Self {
+        ChangeEvent {
+            namespace: namespace.clone(),
+            key: key.to_string(),
+            version: Some(version),
+            deleted: false,
+            reference: None,
+        }
+    }
+
+    // `key`, at `version`, reads the key `cause` is about
+    pub fn referenced(namespace: &Namespace, key: &str, version: u64, cause: &ChangeEvent) -> Self {
+        ChangeEvent {
+            reference: Some(format!("{}:{}", cause.namespace, cause.key)),
+            ..ChangeEvent::changed(namespace, key, version)
+        }
+    }
+
+    pub fn deleted(namespace: &Namespace, key: &str) -> Self {
+        ChangeEvent {
+            namespace: namespace.clone(),
+            key: key.to_string(),
+            version: None,
+            deleted: true,
+            reference: None,
+        }
+    }
+}
+
+// The keys a watcher cares about: exact names and/or prefixes. An empty
+// subscription covers the whole namespace.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Subscription {
+    #[serde(default)]
+    pub keys: Vec<String>,
+    #[serde(default)]
+    pub prefixes: Vec<String>,
+}
+
+impl Subscription {
+    pub fn matches(&self, key: &str) -> bool {
+        (self.keys.is_empty() && self.prefixes.is_empty())
+            || self.keys.iter().any(|k| k == key)
+            || self.prefixes.iter().any(|prefix| key.starts_with(prefix))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subscription(keys: &[&str], prefixes: &[&str]) -> Subscription {
+        Subscription {
+            keys: keys.iter().map(|key| key.to_string()).collect(),
+            prefixes: prefixes.iter().map(|prefix| prefix.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn subscriptions_match_keys_exactly_and_prefixes_by_start() {
+        let watch = subscription(&["API_KEY"], &["DB_"]);
+        assert!(watch.matches("API_KEY"));
+        assert!(!watch.matches("API_KEY_2"));
+        assert!(watch.matches("DB_"));
+        assert!(watch.matches("DB_PASSWORD"));
+        assert!(!watch.matches("MY_DB_PASSWORD"));
+
+        // Subscribing to nothing in particular means everything
+        assert!(subscription(&[], &[]).matches("ANY"));
+        assert!(subscription(&[], &[""]).matches("ANY"));
+    }
+}
@@ -0,0 +1,43 @@
+use serde::Deserialize;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Format {
+    #[serde(alias = "env")]
+    Dotenv,
+    Json,
+    Yaml,
+    // `export KEY='value'` lines, ready for `eval`
+    Shell,
+}
+
+impl Format {
+    pub fn parse_name(name: &str) -> Result<Self, String> {
+        match name {
+            "dotenv" | "env" => Ok(Format::Dotenv),
+            "json" => Ok(Format::Json),
+            "yaml" | "yml" => Ok(Format::Yaml),
+            "shell" | "sh" => Ok(Format::Shell),
+            _ => Err(format!("Unknown format '{}'", name)),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::Dotenv => "dotenv",
+            Format::Json => "json",
+            Format::Yaml => "yaml",
+            Format::Shell => "shell",
+        }
+    }
+
+    // Guesses the format from a file name, falling back to `.env`
+    pub fn from_path(path: &str) -> Self {
+        match path.rsplit_once('.').map(|(_, ext)| ext) {
+            Some("json") => Format::Json,
+            Some("yaml" | "yml") => Format::Yaml,
+            Some("sh") => Format::Shell,
+            _ => Format::Dotenv,
+        }
+    }
+}
@@ -0,0 +1,42 @@
+use serde::Deserialize;
+
+// How `generate` makes a new value. Lengths count characters for
+// alphanumeric and charset values, and random bytes for hex and base64.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "format", rename_all = "snake_case")]
+pub enum Generator {
+    Alphanumeric {
+        #[serde(default = "default_length")]
+        length: usize,
+    },
+    // Characters drawn uniformly from `charset`
+    Charset {
+        #[serde(default = "default_length")]
+        length: usize,
+        charset: String,
+    },
+    Hex {
+        #[serde(default = "default_length")]
+        length: usize,
+    },
+    Base64 {
+        #[serde(default = "default_length")]
+        length: usize,
+    },
+    // A random (version 4) UUID
+    Uuid,
+    // A PKCS#8 PEM private key; the public half is returned to the caller
+    Ed25519,
+    Rsa {
+        #[serde(default = "default_rsa_bits")]
+        bits: usize,
+    },
+}
+
+fn default_length() -> usize {
+    32
+}
+
+fn default_rsa_bits() -> usize {
+    2048
+}
@@ -0,0 +1,9 @@
+use serde::{Deserialize, Serialize};
+
+// Sent with a request by a client holding a registered identity key: its
+// name and a base64 Ed25519 signature over the key exchange transcript
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct IdentityProof {
+    pub name: String,
+    pub signature: String,
+}
@@ -0,0 +1,123 @@
+use serde::Deserialize;
+
+// Narrows `list_keys` down to matching key names
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct KeyFilter {
+    #[serde(default)]
+    pub prefix: Option<String>,
+    // Shell-style pattern where `*` matches any run of characters and `?` exactly one
+    #[serde(default)]
+    pub pattern: Option<String>,
+}
+
+impl KeyFilter {
+    pub fn matches(&self, key: &str) -> bool {
+        let prefix_ok = self
+            .prefix
+            .as_deref()
+            .is_none_or(|prefix| key.starts_with(prefix));
+        let pattern_ok = self
+            .pattern
+            .as_deref()
+            .is_none_or(|pattern| glob_match(pattern.as_bytes(), key.as_bytes()));
+
+        prefix_ok && pattern_ok
+    }
+}
+
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    p = star + 1;
+                    t = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn glob(pattern: &str, text: &str) -> bool {
+        glob_match(pattern.as_bytes(), text.as_bytes())
+    }
+
+    #[test]
+    fn glob_edge_cases() {
+        let cases: &[(&str, &str, bool)] = &[
+            // An empty pattern only matches an empty key
+            ("", "", true),
+            ("", "KEY", false),
+            // Literals match exactly
+            ("DB_PASSWORD", "DB_PASSWORD", true),
+            ("DB_PASSWORD", "DB_PASSWORD2", false),
+            ("DB_PASSWORD", "DB_PASSWOR", false),
+            ("db_password", "DB_PASSWORD", false),
+            // A trailing `*` also matches nothing at all
+            ("DB_*", "DB_", true),
+            ("DB_*", "DB_PASSWORD", true),
+            ("DB_*", "DB", false),
+            ("*", "", true),
+            ("**", "ANYTHING", true),
+            // Leading and inner stars backtrack
+            ("*_URL", "DATABASE_URL", true),
+            ("*_URL", "DATABASE_URLS", false),
+            ("A*B*C", "AXXBYYC", true),
+            ("A*B*C", "AXXCYYB", false),
+            ("*A*A*", "BANANA", true),
+            // `?` takes exactly one character
+            ("KEY_?", "KEY_1", true),
+            ("KEY_?", "KEY_", false),
+            ("KEY_?", "KEY_12", false),
+            ("?*", "", false),
+        ];
+        for (pattern, text, expected) in cases {
+            assert_eq!(
+                glob(pattern, text),
+                *expected,
+                "{:?} on {:?}",
+                pattern,
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn prefix_and_pattern_must_both_match() {
+        let filter = KeyFilter {
+            prefix: Some("DB_".to_string()),
+            pattern: Some("*_URL".to_string()),
+        };
+        assert!(filter.matches("DB_READ_URL"));
+        assert!(!filter.matches("DB_PASSWORD"));
+        assert!(!filter.matches("CACHE_URL"));
+
+        assert!(KeyFilter::default().matches(""));
+        assert!(KeyFilter::default().matches("ANY"));
+
+        let empty_prefix = KeyFilter {
+            prefix: Some(String::new()),
+            pattern: None,
+        };
+        assert!(empty_prefix.matches("ANY"));
+    }
+}
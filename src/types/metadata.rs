@@ -0,0 +1,65 @@
+use serde::{Deserialize, Serialize};
+
+use crate::types::secret_type::SecretType;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Where a stored version came from
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SecretOrigin {
+    Backend,
+    StoreEnv,
+    Rollback,
+    Snapshot,
+    Import,
+    Generated,
+}
+
+// Everything about a secret version except its value
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SecretMetadata {
+    pub version: u64,
+    pub created_at: u64,
+    pub origin: SecretOrigin,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+}
+
+impl SecretMetadata {
+    pub fn new(version: u64, origin: SecretOrigin) -> Self {
+        SecretMetadata {
+            version,
+            created_at: unix_now(),
+            origin,
+            expires_at: None,
+        }
+    }
+
+    pub fn with_ttl(mut self, ttl: Option<u64>) -> Self {
+        self.expires_at = ttl.map(|ttl| self.created_at.saturating_add(ttl));
+        self
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+// A key name and its live metadata, as returned by `list_keys`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct KeyInfo {
+    pub key: String,
+    #[serde(flatten)]
+    pub metadata: SecretMetadata,
+    pub retained_versions: usize,
+    // The declared type, which the store itself does not know about
+    #[serde(rename = "type", default, skip_serializing_if = "Option::is_none")]
+    pub value_type: Option<SecretType>,
+}
@@ -1 +1,11 @@
+pub mod admin;
+pub mod change;
+pub mod format;
+pub mod generate;
+pub mod identity;
+pub mod key_filter;
+pub mod metadata;
+pub mod namespace;
 pub mod protected_secret;
+pub mod request;
+pub mod secret_type;
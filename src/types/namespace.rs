@@ -0,0 +1,41 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+pub const DEFAULT_ENVIRONMENT: &str = "default";
+
+// A project/environment pair that scopes every key held by the store
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Namespace {
+    pub project: String,
+    pub environment: String,
+}
+
+impl Namespace {
+    pub fn new(project: impl Into<String>, environment: impl Into<String>) -> Self {
+        Namespace {
+            project: project.into(),
+            environment: environment.into(),
+        }
+    }
+
+    pub fn project(project: impl Into<String>) -> Self {
+        Self::new(project, DEFAULT_ENVIRONMENT)
+    }
+
+    // Accepts either "project" or "project/environment"
+    pub fn parse(value: &str) -> Self {
+        match value.split_once('/') {
+            Some((project, environment)) if !environment.is_empty() => {
+                Self::new(project, environment)
+            }
+            Some((project, _)) => Self::project(project),
+            None => Self::project(value),
+        }
+    }
+}
+
+impl fmt::Display for Namespace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.project, self.environment)
+    }
+}
@@ -1,4 +1,4 @@
-use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::{Deserialize, Serialize};
 use std::fmt;
 use std::ops::Deref;
 
@@ -15,7 +15,7 @@ impl Deref for ProtectedValue {
 
 impl PartialEq<str> for ProtectedValue {
     fn eq(&self, other: &str) -> bool {
-        &self.0 == other
+        self.0 == *other
     }
 }
 
@@ -34,15 +34,27 @@ impl fmt::Debug for ProtectedValue {
 #[derive(Clone, Deserialize, Serialize)]
 pub struct ProtectedSecret {
     value: Option<ProtectedValue>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>,
 }
 
 impl ProtectedSecret {
     pub fn new(value: Option<String>) -> Self {
         ProtectedSecret {
             value: value.map(ProtectedValue),
+            expires_at: None,
         }
     }
 
+    pub fn with_expiry(mut self, expires_at: Option<u64>) -> Self {
+        self.expires_at = expires_at;
+        self
+    }
+
+    pub fn expires_at(&self) -> Option<u64> {
+        self.expires_at
+    }
+
     pub fn get_value(&self) -> Option<&ProtectedValue> {
         self.value.as_ref()
     }
@@ -71,7 +83,7 @@ impl fmt::Debug for ProtectedSecret {
 
 impl PartialEq<str> for ProtectedSecret {
     fn eq(&self, other: &str) -> bool {
-        self.value.as_ref().map(|v| &**v) == Some(other)
+        self.value.as_deref() == Some(other)
     }
 }
 
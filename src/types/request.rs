@@ -0,0 +1,256 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+
+use crate::types::admin::AdminAction;
+use crate::types::change::Subscription;
+use crate::types::format::Format;
+use crate::types::generate::Generator;
+use crate::types::identity::IdentityProof;
+use crate::types::key_filter::KeyFilter;
+use crate::types::secret_type::SecretType;
+
+#[derive(Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Command {
+    GetEnv {
+        #[serde(default)]
+        keys: Vec<String>,
+        // Pins individual keys to an older version instead of the live one
+        #[serde(default)]
+        versions: HashMap<String, u64>,
+    },
+    StoreEnv {
+        #[serde(default)]
+        secrets: HashMap<String, String>,
+        // Lifetime in seconds for every secret in this request
+        #[serde(default)]
+        ttl: Option<u64>,
+        // Declares the type of some of the keys being written
+        #[serde(default)]
+        types: HashMap<String, SecretType>,
+    },
+    DeleteEnv {
+        #[serde(default)]
+        keys: Vec<String>,
+    },
+    ListKeys {
+        #[serde(flatten)]
+        filter: KeyFilter,
+    },
+    Exists {
+        #[serde(default)]
+        keys: Vec<String>,
+    },
+    Import {
+        format: Format,
+        content: String,
+        #[serde(default)]
+        ttl: Option<u64>,
+    },
+    Export {
+        format: Format,
+        #[serde(flatten)]
+        filter: KeyFilter,
+    },
+    SyncStatus {
+        #[serde(default)]
+        keys: Vec<String>,
+    },
+    History {
+        key: String,
+    },
+    Rollback {
+        key: String,
+        #[serde(default)]
+        version: Option<u64>,
+    },
+    // Creates a random value or key pair server-side; see `Generator`
+    Generate {
+        key: String,
+        #[serde(flatten)]
+        generator: Generator,
+        #[serde(default)]
+        ttl: Option<u64>,
+        // Overwrite the key if it already exists
+        #[serde(default)]
+        replace: bool,
+        // Also send the new value to the backend
+        #[serde(default)]
+        write_back: bool,
+    },
+    // Daemon health and counters. Needs no token, so it can serve as a probe,
+    // but sync state and key counts are only shown to token holders.
+    Status,
+    // Keeps the connection open and streams change events
+    Watch {
+        #[serde(flatten)]
+        subscription: Subscription,
+    },
+    // Daemon operations, authorized by the admin credential rather than a
+    // namespace
+    Admin {
+        #[serde(default)]
+        admin_token: Option<String>,
+        #[serde(flatten)]
+        action: AdminAction,
+    },
+}
+
+impl Command {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::GetEnv { .. } => "get_env",
+            Command::StoreEnv { .. } => "store_env",
+            Command::DeleteEnv { .. } => "delete_env",
+            Command::ListKeys { .. } => "list_keys",
+            Command::Exists { .. } => "exists",
+            Command::Import { .. } => "import",
+            Command::Export { .. } => "export",
+            Command::SyncStatus { .. } => "sync_status",
+            Command::History { .. } => "history",
+            Command::Rollback { .. } => "rollback",
+            Command::Generate { .. } => "generate",
+            Command::Status => "status",
+            Command::Watch { .. } => "watch",
+            Command::Admin { action, .. } => action.name(),
+        }
+    }
+}
+
+// A decoded client request. `namespace` and `token` are optional so that the
+// legacy `["get_env", ...]` array form keeps working against a single-project daemon.
+#[derive(Deserialize)]
+pub struct Request {
+    // Set for the legacy array form, which is the only one that may leave
+    // out the token
+    #[serde(skip)]
+    pub legacy: bool,
+    #[serde(default)]
+    pub namespace: Option<String>,
+    #[serde(default)]
+    pub environment: Option<String>,
+    #[serde(default)]
+    pub token: Option<String>,
+    #[serde(default)]
+    pub identity: Option<IdentityProof>,
+    #[serde(flatten)]
+    pub command: Command,
+}
+
+impl Request {
+    pub fn parse(data: &[u8]) -> Result<Self, String> {
+        if let Ok(request) = serde_json::from_slice::<Request>(data) {
+            return Ok(request);
+        }
+
+        let commands: Vec<String> =
+            serde_json::from_slice(data).map_err(|_| "Invalid request".to_string())?;
+
+        Self::from_legacy(&commands)
+    }
+
+    fn from_legacy(commands: &[String]) -> Result<Self, String> {
+        let command = match commands.first().map(String::as_str) {
+            Some("get_env") => Command::GetEnv {
+                keys: commands[1..].to_vec(),
+                versions: HashMap::new(),
+            },
+            Some("store_env") => {
+                let secrets = match commands.get(1) {
+                    Some(data) => serde_json::from_str(data)
+                        .map_err(|_| "Invalid secrets data".to_string())?,
+                    None => HashMap::new(),
+                };
+                Command::StoreEnv {
+                    secrets,
+                    ttl: None,
+                    types: HashMap::new(),
+                }
+            }
+            Some("delete_env") => Command::DeleteEnv {
+                keys: commands[1..].to_vec(),
+            },
+            Some("list_keys") => Command::ListKeys {
+                filter: KeyFilter {
+                    prefix: commands.get(1).cloned(),
+                    pattern: None,
+                },
+            },
+            Some("exists") => Command::Exists {
+                keys: commands[1..].to_vec(),
+            },
+            Some("status") => Command::Status,
+            _ => return Err("Invalid command".to_string()),
+        };
+
+        Ok(Request {
+            legacy: true,
+            namespace: None,
+            environment: None,
+            token: None,
+            identity: None,
+            command,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    const VALID: &[&str] = &[
+        r#"{"command":"get_env","keys":["A","B"],"versions":{"A":2}}"#,
+        r#"{"command":"store_env","secrets":{"A":"1"},"ttl":60,"types":{"A":"int"}}"#,
+        r#"{"command":"generate","key":"K","format":"charset","charset":"ab","length":8}"#,
+        r#"{"command":"admin","action":"grant","permission":"trust","principal":"uid:0"}"#,
+        r#"["store_env","{\"A\":\"1\"}"]"#,
+        r#"["get_env","A"]"#,
+    ];
+
+    #[test]
+    fn valid_requests_parse() {
+        for request in VALID {
+            assert!(Request::parse(request.as_bytes()).is_ok(), "{}", request);
+        }
+    }
+
+    #[test]
+    fn random_bytes_never_panic() {
+        let mut rng = StdRng::seed_from_u64(41);
+        for _ in 0..5000 {
+            let data: Vec<u8> = (0..rng.gen_range(0..512)).map(|_| rng.gen()).collect();
+            let _ = Request::parse(&data);
+        }
+    }
+
+    #[test]
+    fn truncated_and_mutated_requests_never_panic() {
+        let mut rng = StdRng::seed_from_u64(42);
+        for request in VALID {
+            let bytes = request.as_bytes();
+            for len in 0..bytes.len() {
+                let _ = Request::parse(&bytes[..len]);
+            }
+            for _ in 0..500 {
+                let mut mutated = bytes.to_vec();
+                let index = rng.gen_range(0..mutated.len());
+                mutated[index] = rng.gen();
+                let _ = Request::parse(&mutated);
+            }
+        }
+    }
+
+    #[test]
+    fn oversized_and_deeply_nested_input_is_refused() {
+        let nested = "[".repeat(100_000);
+        assert!(Request::parse(nested.as_bytes()).is_err());
+
+        let huge_key = format!(r#"["get_env",{}]"#, "\"A\",".repeat(200_000));
+        assert!(Request::parse(huge_key.as_bytes()).is_err());
+
+        let legacy = r#"["store_env","not json"]"#;
+        assert!(Request::parse(legacy.as_bytes()).is_err());
+    }
+}
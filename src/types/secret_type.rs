@@ -0,0 +1,50 @@
+use serde::{Deserialize, Serialize};
+
+// What a key's value must look like. Keys without a declared type take any
+// string.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SecretType {
+    String,
+    Int,
+    Url,
+    Json,
+    PemCert,
+    PemPrivateKey,
+    Base64,
+    // An OpenSSH public key line or private key file
+    SshKey,
+    // Any string, with `${KEY}` references expanded when it is read
+    Template,
+}
+
+impl SecretType {
+    pub fn parse_name(name: &str) -> Result<Self, String> {
+        match name {
+            "string" => Ok(SecretType::String),
+            "int" => Ok(SecretType::Int),
+            "url" => Ok(SecretType::Url),
+            "json" => Ok(SecretType::Json),
+            "pem-cert" => Ok(SecretType::PemCert),
+            "pem-private-key" => Ok(SecretType::PemPrivateKey),
+            "base64" => Ok(SecretType::Base64),
+            "ssh-key" => Ok(SecretType::SshKey),
+            "template" => Ok(SecretType::Template),
+            _ => Err(format!("Unknown secret type '{}'", name)),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            SecretType::String => "string",
+            SecretType::Int => "int",
+            SecretType::Url => "url",
+            SecretType::Json => "json",
+            SecretType::PemCert => "pem-cert",
+            SecretType::PemPrivateKey => "pem-private-key",
+            SecretType::Base64 => "base64",
+            SecretType::SshKey => "ssh-key",
+            SecretType::Template => "template",
+        }
+    }
+}